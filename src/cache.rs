@@ -0,0 +1,161 @@
+use crate::matrix::MatrixEntry;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+const INDEX_FILE: &str = "index.json";
+
+/// The results taken from the cache, in the batch directory.
+pub(crate) const CACHED_FILE: &str = "cached.json";
+
+/// Where a cached result was measured.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct CacheRecord {
+    pub(crate) key: String,
+    pub(crate) sample: u32,
+    pub(crate) entry: MatrixEntry,
+    pub(crate) batch_dir: String,
+    /// UTC, RFC 3339.
+    pub(crate) measured_at: String,
+}
+
+/// Outputs of earlier runs, one directory per key with an index of where
+/// each was measured.
+pub(crate) struct Cache {
+    dir: PathBuf,
+    index: BTreeMap<String, CacheRecord>,
+}
+
+/// FNV-1a, which unlike std's hasher is stable across releases.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Cache key of everything a result depends on.
+pub(crate) fn key(inputs: &str) -> String {
+    format!("{:016x}", fnv1a(inputs.as_bytes()))
+}
+
+impl Cache {
+    pub(crate) fn open(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {dir:?}"))?;
+        let path = dir.join(INDEX_FILE);
+        let index = match path.exists() {
+            true => {
+                let file = std::fs::File::open(&path)
+                    .with_context(|| format!("Failed to open {path:?}"))?;
+                serde_json::from_reader(std::io::BufReader::new(file))
+                    .with_context(|| format!("Failed to parse {path:?}"))?
+            }
+            false => BTreeMap::new(),
+        };
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            index,
+        })
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&CacheRecord> {
+        self.index.get(key)
+    }
+
+    /// Copy the outputs cached under `key` into `run_dir`.
+    pub(crate) fn restore(&self, key: &str, run_dir: &Path) -> Result<()> {
+        let dir = self.dir.join(key);
+        for file in std::fs::read_dir(&dir).with_context(|| format!("Failed to read {dir:?}"))? {
+            let path = file?.path();
+            let target = run_dir.join(path.file_name().context("Cached file without a name")?);
+            std::fs::copy(&path, &target)
+                .with_context(|| format!("Failed to copy {path:?} to {target:?}"))?;
+        }
+        Ok(())
+    }
+
+    /// Cache `files` under the key of `record`, replacing what was there.
+    pub(crate) fn store(&mut self, record: CacheRecord, files: &[PathBuf]) -> Result<()> {
+        let dir = self.dir.join(&record.key);
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).with_context(|| format!("Failed to remove {dir:?}"))?;
+        }
+        std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {dir:?}"))?;
+        for path in files {
+            let target = dir.join(path.file_name().context("Output without a name")?);
+            std::fs::copy(path, &target)
+                .with_context(|| format!("Failed to copy {path:?} to {target:?}"))?;
+        }
+        self.index.insert(record.key.clone(), record);
+
+        // Renamed into place so an interrupted write keeps the old index
+        let path = self.dir.join(INDEX_FILE);
+        let tmp = self.dir.join(format!("{INDEX_FILE}.tmp"));
+        let file =
+            std::fs::File::create(&tmp).with_context(|| format!("Failed to create {tmp:?}"))?;
+        serde_json::to_writer_pretty(file, &self.index).context("Failed to write cache index")?;
+        std::fs::rename(&tmp, &path).with_context(|| format!("Failed to rename {tmp:?}"))
+    }
+}
+
+pub(crate) fn write_cached(batch_dir: &Path, cached: &[CacheRecord]) -> Result<()> {
+    let path = batch_dir.join(CACHED_FILE);
+    let file =
+        std::fs::File::create(&path).with_context(|| format!("Failed to create {path:?}"))?;
+    serde_json::to_writer_pretty(file, cached).context("Failed to write cached results")
+}
+
+/// The cached results of a batch being resumed, empty if it has none.
+pub(crate) fn read_cached(batch_dir: &Path) -> Result<Vec<CacheRecord>> {
+    let path = batch_dir.join(CACHED_FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let file = std::fs::File::open(&path).with_context(|| format!("Failed to open {path:?}"))?;
+    serde_json::from_reader(file).with_context(|| format!("Failed to parse {path:?}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_key_is_stable() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(key("a"), "af63dc4c8601ec8c");
+        assert_ne!(key("ab"), key("ba"));
+    }
+
+    #[test]
+    fn test_store_and_restore() -> Result<()> {
+        let root = crate::test::test_dir("cache");
+        let (cache_dir, run_dir, restored) =
+            (root.join("cache"), root.join("run"), root.join("new"));
+        std::fs::create_dir_all(&run_dir)?;
+        std::fs::create_dir_all(&restored)?;
+        let output = run_dir.join("j1-r30.json");
+        std::fs::write(&output, "{}")?;
+
+        let record = CacheRecord {
+            key: key("inputs"),
+            sample: 0,
+            entry: crate::matrix::build_matrix(&Default::default())[0].clone(),
+            batch_dir: "batch".into(),
+            measured_at: "2024-01-01T00:00:00.000Z".into(),
+        };
+        let mut cache = Cache::open(&cache_dir)?;
+        assert!(cache.get(&record.key).is_none());
+        cache.store(record.clone(), &[output])?;
+
+        let cache = Cache::open(&cache_dir)?;
+        assert_eq!(cache.get(&record.key), Some(&record));
+        cache.restore(&record.key, &restored)?;
+        assert_eq!(std::fs::read_to_string(restored.join("j1-r30.json"))?, "{}");
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
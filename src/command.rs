@@ -1,6 +1,9 @@
 use anyhow::{anyhow, Result};
-use std::process::{self, Stdio};
 use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{self, Stdio};
+use std::thread::JoinHandle;
 
 pub(crate) struct Command {
     command: process::Command,
@@ -32,31 +35,276 @@ impl Command {
         self
     }
 
+    pub(crate) fn stdin<T: Into<Stdio>>(&mut self, cfg: T) -> &mut Self {
+        self.command.stdin(cfg);
+        self
+    }
+
     pub(crate) fn stdout<T: Into<Stdio>>(&mut self, cfg: T) -> &mut Self {
         self.command.stdout(cfg);
         self
     }
 
     pub(crate) fn stderr<T: Into<Stdio>>(&mut self, cfg: T) -> &mut Self {
-        self.command.stdout(cfg);
+        self.command.stderr(cfg);
         self
     }
+
+    /// Set the nice value and, with `rt_priority`, `SCHED_FIFO` on the child
+    /// before it execs.
+    pub(crate) fn scheduling(&mut self, nice: Option<i32>, rt_priority: Option<i32>) -> &mut Self {
+        use std::os::unix::process::CommandExt;
+        if nice.is_none() && rt_priority.is_none() {
+            return self;
+        }
+        // SAFETY: Only async-signal-safe libc calls between fork and exec.
+        unsafe {
+            self.command.pre_exec(move || {
+                if let Some(nice) = nice {
+                    if libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                if let Some(priority) = rt_priority {
+                    let param = libc::sched_param {
+                        sched_priority: priority,
+                    };
+                    if libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        self
+    }
+
+    /// Pin the child to the cpus of `set` before it execs.
+    pub(crate) fn affinity(&mut self, set: libc::cpu_set_t) -> &mut Self {
+        use std::os::unix::process::CommandExt;
+        // SAFETY: sched_setaffinity is async-signal-safe and `set` is a copy.
+        unsafe {
+            self.command.pre_exec(move || {
+                if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        self
+    }
+
+    /// Start the child in a process group of its own, out of the
+    /// terminal's, so a Ctrl-C reaches only fio-matrix, which then stops the
+    /// child when it is done with it.
+    pub(crate) fn own_process_group(&mut self) -> &mut Self {
+        use std::os::unix::process::CommandExt;
+        self.command.process_group(0);
+        self
+    }
+
+    /// Spawn with output written to the given files, and also copied
+    /// where `tee` says.
+    pub(crate) fn spawn_captured(
+        &mut self,
+        stdout: Option<File>,
+        stderr: Option<File>,
+        tee: Tee,
+    ) -> std::io::Result<CapturedChild> {
+        // Output that is only written to its file goes there directly
+        let stdout = match stdout {
+            Some(file) if !tee.echo && !tee.log_stdout => {
+                self.stdout(file);
+                None
+            }
+            Some(file) => {
+                self.stdout(Stdio::piped());
+                Some(file)
+            }
+            None => None,
+        };
+        let stderr = match stderr {
+            Some(file) if !tee.echo && !tee.log_stderr => {
+                self.stderr(file);
+                None
+            }
+            Some(file) => {
+                self.stderr(Stdio::piped());
+                Some(file)
+            }
+            None => None,
+        };
+        let mut child = self.spawn()?;
+
+        let mut threads = Vec::new();
+        if let (Some(pipe), Some(file)) = (child.stdout.take(), stdout) {
+            let echo = tee.echo.then_some(std::io::stdout as fn() -> _);
+            let stream = tee.log_stdout.then_some("stdout");
+            threads.push(tee_lines(pipe, file, echo, stream));
+        }
+        if let (Some(pipe), Some(file)) = (child.stderr.take(), stderr) {
+            let echo = tee.echo.then_some(std::io::stderr as fn() -> _);
+            let stream = tee.log_stderr.then_some("stderr");
+            threads.push(tee_lines(pipe, file, echo, stream));
+        }
+
+        Ok(CapturedChild {
+            child,
+            threads,
+            grace_period: None,
+        })
+    }
+}
+
+/// Where the output of a captured child goes besides its files.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Tee {
+    /// Echo to our own stdout and stderr.
+    pub(crate) echo: bool,
+    /// Log each line of stdout.
+    pub(crate) log_stdout: bool,
+    /// Log each line of stderr.
+    pub(crate) log_stderr: bool,
+}
+
+/// Longer lines are copied in pieces, so a line never buffers more.
+const TEE_LINE_BYTES: u64 = 64 * 1024;
+/// Lines logged per stream, the rest only go to the file.
+const TEE_LOG_LINES: usize = 10_000;
+
+/// Copy `pipe` to `file` unchanged, and line by line to `echo` and, as
+/// `stream`, into the log.
+fn tee_lines<R, W>(
+    pipe: R,
+    mut file: File,
+    echo: Option<fn() -> W>,
+    stream: Option<&'static str>,
+) -> JoinHandle<std::io::Result<()>>
+where
+    R: std::io::Read + Send + 'static,
+    W: Write + 'static,
+{
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(pipe);
+        let mut line = Vec::new();
+        let mut logged = 0;
+        loop {
+            line.clear();
+            let read = (&mut reader)
+                .take(TEE_LINE_BYTES)
+                .read_until(b'\n', &mut line)?;
+            if read == 0 {
+                break;
+            }
+            file.write_all(&line)?;
+            // The echo and log are best effort, the file is what matters
+            if let Some(echo) = echo {
+                let _ = echo().write_all(&line);
+            }
+            let Some(stream) = stream else {
+                continue;
+            };
+            if logged < TEE_LOG_LINES {
+                let text = line.strip_suffix(b"\n").unwrap_or(&line);
+                log::info!("fio {stream}: {}", String::from_utf8_lossy(text));
+            } else if logged == TEE_LOG_LINES {
+                log::warn!("fio {stream}: more than {TEE_LOG_LINES} lines, logging no more");
+            }
+            logged += 1;
+        }
+        file.flush()
+    })
+}
+
+/// A child whose output may be copied by background threads. Waiting for it
+/// also waits for the copies to finish.
+pub(crate) struct CapturedChild {
+    child: process::Child,
+    threads: Vec<JoinHandle<std::io::Result<()>>>,
+    grace_period: Option<std::time::Duration>,
+}
+
+impl CapturedChild {
+    /// Stop the child if it is dropped while still running, e.g. when we
+    /// bail out on an error: `SIGTERM` first, which lets fio finish its
+    /// jobs and write its output, and `SIGKILL` after `grace_period`.
+    pub(crate) fn stop_on_drop(mut self, grace_period: std::time::Duration) -> Self {
+        self.grace_period = Some(grace_period);
+        self
+    }
+
+    pub(crate) fn wait(&mut self) -> Result<process::ExitStatus> {
+        let status = self.child.wait()?;
+        self.join()?;
+        Ok(status)
+    }
+
+    pub(crate) fn try_wait(&mut self) -> Result<Option<process::ExitStatus>> {
+        match self.child.try_wait()? {
+            Some(status) => {
+                self.join()?;
+                Ok(Some(status))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn join(&mut self) -> Result<()> {
+        for thread in self.threads.drain(..) {
+            thread
+                .join()
+                .map_err(|_| anyhow!("Output copy thread panicked"))??;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CapturedChild {
+    fn drop(&mut self) {
+        let Some(grace_period) = self.grace_period else {
+            return;
+        };
+        if let Err(e) = terminate(&mut self.child, libc::SIGTERM, grace_period) {
+            log::warn!("Failed to stop process {}: {e:?}", self.child.id());
+        }
+    }
+}
+
+/// Send `signal` to `child` unless it has exited, and kill it if it is
+/// still running after `grace_period`.
+fn terminate(
+    child: &mut process::Child,
+    signal: libc::c_int,
+    grace_period: std::time::Duration,
+) -> Result<()> {
+    if child.try_wait()?.is_some() {
+        return Ok(());
+    }
+
+    log::info!("Stopping process {} with signal {signal}", child.id());
+    // SAFETY: Sending a signal to a child we own and have not reaped yet.
+    unsafe { libc::kill(child.id() as libc::pid_t, signal) };
+
+    let deadline = std::time::Instant::now() + grace_period;
+    while std::time::Instant::now() < deadline {
+        if child.try_wait()?.is_some() {
+            return Ok(());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    log::warn!("Process {} did not exit, killing", child.id());
+    child.kill()?;
+    child.wait()?;
+    Ok(())
 }
 
 pub(crate) trait SpawnRetry {
-    fn spawn_retry(
-        &mut self,
-        retry_max: u32,
-        retry_delay: std::time::Duration,
-    ) -> Result<()> ;
+    fn spawn_retry(&mut self, retry_max: u32, retry_delay: std::time::Duration) -> Result<()>;
 }
 
 impl SpawnRetry for process::Command {
-    fn spawn_retry(
-        &mut self,
-        retry_max: u32,
-        retry_delay: std::time::Duration,
-    ) -> Result<()> {
+    fn spawn_retry(&mut self, retry_max: u32, retry_delay: std::time::Duration) -> Result<()> {
         if retry_max == 0 {
             return Err(anyhow!("Invalid retry count value"));
         }
@@ -68,7 +316,8 @@ impl SpawnRetry for process::Command {
             match self.spawn()?.wait()?.check_status() {
                 Ok(v) => {
                     log::info!("Command succeeded: {:?}", self);
-                    return Ok(v)},
+                    return Ok(v);
+                }
                 Err(e) => {
                     log::warn!("Command retry count: {retry_cnt}");
                     log::warn!("Command failed: {:?}", self);
@@ -77,13 +326,43 @@ impl SpawnRetry for process::Command {
                         return Err(e);
                     }
                     std::thread::sleep(retry_delay);
-                },
+                }
             }
         }
 
         unreachable!()
     }
+}
+
+/// A helper process running alongside a workload. It is stopped by sending
+/// `signal` when dropped, so it does not outlive a failed workload.
+pub(crate) struct BackgroundChild {
+    child: process::Child,
+    signal: libc::c_int,
+}
+
+impl BackgroundChild {
+    const GRACE_PERIOD: std::time::Duration = std::time::Duration::from_secs(10);
+
+    pub(crate) fn new(child: process::Child, signal: libc::c_int) -> Self {
+        Self { child, signal }
+    }
+
+    pub(crate) fn stop(mut self) -> Result<()> {
+        self.stop_inner()
+    }
+
+    fn stop_inner(&mut self) -> Result<()> {
+        terminate(&mut self.child, self.signal, Self::GRACE_PERIOD)
+    }
+}
 
+impl Drop for BackgroundChild {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop_inner() {
+            log::warn!("Failed to stop background process: {e:?}");
+        }
+    }
 }
 
 pub(crate) trait CheckExitCode {
@@ -113,3 +392,57 @@ impl std::ops::DerefMut for Command {
         &mut self.command
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_spawn_captured_keeps_streams_apart() -> Result<()> {
+        let tees = [
+            Tee::default(),
+            Tee {
+                echo: true,
+                ..Default::default()
+            },
+            Tee {
+                log_stdout: true,
+                log_stderr: true,
+                ..Default::default()
+            },
+        ];
+        for (i, tee) in tees.into_iter().enumerate() {
+            let dir = crate::test::test_dir(&format!("captured-{i}"));
+            std::fs::create_dir_all(&dir)?;
+            let (stdout, stderr) = (dir.join("run.stdout"), dir.join("run.stderr"));
+            Command::new("sh")
+                .args(["-c", "echo out; echo err >&2; printf tail >&2"])
+                .spawn_captured(
+                    Some(File::create(&stdout)?),
+                    Some(File::create(&stderr)?),
+                    tee,
+                )?
+                .wait()?
+                .check_status()?;
+            assert_eq!(std::fs::read_to_string(&stdout)?, "out\n");
+            assert_eq!(std::fs::read_to_string(&stderr)?, "err\ntail");
+            std::fs::remove_dir_all(&dir)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_terminate_escalates() -> Result<()> {
+        let grace_period = std::time::Duration::from_millis(300);
+        let mut child = process::Command::new("sh")
+            .args(["-c", "trap '' TERM; exec sleep 30"])
+            .spawn()?;
+        // Give the shell time to install the trap
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        let start = std::time::Instant::now();
+        terminate(&mut child, libc::SIGTERM, grace_period)?;
+        assert!(start.elapsed() >= grace_period);
+        assert!(child.try_wait()?.is_some());
+        Ok(())
+    }
+}
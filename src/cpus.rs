@@ -0,0 +1,151 @@
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::config::CpuVendor;
+
+/// Parse a kernel style cpu list such as `0-3,8,10-11`.
+pub(crate) fn parse_cpu_list(list: &str) -> Result<Vec<u32>> {
+    let mut cpus = Vec::new();
+    for part in list.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let start: u32 = start
+                    .parse()
+                    .with_context(|| format!("Bad cpu {start:?}"))?;
+                let end: u32 = end.parse().with_context(|| format!("Bad cpu {end:?}"))?;
+                if end < start {
+                    return Err(anyhow!("Bad cpu range {part:?}"));
+                }
+                cpus.extend(start..=end);
+            }
+            None => cpus.push(part.parse().with_context(|| format!("Bad cpu {part:?}"))?),
+        }
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+pub(crate) fn format_cpu_list(cpus: &[u32]) -> String {
+    cpus.iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// CPUs isolated from the scheduler via `isolcpus=` or `nohz_full=` on the
+/// kernel command line.
+fn isolated_cpus_from_cmdline(cmdline: &str) -> Result<Vec<u32>> {
+    let mut cpus = Vec::new();
+    for arg in cmdline.split_whitespace() {
+        let value = match arg.split_once('=') {
+            Some(("isolcpus", value)) | Some(("nohz_full", value)) => value,
+            _ => continue,
+        };
+        // isolcpus may carry flags like `domain,managed_irq,` before the list
+        let list = value
+            .split(',')
+            .filter(|p| p.starts_with(|c: char| c.is_ascii_digit()))
+            .collect::<Vec<_>>()
+            .join(",");
+        cpus.extend(parse_cpu_list(&list)?);
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+/// The affinity mask of `cpus`.
+pub(crate) fn cpu_set(cpus: &[u32]) -> Result<libc::cpu_set_t> {
+    // SAFETY: cpu_set_t is plain data and CPU_SET is bounds checked by us.
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus {
+            if cpu as usize >= libc::CPU_SETSIZE as usize {
+                return Err(anyhow!("Cpu {cpu} out of range"));
+            }
+            libc::CPU_SET(cpu as usize, &mut set);
+        }
+        Ok(set)
+    }
+}
+
+/// Pin the calling thread to `cpus`. Threads and processes started
+/// afterwards inherit the mask.
+pub(crate) fn set_affinity(cpus: &[u32]) -> Result<()> {
+    let set = cpu_set(cpus)?;
+    // SAFETY: `set` is a valid mask of the size passed.
+    if unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to pin to cpus {}", format_cpu_list(cpus)));
+    }
+    Ok(())
+}
+
+pub(crate) fn online_cpus() -> Result<Vec<u32>> {
+    let list = std::fs::read_to_string("/sys/devices/system/cpu/online")
+        .context("Failed to read online cpus")?;
+    parse_cpu_list(&list)
+}
+
+fn vendor_from_cpuinfo(cpuinfo: &str) -> Option<CpuVendor> {
+    let vendor_id = cpuinfo.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        (key.trim() == "vendor_id").then(|| value.trim())
+    })?;
+    match vendor_id {
+        "AuthenticAMD" => Some(CpuVendor::Amd),
+        "GenuineIntel" => Some(CpuVendor::Intel),
+        _ => None,
+    }
+}
+
+/// The vendor of the cpus, by the `vendor_id` of `/proc/cpuinfo`.
+pub(crate) fn vendor() -> Result<CpuVendor> {
+    let cpuinfo = std::fs::read_to_string("/proc/cpuinfo").context("Failed to read cpuinfo")?;
+    vendor_from_cpuinfo(&cpuinfo).ok_or(anyhow!(
+        "Cannot tell the cpu vendor from /proc/cpuinfo, set cpu_vendor to Amd or Intel"
+    ))
+}
+
+pub(crate) fn isolated_cpus() -> Result<Vec<u32>> {
+    let cmdline =
+        std::fs::read_to_string("/proc/cmdline").context("Failed to read kernel command line")?;
+    isolated_cpus_from_cmdline(&cmdline)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_list() -> Result<()> {
+        assert_eq!(parse_cpu_list("0-3,8,10-11")?, vec![0, 1, 2, 3, 8, 10, 11]);
+        assert_eq!(parse_cpu_list("5")?, vec![5]);
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_vendor_from_cpuinfo() {
+        let cpuinfo = "processor\t: 0\nvendor_id\t: AuthenticAMD\ncpu family\t: 25\n";
+        assert_eq!(vendor_from_cpuinfo(cpuinfo), Some(CpuVendor::Amd));
+        assert_eq!(
+            vendor_from_cpuinfo("vendor_id\t: GenuineIntel\n"),
+            Some(CpuVendor::Intel)
+        );
+        assert_eq!(vendor_from_cpuinfo("processor\t: 0\n"), None);
+    }
+
+    #[test]
+    fn test_isolated_cpus_from_cmdline() -> Result<()> {
+        assert_eq!(
+            isolated_cpus_from_cmdline("ro quiet isolcpus=domain,managed_irq,2-4 nohz_full=4-6")?,
+            vec![2, 3, 4, 5, 6]
+        );
+        assert!(isolated_cpus_from_cmdline("ro quiet")?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use crate::meminfo::CounterDelta;
+use anyhow::Context;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+const SYSFS_CPU: &str = "/sys/devices/system/cpu";
+const CPU_DMA_LATENCY: &str = "/dev/cpu_dma_latency";
+
+/// Microseconds spent in each idle state, summed over all cpus and keyed by
+/// the state name, e.g. `C6`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Snapshot(BTreeMap<String, u64>);
+
+impl Snapshot {
+    pub(crate) fn take() -> Result<Self> {
+        Self::take_in(Path::new(SYSFS_CPU))
+    }
+
+    fn take_in(sysfs_cpu: &Path) -> Result<Self> {
+        let read = |path: &Path| {
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read {path:?}"))
+        };
+        let states = glob::glob(&format!("{}/cpu[0-9]*/cpuidle/state*", sysfs_cpu.display()))
+            .context("Bad cpuidle pattern")?;
+
+        let mut residency = BTreeMap::new();
+        for dir in states {
+            let dir = dir?;
+            let name = read(&dir.join("name"))?.trim().to_string();
+            let time: u64 = read(&dir.join("time"))?
+                .trim()
+                .parse()
+                .with_context(|| format!("Bad residency in {dir:?}"))?;
+            *residency.entry(name).or_default() += time;
+        }
+        Ok(Self(residency))
+    }
+
+    pub(crate) fn delta(&self, after: &Snapshot) -> BTreeMap<String, CounterDelta> {
+        crate::meminfo::delta(&self.0, &after.0)
+    }
+}
+
+pub(crate) fn write_delta(path: &Path, before: &Snapshot, after: &Snapshot) -> Result<()> {
+    let file = std::fs::File::create(path).with_context(|| format!("Failed to create {path:?}"))?;
+    serde_json::to_writer_pretty(file, &before.delta(after))
+        .context("Failed to write C-state residency")
+}
+
+/// Keeps the cpus out of deep C-states while it is open, by requesting a
+/// wakeup latency of zero. The kernel drops the request when it is closed.
+pub(crate) fn hold_cpu_dma_latency() -> Result<std::fs::File> {
+    let mut file = std::fs::File::options()
+        .write(true)
+        .open(CPU_DMA_LATENCY)
+        .with_context(|| format!("Failed to open {CPU_DMA_LATENCY}"))?;
+    file.write_all(&0i32.to_ne_bytes())
+        .with_context(|| format!("Failed to write {CPU_DMA_LATENCY}"))?;
+    log::info!("Holding {CPU_DMA_LATENCY} at 0us, deep C-states are disabled");
+    Ok(file)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_delta() -> Result<()> {
+        let dir = crate::test::test_dir("cstates");
+        let write = |cpu: u32, state: u32, name: &str, time: u64| -> Result<()> {
+            let state_dir = dir.join(format!("cpu{cpu}/cpuidle/state{state}"));
+            std::fs::create_dir_all(&state_dir)?;
+            std::fs::write(state_dir.join("name"), format!("{name}\n"))?;
+            std::fs::write(state_dir.join("time"), format!("{time}\n"))?;
+            Ok(())
+        };
+        std::fs::create_dir_all(dir.join("cpufreq"))?;
+
+        write(0, 0, "POLL", 5)?;
+        write(0, 1, "C6", 100)?;
+        write(1, 1, "C6", 200)?;
+        let before = Snapshot::take_in(&dir)?;
+        write(0, 1, "C6", 150)?;
+        write(1, 1, "C6", 260)?;
+        let after = Snapshot::take_in(&dir)?;
+
+        let delta = before.delta(&after);
+        assert_eq!(delta["C6"].before, 300);
+        assert_eq!(delta["C6"].delta, 110);
+        assert_eq!(delta["POLL"].delta, 0);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+use crate::meminfo::CounterDelta;
+use anyhow::Context;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+const DEBUGFS: &str = "/sys/kernel/debug";
+
+/// Numeric counters from files in the debugfs directory of a module. A file
+/// holding a single number is keyed by its name, files of `name value` or
+/// `name: value` lines by `file/name`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Snapshot(BTreeMap<String, u64>);
+
+fn parse_file(file: &str, data: &str) -> BTreeMap<String, u64> {
+    if let Ok(value) = data.trim().parse() {
+        return BTreeMap::from([(file.to_string(), value)]);
+    }
+    data.lines()
+        .filter_map(|l| {
+            let (key, value) = l.split_once(':').or_else(|| l.trim().split_once(' '))?;
+            let value = value.split_whitespace().next()?.parse().ok()?;
+            Some((format!("{file}/{}", key.trim()), value))
+        })
+        .collect()
+}
+
+impl Snapshot {
+    pub(crate) fn take(module: &str, files: &[String]) -> Result<Self> {
+        Self::take_in(&Path::new(DEBUGFS).join(module), files)
+    }
+
+    fn take_in(dir: &Path, files: &[String]) -> Result<Self> {
+        let mut counters = BTreeMap::new();
+        for file in files {
+            let path = dir.join(file);
+            let data = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {path:?}"))?;
+            counters.extend(parse_file(file, &data));
+        }
+        Ok(Self(counters))
+    }
+
+    pub(crate) fn delta(&self, after: &Snapshot) -> BTreeMap<String, CounterDelta> {
+        crate::meminfo::delta(&self.0, &after.0)
+    }
+}
+
+pub(crate) fn write_delta(path: &Path, before: &Snapshot, after: &Snapshot) -> Result<()> {
+    let file = std::fs::File::create(path).with_context(|| format!("Failed to create {path:?}"))?;
+    serde_json::to_writer_pretty(file, &before.delta(after)).context("Failed to write debugfs")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_file() {
+        assert_eq!(
+            parse_file("submitted", "42\n"),
+            BTreeMap::from([("submitted".into(), 42)])
+        );
+        assert_eq!(
+            parse_file("stats", "completed: 7\nqueued 3\nstate: idle\n"),
+            BTreeMap::from([("stats/completed".into(), 7), ("stats/queued".into(), 3)])
+        );
+    }
+
+    #[test]
+    fn test_snapshot_delta() -> Result<()> {
+        let dir = crate::test::test_dir("debugfs");
+        std::fs::create_dir_all(&dir)?;
+        let files = vec!["submitted".to_string()];
+
+        std::fs::write(dir.join("submitted"), "10\n")?;
+        let before = Snapshot::take_in(&dir, &files)?;
+        std::fs::write(dir.join("submitted"), "25\n")?;
+        let after = Snapshot::take_in(&dir, &files)?;
+        assert_eq!(before.delta(&after)["submitted"].delta, 15);
+
+        assert!(Snapshot::take_in(&dir, &["missing".to_string()]).is_err());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+use crate::command::CheckExitCode;
+use crate::command::Command;
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use std::process::Stdio;
+
+/// Which physical drive a batch ran on.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct DeviceIdentity {
+    pub(crate) device: String,
+    pub(crate) model: Option<String>,
+    pub(crate) serial: Option<String>,
+    pub(crate) firmware: Option<String>,
+    /// The tool the fields came from, `nvme id-ctrl` or `lsblk`.
+    pub(crate) source: &'static str,
+}
+
+/// The fields of `nvme id-ctrl`, padded with spaces to their fixed width.
+#[derive(Deserialize)]
+struct IdCtrl {
+    mn: String,
+    sn: String,
+    fr: String,
+}
+
+#[derive(Deserialize)]
+struct Lsblk {
+    blockdevices: Vec<LsblkDevice>,
+}
+
+#[derive(Deserialize)]
+struct LsblkDevice {
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    serial: Option<String>,
+    #[serde(default)]
+    rev: Option<String>,
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+fn parse_id_ctrl(device: &str, data: &str) -> Result<DeviceIdentity> {
+    let id: IdCtrl = serde_json::from_str(data).context("Failed to parse nvme id-ctrl")?;
+    Ok(DeviceIdentity {
+        device: device.to_string(),
+        model: non_empty(&id.mn),
+        serial: non_empty(&id.sn),
+        firmware: non_empty(&id.fr),
+        source: "nvme id-ctrl",
+    })
+}
+
+fn parse_lsblk(device: &str, data: &str) -> Result<DeviceIdentity> {
+    let lsblk: Lsblk = serde_json::from_str(data).context("Failed to parse lsblk")?;
+    let found = lsblk
+        .blockdevices
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("lsblk did not list {device}"))?;
+    let field = |value: Option<String>| value.as_deref().and_then(non_empty);
+    Ok(DeviceIdentity {
+        device: device.to_string(),
+        model: field(found.model),
+        serial: field(found.serial),
+        firmware: field(found.rev),
+        source: "lsblk",
+    })
+}
+
+fn output(command: &mut Command) -> Result<String> {
+    let output = command
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {:?}", command.get_program()))?
+        .wait_with_output()?;
+    output.status.check_status()?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Model, serial and firmware of `device`, e.g. `nvme0n1`. NVMe drives
+/// are asked directly, anything else or a failing `nvme` falls back to
+/// what `lsblk` knows.
+pub(crate) fn read(device: &str) -> Result<DeviceIdentity> {
+    let path = format!("/dev/{device}");
+    if device.starts_with("nvme") {
+        let id_ctrl = output(
+            Command::new("nvme")
+                .arg("id-ctrl")
+                .arg(&path)
+                .arg("--output-format=json"),
+        )
+        .and_then(|data| parse_id_ctrl(device, &data));
+        match id_ctrl {
+            Ok(identity) => return Ok(identity),
+            Err(e) => log::warn!("Falling back to lsblk for {device}: {e:?}"),
+        }
+    }
+    let data = output(
+        Command::new("lsblk")
+            .arg("--json")
+            .arg("--nodeps")
+            .arg("--output=MODEL,SERIAL,REV")
+            .arg(&path),
+    )
+    .context("lsblk failed")?;
+    parse_lsblk(device, &data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let identity = parse_id_ctrl(
+            "nvme0n1",
+            r#"{"vid":5197,"sn":"S6B0NL0T123456      ","mn":"Samsung SSD 980 PRO 1TB                 ","fr":"5B2QGXA7"}"#,
+        )?;
+        assert_eq!(identity.serial.as_deref(), Some("S6B0NL0T123456"));
+        assert_eq!(identity.model.as_deref(), Some("Samsung SSD 980 PRO 1TB"));
+        assert_eq!(identity.firmware.as_deref(), Some("5B2QGXA7"));
+
+        let identity = parse_lsblk(
+            "sda",
+            r#"{"blockdevices":[{"model":"QEMU HARDDISK   ","serial":null,"rev":"2.5+"}]}"#,
+        )?;
+        assert_eq!(identity.model.as_deref(), Some("QEMU HARDDISK"));
+        assert_eq!(identity.serial, None);
+        assert_eq!(identity.source, "lsblk");
+
+        assert!(parse_lsblk("sda", r#"{"blockdevices":[]}"#).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+
+static INTERRUPTS: AtomicU32 = AtomicU32::new(0);
+
+extern "C" fn on_sigint(_signal: libc::c_int) {
+    // Only async-signal-safe work in here
+    INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Counts Ctrl-C instead of dying while held, so a batch can stop between
+/// configs and clean up after itself. The default handler is back once it
+/// is dropped.
+pub(crate) struct Guard {
+    previous: libc::sighandler_t,
+}
+
+pub(crate) fn install() -> Guard {
+    INTERRUPTS.store(0, Ordering::SeqCst);
+    let handler = on_sigint as extern "C" fn(libc::c_int);
+    // SAFETY: The handler only touches an atomic.
+    let previous = unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+    Guard { previous }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        // SAFETY: Puts back what was installed before us.
+        unsafe { libc::signal(libc::SIGINT, self.previous) };
+    }
+}
+
+/// Ctrl-C was pressed, stop before the next config.
+pub(crate) fn requested() -> bool {
+    INTERRUPTS.load(Ordering::SeqCst) >= 1
+}
+
+/// Ctrl-C was pressed again, stop the running fio too.
+pub(crate) fn forced() -> bool {
+    INTERRUPTS.load(Ordering::SeqCst) >= 2
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Raising SIGINT here would leak into the tests waiting on fio, which
+    // run in the same process
+    #[test]
+    fn test_guard_restores_handler() {
+        let current = || unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(libc::SIGINT, std::ptr::null(), &mut action);
+            action.sa_sigaction
+        };
+        let before = current();
+        let guard = install();
+        assert_eq!(
+            current(),
+            on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t
+        );
+        assert!(!requested());
+        drop(guard);
+        assert_eq!(current(), before);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::config::LogFormat;
 use anyhow::Result;
 use log4rs::append::console::ConsoleAppender;
 use log4rs::append::file::FileAppender;
@@ -5,91 +6,161 @@ use log4rs::config::runtime::ConfigBuilder;
 use log4rs::config::Appender;
 use log4rs::config::Config;
 use log4rs::config::Root;
+use log4rs::encode::json::JsonEncoder;
 use log4rs::encode::pattern::PatternEncoder;
 use log4rs::encode::writer::simple::SimpleWriter;
 use std::io::IsTerminal;
+use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::Mutex;
 
-pub(crate) fn init_log() -> Result<log4rs::Handle> {
-    let config_builder = configure_stdout_log(Config::builder());
+pub(crate) fn init_log(level: log::LevelFilter, format: LogFormat) -> Result<log4rs::Handle> {
+    Ok(log4rs::init_config(console_config(level, format)?)?)
+}
 
-    let log_config = config_builder.build(
-        Root::builder()
-            .appender("console")
-            .build(log::LevelFilter::Info),
-    )?;
+/// Log only to the console, at `level`.
+pub(crate) fn set_console_log(
+    handle: &log4rs::Handle,
+    level: log::LevelFilter,
+    format: LogFormat,
+) -> Result<()> {
+    handle.set_config(console_config(level, format)?);
+    Ok(())
+}
 
-    Ok(log4rs::init_config(log_config)?)
+fn encoder(format: LogFormat) -> Box<dyn log4rs::encode::Encode> {
+    match format {
+        LogFormat::Pattern => Box::new(PatternEncoder::default()),
+        LogFormat::Json => Box::new(JsonEncoder::new()),
+    }
 }
 
-fn configure_stdout_log(config_builder: ConfigBuilder) -> ConfigBuilder {
-    let console = ConsoleAppender::builder().build();
-    config_builder.appender(Appender::builder().build("console", Box::new(console)))
+fn console_config(level: log::LevelFilter, format: LogFormat) -> Result<Config> {
+    let config_builder = configure_stdout_log(Config::builder(), format);
+    Ok(config_builder.build(Root::builder().appender("console").build(level))?)
 }
 
-fn configure_file_log(config_builder: ConfigBuilder, output_dir: &str) -> Result<ConfigBuilder> {
-    let mut logfile_path = PathBuf::from(output_dir);
-    logfile_path.push(format!(
-        "log-{}.log",
-        chrono::Local::now().format("%Y-%m-%d-%H%M-%f")
-    ));
-    println!("Log file path: {logfile_path:?}");
+fn configure_stdout_log(config_builder: ConfigBuilder, format: LogFormat) -> ConfigBuilder {
+    let console = ConsoleAppender::builder().encoder(encoder(format)).build();
+    config_builder.appender(Appender::builder().build("console", Box::new(console)))
+}
 
-    let logfile = FileAppender::builder().build(logfile_path)?;
-    Ok(config_builder.appender(Appender::builder().build("logfile", Box::new(logfile))))
+fn configure_file_log(
+    config_builder: ConfigBuilder,
+    name: &str,
+    path: &Path,
+    format: LogFormat,
+) -> Result<ConfigBuilder> {
+    let logfile = FileAppender::builder()
+        .encoder(encoder(format))
+        .build(path)?;
+    Ok(config_builder.appender(Appender::builder().build(name, Box::new(logfile))))
 }
 
-pub(crate) fn setup_log(
+/// The appenders set up for a captured run. The configuration can be
+/// rebuilt to add a log file for a single config.
+pub(crate) struct LogSetup {
     handle: log4rs::Handle,
-    output_dir: Option<&str>,
+    logfile_path: Option<PathBuf>,
     stdout_log: bool,
-    memory_log: bool,
-) -> Result<Option<Arc<MemoryAppender>>> {
-    let mut log_config_builder = Config::builder();
-    let mut root_builder = Root::builder();
+    memory: Option<MemoryAppender>,
+    level: log::LevelFilter,
+    format: LogFormat,
+}
 
-    if let Some(output_dir) = output_dir {
-        log_config_builder = configure_file_log(log_config_builder, output_dir)?;
-        root_builder = root_builder.appender("logfile");
+impl LogSetup {
+    pub(crate) fn memory(&self) -> Option<MemoryAppender> {
+        self.memory.clone()
     }
 
-    if !std::io::stdout().is_terminal() && stdout_log {
-        log_config_builder = configure_stdout_log(log_config_builder);
-        root_builder = root_builder.appender("console");
-    }
+    /// Also log to `run_log` until called again with `None`.
+    pub(crate) fn set_run_log(&self, run_log: Option<&Path>) -> Result<()> {
+        let mut log_config_builder = Config::builder();
+        let mut root_builder = Root::builder();
 
-    let memory_log_handle = if memory_log {
-        let handle = Arc::new(MemoryAppender::new());
-        log_config_builder = log_config_builder
-            .appender(Appender::builder().build("memory", Box::new(handle.clone())));
-        root_builder = root_builder.appender("memory");
-        Some(handle)
-    } else {
-        None
-    };
+        if let Some(path) = &self.logfile_path {
+            log_config_builder =
+                configure_file_log(log_config_builder, "logfile", path, self.format)?;
+            root_builder = root_builder.appender("logfile");
+        }
+
+        if let Some(path) = run_log {
+            log_config_builder =
+                configure_file_log(log_config_builder, "runlog", path, self.format)?;
+            root_builder = root_builder.appender("runlog");
+        }
 
-    let log_config = log_config_builder.build(root_builder.build(log::LevelFilter::Info))?;
+        if !std::io::stdout().is_terminal() && self.stdout_log {
+            log_config_builder = configure_stdout_log(log_config_builder, self.format);
+            root_builder = root_builder.appender("console");
+        }
+
+        if let Some(memory) = &self.memory {
+            log_config_builder = log_config_builder
+                .appender(Appender::builder().build("memory", Box::new(memory.clone())));
+            root_builder = root_builder.appender("memory");
+        }
 
-    handle.set_config(log_config);
-    Ok(memory_log_handle)
+        let log_config = log_config_builder.build(root_builder.build(self.level))?;
+
+        self.handle.set_config(log_config);
+        Ok(())
+    }
 }
 
-#[derive(Debug)]
+pub(crate) fn setup_log(
+    handle: log4rs::Handle,
+    output_dir: Option<&str>,
+    stdout_log: bool,
+    memory_log: bool,
+    level: log::LevelFilter,
+    format: LogFormat,
+) -> Result<LogSetup> {
+    let logfile_path = output_dir.map(|output_dir| {
+        let mut logfile_path = PathBuf::from(output_dir);
+        logfile_path.push(format!(
+            "log-{}.log",
+            chrono::Local::now().format("%Y-%m-%d-%H%M-%f")
+        ));
+        println!("Log file path: {logfile_path:?}");
+        logfile_path
+    });
+
+    let setup = LogSetup {
+        handle,
+        logfile_path,
+        stdout_log,
+        memory: memory_log.then(|| MemoryAppender::new(format)),
+        level,
+        format,
+    };
+    setup.set_run_log(None)?;
+    Ok(setup)
+}
+
+/// Keeps the log in memory until it is drained with [`MemoryAppender::data`].
+/// Clones share the buffer, so one can be handed to log4rs and another kept
+/// for draining.
+#[derive(Debug, Clone)]
 pub(crate) struct MemoryAppender {
-    buffer: Mutex<SimpleWriter<Vec<u8>>>,
-    encoder: Box<dyn log4rs::encode::Encode>,
+    buffer: Arc<Mutex<SimpleWriter<Vec<u8>>>>,
+    encoder: Arc<dyn log4rs::encode::Encode>,
 }
 
 impl MemoryAppender {
-    fn new() -> Self {
+    fn new(format: LogFormat) -> Self {
+        Self::with_encoder(encoder(format))
+    }
+
+    fn with_encoder(encoder: Box<dyn log4rs::encode::Encode>) -> Self {
         Self {
-            buffer: Mutex::new(SimpleWriter(Vec::new())),
-            encoder: Box::<PatternEncoder>::default(),
+            buffer: Arc::new(Mutex::new(SimpleWriter(Vec::new()))),
+            encoder: encoder.into(),
         }
     }
 
+    /// What was logged since the last call.
     pub(crate) fn data(&self) -> Vec<u8> {
         let mut buffer = self.buffer.lock().unwrap();
         let mut new_buffer = Vec::new();
@@ -98,27 +169,71 @@ impl MemoryAppender {
     }
 }
 
-impl log::Log for MemoryAppender {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
-    }
-
-    fn log(&self, record: &log::Record) {
+impl log4rs::append::Append for MemoryAppender {
+    fn append(&self, record: &log::Record) -> anyhow::Result<()> {
         use std::ops::DerefMut;
         let mut buffer = self.buffer.lock().unwrap();
-        self.encoder.encode(buffer.deref_mut(), record).unwrap();
+        self.encoder.encode(buffer.deref_mut(), record)?;
+        Ok(())
     }
 
     fn flush(&self) {}
 }
 
-// impl log4rs::append::Append for MemoryAppender {
-//     fn append(&self, record: &log::Record) -> anyhow::Result<()> {
-//         use std::ops::DerefMut;
-//         let mut buffer = self.buffer.lock().unwrap();
-//         self.encoder.encode(buffer.deref_mut(), record)?;
-//         Ok(())
-//     }
+#[cfg(test)]
+mod test {
+    use super::*;
+    use log4rs::append::Append;
+
+    #[test]
+    fn test_memory_appender_drains() -> Result<()> {
+        // Without the time, so encoding twice gives the same
+        let pattern = "{l} {t} - {m}{n}";
+        let appender = MemoryAppender::with_encoder(Box::new(PatternEncoder::new(pattern)));
+        let drained = appender.clone();
+        let records = ["first", "second", "third"];
+
+        let mut expected = SimpleWriter(Vec::new());
+        let encoder = PatternEncoder::new(pattern);
+        for message in records {
+            let args = format_args!("{message}");
+            let record = log::Record::builder()
+                .args(args)
+                .level(log::Level::Info)
+                .target("fio_matrix")
+                .build();
+            appender.append(&record)?;
+            log4rs::encode::Encode::encode(&encoder, &mut expected, &record)?;
+        }
+
+        assert_eq!(drained.data(), expected.0);
+        assert!(drained.data().is_empty());
+        Ok(())
+    }
 
-//     fn flush(&self) {}
-// }
+    #[test]
+    fn test_json_format() -> Result<()> {
+        let appender = MemoryAppender::new(LogFormat::Json);
+        for message in ["first", "second"] {
+            appender.append(
+                &log::Record::builder()
+                    .args(format_args!("{message}"))
+                    .level(log::Level::Warn)
+                    .module_path(Some("fio_matrix::config"))
+                    .build(),
+            )?;
+        }
+
+        let data = String::from_utf8(appender.data())?;
+        let records = data
+            .lines()
+            .map(serde_json::from_str::<serde_json::Value>)
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1]["message"], "second");
+        assert_eq!(records[1]["level"], "WARN");
+        assert_eq!(records[1]["module_path"], "fio_matrix::config");
+        assert!(records[1]["time"].is_string());
+        Ok(())
+    }
+}
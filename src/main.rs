@@ -6,64 +6,363 @@
 use anyhow::anyhow;
 use anyhow::Context;
 use anyhow::Result;
+use clap::Parser;
 use indicatif::ProgressBar;
-use logging::MemoryAppender;
+use std::collections::HashMap;
 use std::io::IsTerminal;
 use std::io::Write;
 use std::path::Path;
 use std::process::Stdio;
 use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::{fs::File, path::PathBuf};
 use tap::Pipe;
 use tap::Tap;
 
+mod cache;
 mod command;
 mod config;
+mod cpus;
+mod cstates;
+mod debugfs;
+mod identity;
+mod interrupt;
 mod logging;
+mod matrix;
+mod meminfo;
+mod metadata;
+mod power;
+mod report;
+mod results;
+mod settings;
+mod smart;
+mod telemetry;
+mod trend;
 
+use crate::command::BackgroundChild;
 use crate::command::CheckExitCode;
 use crate::command::Command;
 use crate::command::SpawnRetry;
+use crate::matrix::MatrixEntry;
+use crate::matrix::PlannedRun;
 
 fn main() -> Result<()> {
-    let log_handle = logging::init_log()?;
+    let args = config::Cli::parse();
+    // Before logging starts, so they print nothing but the listing
+    if args.list_defaults {
+        print!("{}", config::Config::default().to_toml()?);
+        return Ok(());
+    }
+    if args.list_workloads {
+        for rw in config::READWRITE_MODES {
+            let kind = match workload_writes(rw) {
+                true => "writes, destructive",
+                false => "read only",
+            };
+            println!("{rw:<14} {kind}");
+        }
+        return Ok(());
+    }
+
+    let early_args = std::env::args().collect::<Vec<_>>();
+    let log_handle = logging::init_log(
+        config::early_log_level(&early_args),
+        config::early_log_format(&early_args),
+    )?;
     log::info!("Starting test runner");
 
-    let config = config::Config::parse()?;
+    if let Some(batch_dir) = &args.summarize {
+        return summarize(
+            batch_dir,
+            args.report_html.as_deref(),
+            args.curves_dir.as_deref(),
+            args.canonical_summary.as_deref(),
+        );
+    }
+
+    if let Some(pattern) = &args.summarize_glob {
+        return summarize_batches(pattern, args.summarize_tag.as_deref(), args.summarize_since);
+    }
+
+    let print_result_path = args.print_result_path;
+    let jobfiles_dir = args.export_jobfiles.clone();
+    let config = config::Config::from_cli(args)?;
+    logging::set_console_log(&log_handle, config.log_level.filter(), config.log_format)?;
+    if let Some(dir) = &jobfiles_dir {
+        return export_jobfiles(&config, dir);
+    }
+    if print_result_path && !config.capture {
+        return Err(anyhow!("Cannot print result path without capture"));
+    }
 
+    install_panic_hook(&config);
     let status = Rc::new(run_test(&config, log_handle));
 
-    if let Some(target) = config.remote {
-        shutdown(target, status.clone())?;
+    if config.has_remote() {
+        shutdown(&config, status.clone())?;
+    }
+
+    let batch_dir = Rc::try_unwrap(status).or(Err(anyhow!("Failed to get status")))??;
+    if let (true, Some(batch_dir)) = (print_result_path, batch_dir) {
+        // Last line of stdout, for scripts driving us
+        println!(
+            "{}",
+            serde_json::json!({
+                "batch_dir": batch_dir,
+                "summary": PathBuf::from(&batch_dir).join(results::SUMMARY_FILE),
+            })
+        );
+    }
+    Ok(())
+}
+
+/// On a panic of the main thread, log it and tear down the current config
+/// before unwinding. The rest of the batch is finished by [`run_test`] as
+/// for any other failure.
+fn install_panic_hook(config: &config::Config) {
+    let config = config.clone();
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if std::thread::current().name() == Some("main") {
+            log::error!(
+                "Panicked: {info}\n{}",
+                std::backtrace::Backtrace::force_capture()
+            );
+            if let Err(e) = teardown(&config) {
+                log::warn!("Teardown after panic failed: {e:?}");
+            }
+        }
+        default_hook(info);
+    }));
+}
+
+fn summarize(
+    batch_dir: &Path,
+    report_html: Option<&Path>,
+    curves_dir: Option<&Path>,
+    canonical_summary: Option<&Path>,
+) -> Result<()> {
+    let batch = results::Batch::load(batch_dir).context("Failed to load batch")?;
+    let aggregates = batch.aggregate()?;
+    results::print_summary(&batch.config, &aggregates)?;
+
+    if let Some(path) = report_html {
+        log::info!("Writing HTML report to {path:?}");
+        std::fs::write(path, report::render_html(&batch.config, &aggregates)?)
+            .context("Failed to write HTML report")?;
+    }
+
+    if let Some(dir) = curves_dir {
+        log::info!("Writing curves to {dir:?}");
+        std::fs::create_dir_all(dir).context("Failed to create curves directory")?;
+        if !results::has_percentiles(&aggregates) {
+            log::warn!("{batch_dir:?} has no latency percentiles, the curves only have throughput");
+        }
+        for (name, csv) in report::render_curves(&batch.config, &aggregates)? {
+            std::fs::write(dir.join(name), csv).context("Failed to write curve")?;
+        }
+    }
+
+    if let Some(path) = canonical_summary {
+        log::info!("Writing canonical summary to {path:?}");
+        std::fs::write(path, results::canonical_summary(&aggregates)?)
+            .context("Failed to write canonical summary")?;
+    }
+
+    Ok(())
+}
+
+/// Compare the batches matching `pattern`, optionally only those with
+/// `tag` or started on `since` or later.
+fn summarize_batches(
+    pattern: &str,
+    tag: Option<&str>,
+    since: Option<chrono::NaiveDate>,
+) -> Result<()> {
+    let mut batches = Vec::new();
+    for dir in glob::glob(pattern)? {
+        let dir = dir?;
+        if !dir.join("config.toml").exists() {
+            continue;
+        }
+        if let Some(since) = since {
+            match batch_time(&dir) {
+                Some(time) if time.date() >= since => {}
+                Some(_) => continue,
+                None => {
+                    log::warn!("Skipping {dir:?}: no timestamp in its name");
+                    continue;
+                }
+            }
+        }
+        let batch =
+            results::Batch::load(&dir).with_context(|| format!("Failed to load batch {dir:?}"))?;
+        if tag.is_some() && batch.config.tag.as_deref() != tag {
+            continue;
+        }
+
+        let kernel = metadata::kernel_release(&dir)
+            .inspect_err(|e| log::warn!("No kernel release for {dir:?}: {e:?}"))
+            .unwrap_or_else(|_| "unknown".into());
+        let label = format!(
+            "{kernel} {}",
+            batch.config.tag.as_deref().unwrap_or("untagged")
+        );
+        let aggregates = batch.aggregate()?;
+        if !results::has_percentiles(&aggregates) {
+            log::warn!("{dir:?} has no latency percentiles to compare");
+        }
+        batches.push((label, aggregates));
+    }
+
+    if batches.is_empty() {
+        return Err(anyhow!("No batches match {pattern}"));
     }
+    log::info!("Comparing {} batches", batches.len());
+    results::print_comparison(&results::comparison(&batches));
+    Ok(())
+}
 
-    Rc::try_unwrap(status).or(Err(anyhow!("Failed to get status")))?
+const BATCH_TIME_FORMAT: &str = "%Y-%m-%d-%H%M";
+
+/// When a batch started, from the timestamp that ends its directory name.
+fn batch_time(dir: &Path) -> Option<chrono::NaiveDateTime> {
+    let name = dir.file_name()?.to_str()?;
+    let stamp = name.get(name.len().checked_sub("YYYY-mm-dd-HHMM".len())?..)?;
+    chrono::NaiveDateTime::parse_from_str(stamp, BATCH_TIME_FORMAT).ok()
 }
 
-fn run_test(config: &config::Config, log_handle: log4rs::Handle) -> Result<()> {
+/// Returns the batch directory if output is captured.
+fn run_test(config: &config::Config, log_handle: log4rs::Handle) -> Result<Option<String>> {
+    // Held until we return. The kernel drops the lock when the file is
+    // closed, which also covers unwinding and abnormal exit.
+    let _lock = acquire_lock(config).context("Failed to acquire run lock")?;
+
+    // Before anything spawns threads, so they all inherit the mask
+    if let Some(list) = &config.controller_cpus {
+        cpus::set_affinity(&cpus::parse_cpu_list(list)?)?;
+    }
+
+    let owner = config
+        .output_owner
+        .as_deref()
+        .map(resolve_owner)
+        .transpose()?;
     let output_dir = match config.capture {
-        true => Some(get_batch_dir(config)?),
+        true => Some(get_batch_dir(config, owner)?),
         false => None,
     };
 
-    let mem_log = if config.capture {
-        logging::setup_log(log_handle, Some(output_dir.as_ref().unwrap()), true, true)?
+    let log_setup = if config.capture {
+        Some(logging::setup_log(
+            log_handle,
+            Some(output_dir.as_ref().unwrap()),
+            true,
+            true,
+            config.log_level.filter(),
+            config.log_format,
+        )?)
     } else {
         None
     };
+    let mem_log = log_setup.as_ref().and_then(logging::LogSetup::memory);
 
     log::info!("Configuration: {:#?}", config);
 
+    if let Some(output_dir) = &output_dir {
+        let path = PathBuf::from(output_dir).tap_mut(|p| p.push("config.toml"));
+        std::fs::write(&path, config.to_toml()?).context("Failed to write config.toml")?;
+    }
+
     let push_log = move || -> Result<()> {
-        if let Some(target) = &config.remote {
-            push_log(target, mem_log.clone().unwrap())?;
+        if !config.has_remote() && config.log_spill_path.is_none() {
+            return Ok(());
+        }
+        let Some(mem_log) = &mem_log else {
+            return Ok(());
+        };
+        let buffer = mem_log.data();
+        // Spilled before pushing so a failed push does not lose the drained log
+        if let Some(path) = &config.log_spill_path {
+            spill_log(path, &buffer).context("Failed to spill log")?;
+        }
+        if config.has_remote() {
+            remote_fan_out(config, "Log push", |client, target| {
+                push_log(config, client, target, &buffer)
+            })?;
         }
         Ok(())
     };
 
     print_uname()?;
-    let status = run_workloads(output_dir.as_deref(), config, &push_log);
+    let mut metadata = metadata::Metadata::collect(config).context("Failed to collect metadata")?;
+    log::info!("System: {:?}", metadata.system);
+    log::info!("fio: {}", metadata.fio_version);
+    if let Some(output_dir) = &output_dir {
+        if config.capture_kernel_config {
+            metadata.save_kernel_config(Path::new(output_dir))?;
+        }
+        if config.capture_device_identity {
+            // Not every device can say what it is, that is no reason to stop
+            metadata.device_identity = identity::read(&config.device)
+                .inspect_err(|e| log::warn!("Failed to identify {}: {e:?}", config.device))
+                .ok();
+        }
+        metadata.write(Path::new(output_dir))?;
+    }
+
+    // Wear accounting is best effort, it must not fail a sweep
+    let read_smart = || {
+        smart::read(&config.device)
+            .inspect_err(|e| log::warn!("Failed to read smart log: {e:?}"))
+            .ok()
+    };
+    let smart_before = config.capture_smart.then(read_smart).flatten();
+
+    // Before run_workloads tunes anything, restored whether it succeeds or not
+    let mut system_settings =
+        settings::capture(config).context("Failed to save system settings")?;
+
+    // Keep the partial results and push the log also if a bug panics
+    let mut status = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        run_workloads(output_dir.as_deref(), config, log_setup.as_ref(), &push_log)
+    }))
+    .unwrap_or_else(|_| Err(anyhow!("Panicked while running workloads")));
+    system_settings.restore();
+
+    if let (Some(before), Some(output_dir)) = (smart_before, &output_dir) {
+        if let Some(after) = read_smart() {
+            let delta = smart::SmartDelta::new(before, after);
+            log::info!(
+                "Sweep wrote {} bytes to the device, {} media errors, {}% life used",
+                delta.bytes_written,
+                delta.media_errors,
+                delta.percent_used
+            );
+            metadata.smart = Some(delta);
+            metadata.write(Path::new(output_dir))?;
+        }
+    }
+    if let (true, Some(output_dir)) = (config.results_ndjson, &output_dir) {
+        // Also for a failed sweep, the partial results are worth keeping
+        match results::write_ndjson(config, Path::new(output_dir), config.remove_raw_results) {
+            Ok(count) => log::info!("Collected {count} results into {}", results::NDJSON_FILE),
+            Err(e) => status = status.and(Err(e.context("Failed to collect results"))),
+        }
+    }
+    if let (true, Some(output_dir)) = (config.capture, &output_dir) {
+        match results::write_summary_csv(config, Path::new(output_dir)) {
+            Ok(rows) => log::info!(
+                "Summarized {rows} results into {}",
+                results::SUMMARY_CSV_FILE
+            ),
+            Err(e) => status = status.and(Err(e.context("Failed to write summary"))),
+        }
+    }
+    if let (Ok(()), Some(output_dir)) = (&status, &output_dir) {
+        status = evaluate_results(config, Path::new(output_dir));
+    }
 
     // Print the error to log before compressing
     if let Err(e) = &status {
@@ -74,15 +373,126 @@ fn run_test(config: &config::Config, log_handle: log4rs::Handle) -> Result<()> {
 
     push_log()?;
 
+    if let (Some((uid, gid)), Some(output_dir)) = (owner, &output_dir) {
+        chown_tree(Path::new(output_dir), uid, gid)
+            .context("Failed to hand over output ownership")?;
+    }
+
     if config.capture && config.compress {
-        compress(output_dir.as_ref().unwrap())?;
+        let filename = compress(config, output_dir.as_ref().unwrap())?;
+        if let Some((uid, gid)) = owner {
+            std::os::unix::fs::chown(&filename, Some(uid), Some(gid))
+                .with_context(|| format!("Failed to chown {filename}"))?;
+        }
+        remote_fan_out(config, "Upload", |client, target| {
+            upload(config, client, target, &filename)
+        })?;
+    }
+
+    status.map(|()| output_dir)
+}
+
+/// Write the summary of a finished batch and check it against the
+/// configured thresholds.
+fn evaluate_results(config: &config::Config, batch_dir: &Path) -> Result<()> {
+    let batch = results::Batch::load(batch_dir).context("Failed to load results")?;
+    let aggregates = batch.aggregate()?;
+    let path = results::write_summary(batch_dir, &aggregates).context("Failed to write summary")?;
+    log::info!("Wrote summary to {path:?}");
+    let path =
+        results::write_aggregate(batch_dir, &aggregates).context("Failed to write aggregate")?;
+    log::info!("Wrote aggregate to {path:?}");
+    for aggregate in &aggregates {
+        for (metric, cv) in aggregate.noisy(config.max_cv) {
+            log::warn!(
+                "Noisy {metric} for {}: CoV {:.1}% over {} samples is above {:.1}%",
+                aggregate.entry,
+                cv * 100.0,
+                aggregate.samples,
+                config.max_cv * 100.0
+            );
+        }
+    }
+
+    if let Some(path) = &config.prometheus_textfile {
+        let metrics = report::render_prometheus(config, &aggregates)?;
+        write_atomic(path, &metrics).context("Failed to write Prometheus textfile")?;
+        log::info!("Wrote Prometheus metrics to {path:?}");
+    }
+
+    if let Some(baseline) = &config.baseline {
+        let baseline = results::load_baseline(baseline)?;
+        let current = aggregates
+            .iter()
+            .map(results::EntrySpread::from)
+            .collect::<Vec<_>>();
+        let rows = results::compare_baseline(&baseline, &current);
+        let path = results::write_comparison_csv(batch_dir, &rows)
+            .context("Failed to write comparison")?;
+        log::info!("Wrote comparison against the baseline to {path:?}");
+        if let Some(max_pct) = config.max_regression_pct {
+            let regressions = results::regressions(&rows, max_pct);
+            for regression in &regressions {
+                log::error!("Regression against the baseline: {regression}");
+            }
+            if !regressions.is_empty() {
+                return Err(anyhow!(
+                    "{} regressions against the baseline",
+                    regressions.len()
+                ));
+            }
+            log::info!("No regressions against the baseline");
+        }
+    }
+
+    if let Some(thresholds) = &config.thresholds {
+        let violations = results::check_thresholds(thresholds, &aggregates);
+        for violation in &violations {
+            log::error!("Threshold violated: {violation}");
+        }
+        if !violations.is_empty() {
+            return Err(anyhow!("{} thresholds violated", violations.len()));
+        }
+        log::info!("All thresholds met");
+    }
+    Ok(())
+}
+
+/// Write through a temporary file next to `path` so readers never see a
+/// partial file.
+fn write_atomic(path: &Path, contents: &str) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, contents).with_context(|| format!("Failed to write {tmp:?}"))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Failed to rename {tmp:?} to {path:?}"))
+}
+
+fn acquire_lock(config: &config::Config) -> Result<File> {
+    let path = &config.lock_path;
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to open lock file {path:?}"))?;
 
-        if let Some(target) = &config.remote {
-            upload(target, &format!("{}.tgz", output_dir.as_ref().unwrap()))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(std::fs::TryLockError::WouldBlock) if config.wait_for_lock => {
+            log::info!("Another run is in progress, waiting for lock {path:?}");
+            file.lock()?;
         }
+        Err(std::fs::TryLockError::WouldBlock) => {
+            return Err(anyhow!(
+                "Another run is in progress (lock {path:?} is held), use --wait-for-lock to wait"
+            ));
+        }
+        Err(std::fs::TryLockError::Error(e)) => return Err(e.into()),
     }
 
-    status
+    log::info!("Acquired run lock {path:?}");
+    Ok(file)
 }
 
 fn print_uname() -> Result<()> {
@@ -102,63 +512,290 @@ fn print_uname() -> Result<()> {
     Ok(())
 }
 
-fn compress(output_dir: &str) -> Result<()> {
-    let outfile_path = format!("{output_dir}.tgz");
+/// Resolve `user:group` to ids, either part may be numeric.
+fn resolve_owner(owner: &str) -> Result<(u32, u32)> {
+    let (user, group) = owner
+        .split_once(':')
+        .ok_or(anyhow!("output_owner must be user:group, got {owner:?}"))?;
+    let uid = match user.parse() {
+        Ok(uid) => uid,
+        Err(_) => {
+            let name = std::ffi::CString::new(user)?;
+            // SAFETY: The entry is read right away, before any other getpw call.
+            unsafe { libc::getpwnam(name.as_ptr()).as_ref().map(|e| e.pw_uid) }
+                .ok_or(anyhow!("Unknown user {user:?}"))?
+        }
+    };
+    let gid = match group.parse() {
+        Ok(gid) => gid,
+        Err(_) => {
+            let name = std::ffi::CString::new(group)?;
+            // SAFETY: The entry is read right away, before any other getgr call.
+            unsafe { libc::getgrnam(name.as_ptr()).as_ref().map(|e| e.gr_gid) }
+                .ok_or(anyhow!("Unknown group {group:?}"))?
+        }
+    };
+    Ok((uid, gid))
+}
+
+fn chown_tree(path: &Path, uid: u32, gid: u32) -> Result<()> {
+    for entry in walkdir::WalkDir::new(path) {
+        let entry = entry?;
+        std::os::unix::fs::lchown(entry.path(), Some(uid), Some(gid))
+            .with_context(|| format!("Failed to chown {:?}", entry.path()))?;
+    }
+    Ok(())
+}
+
+/// Pack the batch directory next to it and return the path of the tarball.
+fn compress(config: &config::Config, output_dir: &str) -> Result<String> {
+    let outfile_path = format!("{output_dir}.{}", config.compression.extension());
     log::info!("Compressing to {outfile_path}");
-    let outfile = File::create(outfile_path)?;
-    let encoder = libflate::gzip::Encoder::new(outfile)?;
-    let mut tarball = tar::Builder::new(encoder);
+    let outfile = File::create(&outfile_path)?;
+    match config.compression {
+        config::Compression::Gzip => {
+            let encoder = libflate::gzip::Encoder::new(outfile)?;
+            append_tree(tar::Builder::new(encoder), output_dir)?
+                .finish()
+                .into_result()?;
+        }
+        config::Compression::Zstd => {
+            let mut zstd = Command::new("zstd");
+            zstd.arg("--quiet");
+            if let Some(level) = config.zstd_level {
+                zstd.arg(format!("-{level}"));
+            }
+            let mut child = zstd
+                .stdin(Stdio::piped())
+                .stdout(outfile)
+                .spawn()
+                .context("Failed to run zstd")?;
+            let stdin = child.stdin.take().ok_or(anyhow!("No stdin of zstd"))?;
+            // Closing its stdin lets zstd finish, also when tar failed
+            let result = append_tree(tar::Builder::new(stdin), output_dir).map(drop);
+            let status = child.wait()?;
+            result?;
+            status.check_status().context("zstd failed")?;
+        }
+        config::Compression::None => {
+            append_tree(tar::Builder::new(outfile), output_dir)?;
+        }
+    }
+    Ok(outfile_path)
+}
+
+fn check_zstd() -> Result<()> {
+    Command::new("zstd")
+        .arg("--version")
+        .stdout(Stdio::null())
+        .spawn()
+        .context("Failed to run zstd")?
+        .wait()?
+        .check_status()
+}
 
-    for file in walkdir::WalkDir::new(output_dir)
+fn append_tree<W: Write>(mut tarball: tar::Builder<W>, dir: &str) -> Result<W> {
+    for file in walkdir::WalkDir::new(dir)
         .into_iter()
         .filter_map(|e| e.ok())
         .filter(|e| e.clone().into_path().is_file())
     {
         tarball.append_path(file.into_path())?;
     }
+    Ok(tarball.into_inner()?)
+}
 
-    tarball.into_inner()?.finish().into_result()?;
+/// Shared by every request to the remotes, with the configured credentials
+/// as default headers. Built on first use.
+fn remote_client(config: &config::Config) -> Result<&'static reqwest::blocking::Client> {
+    static CLIENT: std::sync::OnceLock<reqwest::blocking::Client> = std::sync::OnceLock::new();
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
 
-    Ok(())
+    let authorization = match (&config.remote_token, &config.remote_basic_auth) {
+        (Some(config::Secret(token)), _) => Some(format!("Bearer {token}")),
+        (None, Some(config::Secret(credential))) => {
+            use base64::Engine;
+            let encoded = base64::engine::general_purpose::STANDARD.encode(credential);
+            Some(format!("Basic {encoded}"))
+        }
+        (None, None) => None,
+    };
+    let mut headers = reqwest::header::HeaderMap::new();
+    if let Some(authorization) = authorization {
+        let mut value = reqwest::header::HeaderValue::from_str(&authorization)
+            .context("Invalid remote credential")?;
+        // Keeps it out of the debug output of requests
+        value.set_sensitive(true);
+        headers.insert(reqwest::header::AUTHORIZATION, value);
+    }
+    let client = reqwest::blocking::Client::builder()
+        .default_headers(headers)
+        .build()
+        .context("Failed to build HTTP client")?;
+    Ok(CLIENT.get_or_init(|| client))
 }
 
-fn push_log(target: &url::Url, log: Arc<MemoryAppender>) -> Result<()> {
-    let client = reqwest::blocking::Client::new();
-    let buffer = log.data();
+/// Run `op` against every configured remote and decide the overall outcome
+/// according to `remote_quorum`.
+fn remote_fan_out(
+    config: &config::Config,
+    what: &str,
+    mut op: impl FnMut(&reqwest::blocking::Client, &url::Url) -> Result<()>,
+) -> Result<()> {
+    let client = remote_client(config)?;
+    let targets = config.remote_targets();
+    let mut errors = Vec::new();
+    for target in &targets {
+        let shown = config::redact_url(target);
+        match op(client, target) {
+            Ok(()) => log::info!("{what} to {shown} succeeded"),
+            Err(e) => {
+                log::warn!("{what} to {shown} failed: {e:?}");
+                errors.push(e);
+            }
+        }
+    }
 
-    client
-        .put(target.join("log/")?)
-        .body(buffer)
-        .send()?
-        .error_for_status()?;
-    Ok(())
+    let ok = match config.remote_quorum {
+        config::RemoteQuorum::Any => errors.len() < targets.len() || targets.is_empty(),
+        config::RemoteQuorum::All => errors.is_empty(),
+    };
+
+    match (ok, errors.pop()) {
+        (false, Some(e)) => Err(e.context(format!(
+            "{what} failed for {} of {} remotes",
+            errors.len() + 1,
+            targets.len()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// The longest wait between remote attempts, unless the first is longer.
+const MAX_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// The waits after each failed attempt, starting at `first` and doubling up
+/// to [`MAX_RETRY_DELAY`].
+fn backoff_delays(first: std::time::Duration) -> impl Iterator<Item = std::time::Duration> {
+    let cap = first.max(MAX_RETRY_DELAY);
+    std::iter::successors(Some(first), move |delay| {
+        Some(delay.saturating_mul(2).min(cap))
+    })
+}
+
+/// Run `op` up to `remote_attempts` times, waiting as [`backoff_delays`]
+/// says after every failure. Errors the server reports for the request
+/// itself are not retried.
+fn with_backoff<T>(
+    config: &config::Config,
+    what: &str,
+    mut op: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut delays = backoff_delays(std::time::Duration::from_secs(
+        config.remote_retry_delay_secs.into(),
+    ));
+    let mut attempt = 1;
+    loop {
+        let e = match op() {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        let permanent = e
+            .downcast_ref::<reqwest::Error>()
+            .and_then(reqwest::Error::status)
+            .is_some_and(|status| status.is_client_error());
+        if permanent || attempt >= config.remote_attempts || interrupt::forced() {
+            return Err(e);
+        }
+        let delay = delays.next().unwrap_or(MAX_RETRY_DELAY);
+        log::warn!(
+            "{what} attempt {attempt} of {} failed, retrying in {delay:?}: {e:?}",
+            config.remote_attempts
+        );
+        std::thread::sleep(delay);
+        attempt += 1;
+    }
+}
+
+fn push_log(
+    config: &config::Config,
+    client: &reqwest::blocking::Client,
+    target: &url::Url,
+    buffer: &[u8],
+) -> Result<()> {
+    with_backoff(config, "Log push", || {
+        let content_type = match config.log_format {
+            config::LogFormat::Pattern => "text/plain",
+            config::LogFormat::Json => "application/x-ndjson",
+        };
+        client
+            .put(target.join("log/")?)
+            .header(reqwest::header::CONTENT_TYPE, content_type)
+            .body(buffer.to_vec())
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    })
 }
 
-fn upload(target: &url::Url, filename: &str) -> Result<()> {
-    let file = std::fs::File::open(filename)?;
-    let client = reqwest::blocking::Client::new();
-    client
-        .put(target.join("upload/")?.join(filename)?)
-        .body(file)
-        .send()?
-        .error_for_status()?;
+fn spill_log(path: &Path, buffer: &[u8]) -> Result<()> {
+    File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {path:?}"))?
+        .write_all(buffer)?;
     Ok(())
 }
 
-fn shutdown(target: url::Url, status: Rc<Result<()>>) -> Result<()> {
+fn upload(
+    config: &config::Config,
+    client: &reqwest::blocking::Client,
+    target: &url::Url,
+    filename: &str,
+) -> Result<()> {
+    with_backoff(config, "Upload", || {
+        // Opened again for every try, a body is consumed by sending it
+        let file = std::fs::File::open(filename)?;
+        client
+            .put(target.join("upload/")?.join(filename)?)
+            .body(file)
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    })
+}
+
+fn shutdown<T>(config: &config::Config, status: Rc<Result<T>>) -> Result<()> {
     let code = match *status {
         Ok(_) => 0,
         Err(_) => 1,
     };
-    let client = reqwest::blocking::Client::new();
-    client
-        .put(target.join("shutdown/")?.join(&format!("{code}"))?)
-        .send()?
-        .error_for_status()?;
-    Ok(())
+    remote_fan_out(config, "Shutdown", |client, target| {
+        with_backoff(config, "Shutdown", || {
+            client
+                .put(target.join("shutdown/")?.join(&format!("{code}"))?)
+                .send()?
+                .error_for_status()?;
+            Ok(())
+        })
+    })
 }
 
-fn get_batch_dir(config: &config::Config) -> Result<String> {
+fn get_batch_dir(config: &config::Config, owner: Option<(u32, u32)>) -> Result<String> {
+    if let Some(batch_dir) = &config.resume {
+        if !batch_dir.is_dir() {
+            return Err(anyhow!("No batch to resume at {batch_dir:?}"));
+        }
+        log::info!("Resuming batch {batch_dir:?}");
+        return Ok(batch_dir
+            .to_str()
+            .ok_or(anyhow!("failed to convert path to string"))?
+            .into());
+    }
+
     let mut output_path = PathBuf::new();
     if let Some(path) = &config.output_path {
         output_path.push(path);
@@ -178,12 +815,17 @@ fn get_batch_dir(config: &config::Config) -> Result<String> {
     filename.push_str(&format!("-{name}"));
     filename.push_str(&format!(
         "-{}",
-        chrono::Local::now().format("%Y-%m-%d-%H%M")
+        chrono::Local::now().format(BATCH_TIME_FORMAT)
     ));
 
     output_path.push(filename);
 
     std::fs::create_dir(&output_path).context("failed to create batch dir")?;
+    // Early, so the owner can look into a running batch
+    if let Some((uid, gid)) = owner {
+        std::os::unix::fs::chown(&output_path, Some(uid), Some(gid))
+            .context("failed to chown batch dir")?;
+    }
 
     Ok(output_path
         .to_str()
@@ -192,13 +834,33 @@ fn get_batch_dir(config: &config::Config) -> Result<String> {
 }
 
 fn get_run_dir(prefix_dir: &str) -> Result<PathBuf> {
-    let mut run_dir = PathBuf::new();
-    run_dir.push(prefix_dir);
-    run_dir.push(format!(
-        "{}",
-        chrono::Local::now().format("%Y-%m-%d-%H%M-%f")
-    ));
-    std::fs::create_dir(&run_dir).context("failed to create run dir")?;
+    // Runs started within the timestamp resolution would get the same name, so
+    // append a counter until we find a name that is not taken.
+    const MAX_ATTEMPTS: u32 = 1000;
+
+    std::fs::create_dir_all(prefix_dir).context("failed to create run dir prefix")?;
+
+    let name = format!("{}", chrono::Local::now().format("%Y-%m-%d-%H%M-%f"));
+    for attempt in 0..MAX_ATTEMPTS {
+        let run_dir = PathBuf::from(prefix_dir).tap_mut(|p| match attempt {
+            0 => p.push(&name),
+            n => p.push(format!("{name}-{n}")),
+        });
+        match std::fs::create_dir(&run_dir) {
+            Ok(()) => return Ok(run_dir),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e).context("failed to create run dir"),
+        }
+    }
+
+    Err(anyhow!("failed to find unused run dir name for {name}"))
+}
+
+/// Run dir named after the sample index instead of the time, so the
+/// layout of a batch only depends on its config.
+fn get_sample_dir(prefix_dir: &str, sample: u32) -> Result<PathBuf> {
+    let run_dir = PathBuf::from(prefix_dir).tap_mut(|p| p.push(format!("sample-{sample:04}")));
+    std::fs::create_dir_all(&run_dir).context("failed to create sample dir")?;
     Ok(run_dir)
 }
 
@@ -212,277 +874,2217 @@ fn new_bar(enable: bool, total_configs: u64) -> Result<ProgressBar> {
     })
 }
 
+fn log_sample_summary(config: &config::Config, sample: u32, run_dir: &Path) {
+    if let Err(e) = results::log_sample_summary(config, sample, run_dir) {
+        log::warn!("Failed to summarize sample #{sample}: {e:?}");
+    }
+}
+
 fn run_workloads(
     output_dir: Option<&str>,
     config: &config::Config,
-    mut push_log: impl FnMut() -> Result<()>,
+    log_setup: Option<&logging::LogSetup>,
+    mut push_log: impl FnMut() -> Result<()> + Send,
 ) -> Result<()> {
     log::info!("Starting test loop");
-    use itertools::Itertools;
-    let configs = config
-        .block_sizes
-        .clone()
-        .into_iter()
-        .cartesian_product(config.jobcounts.clone())
-        .cartesian_product(config.workloads.clone())
-        .cartesian_product(config.queue_depths.clone())
-        .collect::<Vec<_>>();
+    let _interrupt = interrupt::install();
+    let mut plan = match &config.retry_failed {
+        Some(batch_dir) => {
+            log::info!("Retrying failed configs from {batch_dir:?}");
+            matrix::read_failed(batch_dir).context("Failed to load failed configs")?
+        }
+        None => matrix::build_plan(config),
+    };
+    let mut checkpoint = match &config.resume {
+        Some(batch_dir) => {
+            matrix::read_checkpoint(batch_dir).context("Failed to load checkpoint")?
+        }
+        None => matrix::Checkpoint::default(),
+    };
+    if config.resume.is_some() {
+        plan.retain(|run| !checkpoint.completed.contains(run));
+        log::info!(
+            "Resuming with {} configs done and {} left",
+            checkpoint.completed.len(),
+            plan.len()
+        );
+    }
 
-    if config.device == "nullb0" {
-        let _ = teardown_cnull();
+    for (duplicate, canonical) in matrix::duplicate_block_sizes(config) {
+        log::info!("Block size {duplicate} is the same as {canonical}, running it once");
     }
-    let _ = unload_module(config);
 
-    if let config::ModuleReloadPolicy::Once = config.module_reload_policy {
-        load_module(config).context("Load module once")?;
+    if config.auto_isolate_cpus {
+        let max_jobcount = config.jobcounts.iter().copied().max().unwrap_or(0);
+        let background_jobs = config.background_jobs.as_ref().map_or(0, |b| b.jobs);
+        cpus_allowed(config, 0, max_jobcount + background_jobs)
+            .context("Not enough isolated cpus")?;
     }
 
-    if config.amd_pstate_fixed_3ghz {
-        amd_pstate_fixed_3ghz().context("failed to configure amd-pstate")?;
+    if let Some(list) = &config.controller_cpus {
+        let max_jobcount = config.jobcounts.iter().copied().max().unwrap_or(0);
+        let background_jobs = config.background_jobs.as_ref().map_or(0, |b| b.jobs);
+        let fio_cpus =
+            cpus::parse_cpu_list(&cpus_allowed(config, 0, max_jobcount + background_jobs)?)?;
+        let shared = cpus::parse_cpu_list(list)?
+            .into_iter()
+            .filter(|c| fio_cpus.contains(c))
+            .collect::<Vec<_>>();
+        if !shared.is_empty() {
+            log::warn!(
+                "controller_cpus share cpus {} with fio",
+                cpus::format_cpu_list(&shared)
+            );
+        }
     }
 
-    if config.cpufreq_governor_performance {
-        set_governor().context("failed to set cpu frequency governor")?;
+    // With a fio server the device is on another machine
+    let local_device = config.fio_client.is_none();
+
+    if let Some(priority) = config.fio_rt_priority {
+        check_rt_priority_allowed(priority)?;
     }
 
-    if config.disable_boost_amd {
-        disable_boost_amd().context("failed to disable amd boost")?;
+    // Found missing only after the sweep, the batch would stay unpacked
+    if config.compress && config.compression == config::Compression::Zstd {
+        check_zstd().context("compression Zstd needs the zstd tool")?;
     }
 
-    if config.disable_boost_intel {
-        disable_turbo_intel().context("failed to disable intel turbo")?;
+    for config in &config.device_configs() {
+        check_device(config, local_device)?;
     }
 
-    if config.use_hugepages {
-        set_nr_hugepages(calculate_nr_hugepages(config)?)?;
+    // Allocation fails easily on fragmented memory, so it goes before any
+    // tuning that would be left behind on the error path.
+    if config.require_hugepages {
+        require_hugepages(calculate_nr_hugepages(config)?, SysfsRetry::new(config))
+            .context("Hugepages are required but not available")?;
+    } else if config.use_hugepages {
+        set_nr_hugepages(calculate_nr_hugepages(config)?, SysfsRetry::new(config))?;
     }
 
-    let total_configs = config.samples as u64 * configs.len() as u64;
-    let bar = new_bar(config.capture, total_configs).context("Failed to set up progress bar")?;
-    bar.set_message("Measuring:");
-    log::info!("Starting measurements, total configs: {total_configs}");
-    bar.println(format!(
-        "[+] Starting measurements, total configs: {total_configs}"
-    ));
+    initial_cleanup(config)?;
 
-    for i in 0..config.samples {
-        log::info!("Starting sample #{i}");
-        bar.println(format!("[+] Starting sample #{i}"));
-        let run_dir = output_dir
-            .map(get_run_dir)
-            .transpose()
-            .context("Failed to get run dir")?;
-        for (((block_size, jobcount), workload), queue_depth) in configs.clone() {
-            log::info!(
-                "Starting test qd:{queue_depth} bs:{block_size} jobs:{jobcount} wl:{workload}"
-            );
-            bar.println(format!(
-                "[+] Starting test qd:{queue_depth} bs:{block_size} jobs:{jobcount} wl:{workload}"
-            ));
-            setup(config).context("Failed to set up module")?;
-            run_single_workload(
-                config,
-                run_dir.as_deref(),
-                queue_depth,
-                &block_size,
-                jobcount,
-                &workload,
-            )
-            .context("Failed to run test")?;
-            teardown(config).context("Failed to tear down module")?;
-            bar.inc(1);
-            push_log()?;
-        }
+    if let config::ModuleReloadPolicy::Once = config.module_reload_policy {
+        load_module(config).context("Load module once")?;
     }
 
-    bar.println("[+] All done!");
-    log::info!("Test loop done");
-    Ok(())
-}
+    if let Some(freq) = config.amd_fixed_freq() {
+        amd_pstate_fixed_freq(config, freq).context("failed to configure amd-pstate")?;
+    }
 
-fn run_single_workload(
-    config: &config::Config,
-    output_dir_path: Option<&Path>,
-    queue_depth: u32,
-    block_size: &str,
-    jobcount: u32,
-    workload: &str,
-) -> Result<()> {
-    let run_output_id = format!(
-        "j{jobcount}-r{runtime}-w{workload}-bs{block_size}-qd{queue_depth}",
-        runtime = config.runtime,
-    );
+    if let Some(freq) = config.intel_fixed_freq() {
+        intel_fixed_freq(freq, SysfsRetry::new(config))
+            .context("failed to pin intel cpu frequency")?;
+    }
 
-    log::info!("Setting up workload: {run_output_id}");
+    if config.cpufreq_governor_performance {
+        set_governor(config).context("failed to set cpu frequency governor")?;
+    }
 
-    let run_file_path = |name: &str| -> Option<PathBuf> {
-        output_dir_path.map(|v| {
-            let mut p = PathBuf::from(v);
-            p.push(format!("{run_output_id}{name}"));
-            p
-        })
-    };
+    if config.amd_boost_off() {
+        disable_boost_amd(SysfsRetry::new(config)).context("failed to disable amd boost")?;
+    }
 
-    if config.prep {
-        let prep_stdout_path = run_file_path("-prep.stdout");
-        let prep_stderr_path = run_file_path("-prep.stderr");
+    if config.intel_turbo_off() {
+        disable_turbo_intel(SysfsRetry::new(config)).context("failed to disable intel turbo")?;
+    }
 
-        let mut command = Command::new(&config.fio);
-        command
-            .arg("--name=prep")
-            .arg("--rw=write")
-            .arg("--direct=1")
-            .arg("--bs=4k")
-            .arg(format!("--filename=/dev/{}", config.device));
+    // Closing it at the end of the batch lets the cpus idle deeply again
+    let _cpu_dma_latency = config
+        .disable_cstates
+        .then(cstates::hold_cpu_dma_latency)
+        .transpose()
+        .context("failed to disable deep C-states")?;
 
-        if config.capture {
-            command
-                .stdout(File::create(prep_stdout_path.unwrap())?)
-                .stderr(File::create(prep_stderr_path.unwrap())?);
+    let _cnull = match (config.configure_c_nullblk, config.cnull_lifecycle) {
+        (true, config::CnullLifecycle::Once) => {
+            setup_cnull(&config.device, &config.cnull).context("setup cnull once")?;
+            Some(CnullGuard)
         }
+        _ => None,
+    };
+    // Declared after the null block device it may sit on, so dropped first
+    let _dm = match &config.dm_setup {
+        Some(dm) if !dm_per_config(config) => {
+            setup_dm(dm, &config.device).context("setup dm once")?;
+            Some(DmGuard(&dm.name))
+        }
+        _ => None,
+    };
 
-        log::info!("Running prep command");
+    if config.smoke_test {
+        for device in entry_devices(config) {
+            smoke_test(config, device, output_dir)
+                .context("Smoke test failed, not starting the sweep")?;
+        }
+    }
 
-        let mut prep = || -> Result<()> { command.spawn()?.wait()?.check_status() };
-        prep().context("Prep work failed")?;
+    if let (true, Some(output_dir)) = (config.measure_overhead, output_dir) {
+        measure_overhead(config, Path::new(output_dir)).context("Failed to measure overhead")?;
     }
-    let block_size_bytes = byte_unit::Byte::parse_str(block_size, false)?.as_u64();
 
-    let output_path = run_file_path(".json");
-    let stdout_path = run_file_path(".stdout");
-    let stderr_path = run_file_path(".stderr");
+    if !config.precondition_passes().is_empty() {
+        for device in entry_devices(config) {
+            precondition(config, device, output_dir).context("Preconditioning failed")?;
+        }
+    }
 
-    let mut args = vec![
-        String::from("--group_reporting"),
-        String::from("--name=default"),
-        format!("--filename=/dev/{}", config.device),
-        String::from("--time_based=1"),
-        format!("--runtime={}", config.runtime),
-        String::from("--gtod_reduce=1"),
-        String::from("--clocksource=cpu"),
-        format!("--readwrite={}", workload),
-        format!("--blocksize={}", block_size_bytes),
-        String::from("--direct=1"),
-        String::from("--cpus_allowed_policy=split"),
-        format!("--cpus_allowed=0-{}", jobcount - 1),
-        format!("--numjobs={}", jobcount),
-        String::from("--ioengine=io_uring"),
+    if let (true, Some(output_dir)) = (config.capture_power, output_dir) {
+        if config.power_baseline_secs != 0 {
+            let path = PathBuf::from(output_dir).join(power::BASELINE_FILE);
+            power::measure_baseline(config.power_baseline_secs, &path)
+                .context("Failed to measure idle power")?;
+        }
+    }
+
+    let min_free_space = config.min_free_space_bytes()?;
+    if let (Some(min), Some(output_dir)) = (min_free_space, output_dir) {
+        check_free_space(Path::new(output_dir), min)?;
+    }
+
+    let total_configs = plan.len() as u64;
+    let bar = new_bar(config.capture, total_configs).context("Failed to set up progress bar")?;
+    bar.set_message("Measuring:");
+    log::info!("Starting measurements, total configs: {total_configs}");
+    bar.println(format!(
+        "[+] Starting measurements, total configs: {total_configs}"
+    ));
+
+    let mut cache = config
+        .use_cache
+        .as_deref()
+        .map(cache::Cache::open)
+        .transpose()
+        .context("Failed to open result cache")?;
+    let cache_contexts = cache
+        .as_ref()
+        .map(|_| {
+            config
+                .device_configs()
+                .iter()
+                .map(|config| Ok((config.device.clone(), cache_context(config)?)))
+                .collect::<Result<HashMap<_, _>>>()
+        })
+        .transpose()?;
+    // A resumed batch adds to what it recorded before it stopped
+    let resumed = config.resume.as_deref();
+    let mut cached = match resumed {
+        Some(dir) => cache::read_cached(dir)?,
+        None => Vec::new(),
+    };
+
+    let mut failed: Vec<PlannedRun> = match resumed {
+        Some(dir) if dir.join(matrix::FAILED_FILE).exists() => matrix::read_failed(dir)?,
+        _ => Vec::new(),
+    };
+    // Those are in the plan again and only count if they fail again
+    failed.retain(|run| !plan.contains(run));
+    let sweep_started = std::time::Instant::now();
+    let write_status = |completed: u64, failed: usize, current: Option<&PlannedRun>| {
+        let Some(path) = &config.status_file else {
+            return Ok(());
+        };
+        let status = matrix::Status::new(
+            completed,
+            total_configs,
+            failed,
+            current,
+            sweep_started.elapsed(),
+        );
+        matrix::write_status(path, &status)
+    };
+    if config.concurrent_devices() > 1 {
+        return run_devices_parallel(
+            config,
+            output_dir,
+            &plan,
+            checkpoint,
+            &bar,
+            push_log,
+            &write_status,
+        );
+    }
+
+    let bandwidth_unit = results::BandwidthUnit::parse(&config.bw_unit)?;
+    let mut timeline = match resumed {
+        Some(dir) if config.capture_timeline => matrix::read_timeline(dir)?,
+        _ => Vec::new(),
+    };
+    let mut current_sample = None;
+    let mut run_dir = None;
+    for (index, run) in plan.iter().enumerate() {
+        // Each config tears itself down, so there is nothing left to undo
+        if interrupt::requested() {
+            failed.extend(plan[index..].iter().cloned());
+            write_failed(output_dir, &failed)?;
+            bar.println("[!] Interrupted, stopping");
+            return Err(anyhow!(
+                "Interrupted with {} of {total_configs} configs left",
+                plan.len() - index
+            ));
+        }
+
+        if let (Some(min), Some(dir)) = (min_free_space, output_dir) {
+            if let Err(e) = check_free_space(Path::new(dir), min) {
+                // The rest can be picked up again with retry_failed
+                failed.extend(plan[index..].iter().cloned());
+                write_failed(output_dir, &failed)?;
+                bar.println("[!] Out of space, stopping");
+                return Err(e.context(format!(
+                    "Stopped with {} of {total_configs} configs left",
+                    plan.len() - index
+                )));
+            }
+        }
+
+        if current_sample != Some(run.sample) {
+            if let (Some(sample), Some(run_dir)) = (current_sample, run_dir.as_deref()) {
+                log_sample_summary(config, sample, run_dir);
+            }
+            let i = run.sample;
+            log::info!("Starting sample #{i}");
+            bar.println(format!("[+] Starting sample #{i}"));
+            run_dir = output_dir
+                .map(|dir| sample_run_dir(config, dir, i, &mut checkpoint))
+                .transpose()
+                .context("Failed to get run dir")?;
+            current_sample = Some(i);
+            for config in &config.device_configs() {
+                reset_device(config).context("Failed to reset device before sample")?;
+            }
+        }
+
+        // From here on the config of the device the entry runs on
+        let device_config;
+        let config = match &run.entry.device {
+            Some(device) => {
+                device_config = config.for_device(device);
+                &device_config
+            }
+            None => config,
+        };
+
+        write_status(index as u64, failed.len(), Some(run))?;
+        let entry = &run.entry;
+        log::info!("Starting test {entry}");
+        bar.println(format!("[+] Starting test {entry}"));
+        let progress = matrix::Progress {
+            index: index as u64,
+            total: total_configs,
+            sample: run.sample,
+            entry,
+        };
+
+        let cache_key = cache_contexts
+            .as_ref()
+            .map(|contexts| cache_key(config, &contexts[&config.device], entry, run.sample))
+            .transpose()?;
+        if let (Some(cache), Some(key), Some(run_dir), Some(dir)) =
+            (&cache, &cache_key, &run_dir, output_dir)
+        {
+            if let Some(record) = cache.get(key) {
+                cache
+                    .restore(key, run_dir)
+                    .context("Failed to restore cached result")?;
+                log::info!("Using cached result of {entry} from {}", record.batch_dir);
+                bar.println(format!("[+] Using cached result of {entry}"));
+                cached.push(record.clone());
+                cache::write_cached(Path::new(dir), &cached)?;
+                checkpoint.completed.push(run.clone());
+                matrix::write_checkpoint(Path::new(dir), &checkpoint)?;
+                bar.inc(1);
+                continue;
+            }
+        }
+
+        let attempt = || -> Result<()> {
+            setup(config, entry).context("Failed to set up module")?;
+            let (start, started) = (chrono::Utc::now(), std::time::Instant::now());
+            let result = run_single_workload(config, run_dir.as_deref(), &progress);
+            if config.capture_timeline {
+                timeline.push(matrix::TimelineEntry::ended(
+                    run.sample,
+                    entry,
+                    start,
+                    started,
+                    result.is_ok(),
+                ));
+            }
+            result.context("Failed to run test")?;
+            teardown(config).context("Failed to tear down module")?;
+            Ok(())
+        };
+
+        let run_log = match (config.per_config_log, log_setup, &run_dir) {
+            (true, Some(log_setup), Some(run_dir)) => {
+                let id = matrix::run_output_id(config, entry);
+                log_setup
+                    .set_run_log(Some(&run_dir.join(format!("{id}.log"))))
+                    .context("Failed to set up config log")?;
+                Some(log_setup)
+            }
+            _ => None,
+        };
+
+        let result = with_retries(config, entry, run_dir.as_deref(), &bar, attempt);
+
+        if let Some(log_setup) = run_log {
+            log_setup
+                .set_run_log(None)
+                .context("Failed to reset config log")?;
+        }
+
+        if let (Ok(()), Some(cache), Some(key), Some(run_dir), Some(dir)) =
+            (&result, &mut cache, cache_key, &run_dir, output_dir)
+        {
+            let record = cache::CacheRecord {
+                key,
+                sample: run.sample,
+                entry: entry.clone(),
+                batch_dir: dir.to_string(),
+                measured_at: chrono::Utc::now()
+                    .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            };
+            // A result that is not cached is only measured again next time
+            let stored = run_outputs(run_dir, &matrix::run_output_id(config, entry))
+                .and_then(|files| cache.store(record, &files));
+            if let Err(e) = stored {
+                log::warn!("Failed to cache result of {entry}: {e:?}");
+            }
+        }
+
+        if let (true, Some(dir)) = (config.capture_timeline, output_dir) {
+            matrix::write_timeline(Path::new(dir), &timeline)?;
+        }
+
+        if let (Ok(()), Some(dir)) = (&result, output_dir) {
+            checkpoint.completed.push(run.clone());
+            matrix::write_checkpoint(Path::new(dir), &checkpoint)?;
+        }
+
+        // Only a glance while the sweep runs, a bad output is no reason to stop
+        if let (Ok(()), Some(run_dir)) = (&result, &run_dir) {
+            let id = matrix::run_output_id(config, entry);
+            let path = run_dir.join(format!("{id}{}", results::output_suffix(config)));
+            match results::parse_output(config, &path) {
+                Ok(output) => bar.println(format!(
+                    "[+] Finished test {entry}: {}",
+                    results::brief_summary(&output, bandwidth_unit)
+                )),
+                Err(e) => log::warn!("Failed to summarize {entry}: {e:?}"),
+            }
+        }
+
+        if let Err(e) = result {
+            failed.push(run.clone());
+            if !config.continue_on_error {
+                write_failed(output_dir, &failed)?;
+                return Err(e);
+            }
+            log::error!("Sample #{} {entry} failed, continuing: {e:?}", run.sample);
+            bar.println(format!("[!] Test {entry} failed, continuing"));
+            if let Err(e) = teardown(config) {
+                log::warn!("Teardown after failure failed: {e:?}");
+            }
+        }
+
+        bar.inc(1);
+        push_log()?;
+    }
+    if let (Some(sample), Some(run_dir)) = (current_sample, run_dir.as_deref()) {
+        log_sample_summary(config, sample, run_dir);
+    }
+    write_status(total_configs, failed.len(), None)?;
+
+    if !failed.is_empty() {
+        log::error!("{} of {total_configs} configs failed:", failed.len());
+        for run in &failed {
+            log::error!("  sample #{} {}", run.sample, run.entry);
+        }
+        write_failed(output_dir, &failed)?;
+        return Err(anyhow!("{} configs failed", failed.len()));
+    }
+    if resumed.is_some_and(|dir| dir.join(matrix::FAILED_FILE).exists()) {
+        // What failed before the batch stopped has passed since
+        write_failed(output_dir, &failed)?;
+    }
+
+    bar.println("[+] All done!");
+    log::info!("Test loop done");
+    Ok(())
+}
+
+/// Write status.json with the configs finished and failed so far and the
+/// one running.
+type WriteStatus<'a> = dyn Fn(u64, usize, Option<&PlannedRun>) -> Result<()> + Sync + 'a;
+
+/// What the device workers of `parallel_devices` share.
+struct ParallelSweep<'a> {
+    output_dir: Option<&'a str>,
+    total_configs: u64,
+    bar: &'a ProgressBar,
+    write_status: &'a WriteStatus<'a>,
+    progress: std::sync::Mutex<SweepProgress>,
+}
+
+struct SweepProgress {
+    checkpoint: matrix::Checkpoint,
+    failed: Vec<PlannedRun>,
+    /// Configs finished, failed or not.
+    finished: u64,
+}
+
+/// Run the plan with a thread for each device, see `parallel_devices`. A
+/// failing device only stops its own configs, the errors of all devices
+/// are reported together once every device is done.
+fn run_devices_parallel(
+    config: &config::Config,
+    output_dir: Option<&str>,
+    plan: &[PlannedRun],
+    checkpoint: matrix::Checkpoint,
+    bar: &ProgressBar,
+    push_log: impl FnMut() -> Result<()> + Send,
+    write_status: &WriteStatus<'_>,
+) -> Result<()> {
+    let total_configs = plan.len() as u64;
+    let workers = config.concurrent_devices();
+    log::info!(
+        "Running {} devices, {workers} in parallel",
+        config.devices.len()
+    );
+    let sweep = ParallelSweep {
+        output_dir,
+        total_configs,
+        bar,
+        write_status,
+        progress: std::sync::Mutex::new(SweepProgress {
+            checkpoint,
+            failed: Vec::new(),
+            finished: 0,
+        }),
+    };
+    let push_log = std::sync::Mutex::new(push_log);
+
+    // Each worker takes the next device once done with one
+    let devices = std::sync::Mutex::new(config.devices.iter());
+    let errors = std::thread::scope(|scope| {
+        let workers = (0..workers)
+            .map(|_| {
+                let (sweep, push_log, devices) = (&sweep, &push_log, &devices);
+                scope.spawn(move || {
+                    let mut errors = Vec::new();
+                    while let Some(device) = devices.lock().unwrap().next() {
+                        let runs = plan
+                            .iter()
+                            .filter(|run| run.entry.device.as_ref() == Some(device))
+                            .collect::<Vec<_>>();
+                        let mut push_log = || push_log.lock().unwrap()();
+                        if let Err(e) =
+                            run_device(&config.for_device(device), &runs, sweep, &mut push_log)
+                        {
+                            errors.push(e.context(format!("Device {device} stopped")));
+                        }
+                    }
+                    errors
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .flat_map(|worker| match worker.join() {
+                Ok(errors) => errors,
+                Err(_) => vec![anyhow!("Device worker panicked")],
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let progress = sweep.progress.into_inner().unwrap();
+    if let Some(dir) = output_dir {
+        for (sample, name) in &progress.checkpoint.run_dirs {
+            log_sample_summary(config, *sample, &Path::new(dir).join(name));
+        }
+    }
+    write_status(total_configs, progress.failed.len(), None)?;
+    write_failed(output_dir, &progress.failed)?;
+
+    for e in &errors {
+        log::error!("{e:?}");
+    }
+    if !progress.failed.is_empty() {
+        log::error!(
+            "{} of {total_configs} configs failed:",
+            progress.failed.len()
+        );
+        for run in &progress.failed {
+            log::error!("  sample #{} {}", run.sample, run.entry);
+        }
+    }
+    match (errors.len(), progress.failed.len()) {
+        (0, 0) => {
+            bar.println("[+] All done!");
+            log::info!("Test loop done");
+            Ok(())
+        }
+        (0, failed) => Err(anyhow!("{failed} configs failed")),
+        (stopped, failed) => Err(anyhow!(
+            "{stopped} devices stopped early, {failed} configs failed"
+        )),
+    }
+}
+
+/// The configs of one device in a parallel sweep, in plan order.
+fn run_device(
+    config: &config::Config,
+    runs: &[&PlannedRun],
+    sweep: &ParallelSweep,
+    push_log: &mut dyn FnMut() -> Result<()>,
+) -> Result<()> {
+    let bar = sweep.bar;
+    let bandwidth_unit = results::BandwidthUnit::parse(&config.bw_unit)?;
+    let mut current_sample = None;
+    let mut run_dir = None;
+    for (index, run) in runs.iter().enumerate() {
+        if interrupt::requested() {
+            let mut progress = sweep.progress.lock().unwrap();
+            progress
+                .failed
+                .extend(runs[index..].iter().map(|run| (*run).clone()));
+            return Err(anyhow!(
+                "Interrupted with {} configs left",
+                runs.len() - index
+            ));
+        }
+
+        if current_sample != Some(run.sample) {
+            let i = run.sample;
+            log::info!("Starting sample #{i} on {}", config.device);
+            // The devices share the sample, each in a directory of its own
+            run_dir = sweep
+                .output_dir
+                .map(|dir| -> Result<PathBuf> {
+                    let checkpoint = &mut sweep.progress.lock().unwrap().checkpoint;
+                    let run_dir = sample_run_dir(config, dir, i, checkpoint)?.join(&config.device);
+                    std::fs::create_dir_all(&run_dir)?;
+                    Ok(run_dir)
+                })
+                .transpose()
+                .context("Failed to get run dir")?;
+            current_sample = Some(i);
+            reset_device(config).context("Failed to reset device before sample")?;
+        }
+
+        let entry = &run.entry;
+        log::info!("Starting test {entry}");
+        bar.println(format!("[+] Starting test {entry}"));
+        let progress = {
+            let progress = sweep.progress.lock().unwrap();
+            (sweep.write_status)(progress.finished, progress.failed.len(), Some(run))?;
+            matrix::Progress {
+                index: progress.finished,
+                total: sweep.total_configs,
+                sample: run.sample,
+                entry,
+            }
+        };
+
+        let attempt = || -> Result<()> {
+            setup(config, entry).context("Failed to set up module")?;
+            run_single_workload(config, run_dir.as_deref(), &progress)
+                .context("Failed to run test")?;
+            teardown(config).context("Failed to tear down module")
+        };
+        let result = with_retries(config, entry, run_dir.as_deref(), bar, attempt);
+
+        {
+            let mut progress = sweep.progress.lock().unwrap();
+            progress.finished += 1;
+            match &result {
+                Ok(()) => {
+                    if let Some(dir) = sweep.output_dir {
+                        progress.checkpoint.completed.push((*run).clone());
+                        matrix::write_checkpoint(Path::new(dir), &progress.checkpoint)?;
+                    }
+                }
+                Err(_) => progress.failed.push((*run).clone()),
+            }
+        }
+        bar.inc(1);
+
+        match (result, &run_dir) {
+            (Ok(()), Some(run_dir)) => {
+                let id = matrix::run_output_id(config, entry);
+                let path = run_dir.join(format!("{id}{}", results::output_suffix(config)));
+                match results::parse_output(config, &path) {
+                    Ok(output) => bar.println(format!(
+                        "[+] Finished test {entry}: {}",
+                        results::brief_summary(&output, bandwidth_unit)
+                    )),
+                    Err(e) => log::warn!("Failed to summarize {entry}: {e:?}"),
+                }
+            }
+            (Ok(()), None) => {}
+            (Err(e), _) if !config.continue_on_error => return Err(e),
+            (Err(e), _) => {
+                log::error!("Sample #{} {entry} failed, continuing: {e:?}", run.sample);
+                bar.println(format!("[!] Test {entry} failed, continuing"));
+                if let Err(e) = teardown(config) {
+                    log::warn!("Teardown after failure failed: {e:?}");
+                }
+            }
+        }
+
+        push_log()?;
+    }
+    Ok(())
+}
+
+/// The run directory of `sample`: the one the checkpoint has for it, or a
+/// new one that is recorded there.
+fn sample_run_dir(
+    config: &config::Config,
+    output_dir: &str,
+    sample: u32,
+    checkpoint: &mut matrix::Checkpoint,
+) -> Result<PathBuf> {
+    let run_dir = match (
+        checkpoint.run_dirs.get(&sample),
+        config.deterministic_layout,
+    ) {
+        (Some(name), _) => Path::new(output_dir).join(name),
+        (None, true) => get_sample_dir(output_dir, sample)?,
+        (None, false) => get_run_dir(output_dir)?,
+    };
+    if let Some(name) = run_dir.file_name() {
+        checkpoint
+            .run_dirs
+            .insert(sample, name.to_string_lossy().into_owned());
+    }
+    Ok(run_dir)
+}
+
+/// Run `attempt` again while it fails, up to `workload_retries` times.
+/// The outputs of each failed attempt are moved out of the way first.
+fn with_retries(
+    config: &config::Config,
+    entry: &MatrixEntry,
+    run_dir: Option<&Path>,
+    bar: &ProgressBar,
+    mut attempt: impl FnMut() -> Result<()>,
+) -> Result<()> {
+    let mut result = attempt();
+    let mut attempts = 1;
+    for retry in 1..=config.workload_retries {
+        let Err(e) = &result else {
+            break;
+        };
+        // A hung fio likely hangs again, and after Ctrl-C we stop anyway
+        if e.downcast_ref::<FioTimeout>().is_some() || interrupt::requested() {
+            break;
+        }
+        log::warn!(
+            "Test {entry} failed, retry {retry} of {}: {e:?}",
+            config.workload_retries
+        );
+        bar.println(format!("[!] Test {entry} failed, retrying"));
+        if let Err(e) = teardown(config) {
+            log::warn!("Teardown before retry failed: {e:?}");
+        }
+        if let Some(run_dir) = run_dir {
+            archive_run_outputs(run_dir, &matrix::run_output_id(config, entry), attempts)
+                .context("Failed to move outputs of failed attempt")?;
+        }
+        result = attempt();
+        attempts += 1;
+    }
+    if attempts > 1 {
+        result = result.with_context(|| format!("Failed after {attempts} attempts"));
+    }
+    result
+}
+
+/// Bytes available to unprivileged users on the filesystem of `path`.
+fn free_space(path: &Path) -> Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: statvfs only writes into `stat`, the path is NUL terminated.
+    let stat = unsafe {
+        let mut stat: libc::statvfs = std::mem::zeroed();
+        if libc::statvfs(c_path.as_ptr(), &mut stat) != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Failed to statvfs {path:?}"));
+        }
+        stat
+    };
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+fn check_free_space(output_dir: &Path, min: u64) -> Result<()> {
+    let free = free_space(output_dir)?;
+    if free < min {
+        return Err(anyhow!(
+            "Only {} free on the output filesystem, below min_free_space {}",
+            byte_unit::Byte::from_u64(free).get_appropriate_unit(byte_unit::UnitType::Binary),
+            byte_unit::Byte::from_u64(min).get_appropriate_unit(byte_unit::UnitType::Binary)
+        ));
+    }
+    Ok(())
+}
+
+/// Run the smallest config briefly through the full setup and teardown, to
+/// catch a broken setup in seconds instead of after the first config.
+fn smoke_test(
+    config: &config::Config,
+    device: Option<&str>,
+    output_dir: Option<&str>,
+) -> Result<()> {
+    const RUNTIME_SECS: u32 = 5;
+
+    let config = &device.map_or_else(|| config.clone(), |d| config.for_device(d));
+    let smoke_config = config::Config {
+        runtime: RUNTIME_SECS,
+        ramp: None,
+        ..config.clone()
+    };
+    let entry = MatrixEntry {
+        block_size: String::from("4k"),
+        jobcount: 1,
+        workload: config
+            .workloads
+            .first()
+            .cloned()
+            .ok_or(anyhow!("workloads empty"))?,
+        queue_depth: 1,
+        scheduler: config
+            .schedulers
+            .first()
+            .cloned()
+            .ok_or(anyhow!("schedulers empty"))?,
+        rate: None,
+        thinktime: None,
+        labels: Vec::new(),
+        device: device.map(String::from),
+    };
+    let progress = matrix::Progress {
+        index: 0,
+        total: 1,
+        sample: 0,
+        entry: &entry,
+    };
+    let smoke_dir = output_dir
+        .map(|dir| -> Result<PathBuf> {
+            let path = PathBuf::from(dir).join(results::SMOKE_DIR);
+            std::fs::create_dir_all(&path).context("Failed to create smoke test dir")?;
+            Ok(path)
+        })
+        .transpose()?;
+
+    log::info!("Running smoke test {entry}");
+    let result = setup(&smoke_config, &entry)
+        .and_then(|()| run_single_workload(&smoke_config, smoke_dir.as_deref(), &progress));
+    if let Err(e) = teardown(&smoke_config) {
+        log::warn!("Teardown after smoke test failed: {e:?}");
+    }
+    result?;
+    log::info!("Smoke test passed");
+    Ok(())
+}
+
+/// The `device` of the entries in the matrix, once per device swept.
+fn entry_devices(config: &config::Config) -> Vec<Option<&str>> {
+    match config.devices.is_empty() {
+        true => vec![None],
+        false => config.devices.iter().map(|d| Some(d.as_str())).collect(),
+    }
+}
+
+/// Checks of the device a config runs on that can be done before the
+/// sweep. With a fio server the device is on another machine and only
+/// its path is used.
+fn check_device(config: &config::Config, local_device: bool) -> Result<()> {
+    if let (config::IoEngine::IoUringCmd, true) = (config.ioengine, local_device) {
+        let path = config.target_path();
+        if !Path::new(&path).exists() {
+            return Err(anyhow!("NVMe generic device {path} does not exist"));
+        }
+    }
+
+    if is_destructive(config) && !config.force_destructive && local_device {
+        check_not_mounted(&config.device).context("Refusing to run destructive workloads")?;
+    }
+
+    // A device we or the module create is only checked once it exists
+    if !creates_device(config) && local_device {
+        check_block_device(Path::new("/dev"), Path::new(SYSFS_BLOCK), &config.device)?;
+    }
+
+    // A device we create ourselves is only checked once it exists
+    if config.require_device_fits && !config.configure_c_nullblk && local_device {
+        let matrix = matrix::build_matrix(config);
+        check_device_fits(&config.device, matrix.iter()).context("Device too small")?;
+    }
+
+    if !config.configure_c_nullblk && local_device {
+        match read_queue_limits(Path::new(SYSFS_BLOCK), &config.device) {
+            Ok(limits) => {
+                log::info!(
+                    "{}: logical block size {}, physical block size {}, optimal IO size {}",
+                    config.device,
+                    limits.logical_block_size,
+                    limits.physical_block_size,
+                    limits.optimal_io_size
+                );
+                for warning in misaligned_block_sizes(&limits, &config.all_block_sizes()) {
+                    log::warn!("{warning}");
+                }
+            }
+            Err(e) => log::warn!("Not checking block size alignment: {e:?}"),
+        }
+    }
+    Ok(())
+}
+
+/// Fail before the sweep rather than on the first fio spawn if we may not
+/// use realtime scheduling.
+fn check_rt_priority_allowed(priority: i32) -> Result<()> {
+    // SAFETY: geteuid cannot fail and getrlimit only writes into `limit`.
+    let (euid, limit) = unsafe {
+        let mut limit: libc::rlimit = std::mem::zeroed();
+        if libc::getrlimit(libc::RLIMIT_RTPRIO, &mut limit) != 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to get RLIMIT_RTPRIO");
+        }
+        (libc::geteuid(), limit)
+    };
+    if euid != 0 && limit.rlim_cur < priority as libc::rlim_t {
+        return Err(anyhow!(
+            "fio_rt_priority {priority} needs root or CAP_SYS_NICE, RLIMIT_RTPRIO is {}",
+            limit.rlim_cur
+        ));
+    }
+    Ok(())
+}
+
+fn write_failed(output_dir: Option<&str>, failed: &[PlannedRun]) -> Result<()> {
+    match output_dir {
+        Some(dir) => matrix::write_failed(Path::new(dir), failed),
+        None => Ok(()),
+    }
+}
+
+/// Move everything a failed attempt of a run wrote out of the way, so a
+/// retry cannot leave partial output behind, e.g. `a.json` of the first
+/// attempt becomes `a.attempt1.json`. The log of the config is kept across
+/// attempts.
+fn archive_run_outputs(run_dir: &Path, run_output_id: &str, attempt: u32) -> Result<()> {
+    for path in run_outputs(run_dir, run_output_id)? {
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        let rest = &name[run_output_id.len()..];
+        let target = run_dir.join(format!("{run_output_id}.attempt{attempt}{rest}"));
+        std::fs::rename(&path, &target)
+            .with_context(|| format!("Failed to move {path:?} to {target:?}"))?;
+    }
+    Ok(())
+}
+
+/// Files of a run in `run_dir`, without the log of the config and the
+/// outputs of failed attempts.
+fn run_outputs(run_dir: &Path, run_output_id: &str) -> Result<Vec<PathBuf>> {
+    let mut outputs = Vec::new();
+    for entry in std::fs::read_dir(run_dir)? {
+        let path = entry?.path();
+        let is_run_output = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_prefix(run_output_id))
+            .is_some_and(|rest| {
+                rest.starts_with(['.', '-']) && rest != ".log" && !rest.starts_with(".attempt")
+            });
+        if is_run_output {
+            outputs.push(path);
+        }
+    }
+    Ok(outputs)
+}
+
+/// What a result depends on besides the matrix entry: the device, the
+/// kernel, the module under test, how fio is run, the tuning around the
+/// run and the tracing alongside it. Every option that changes a result
+/// belongs here, even when it also ends up in the fio arguments.
+fn cache_context(config: &config::Config) -> Result<String> {
+    let kernel = std::fs::read_to_string("/proc/sys/kernel/osrelease")
+        .context("Failed to read kernel release")?;
+    let device_dir = Path::new(SYSFS_BLOCK).join(&config.device).join("device");
+    let device_ids = ["wwid", "serial", "model", "firmware_rev"]
+        .iter()
+        .filter_map(|name| std::fs::read_to_string(device_dir.join(name)).ok())
+        .map(|id| id.trim().to_string())
+        .collect::<Vec<_>>();
+    Ok(serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "kernel": kernel.trim(),
+        "device": {
+            "name": config.device,
+            "ids": device_ids,
+            "bytes": device_size_bytes(&config.device).ok(),
+            "dm_setup": config.dm_setup,
+            "prep": config.prep,
+            "preconditioning": config.precondition_passes(),
+        },
+        "module": {
+            "name": config.module,
+            "args": config.module_args,
+            "reload_policy": config.module_reload_policy,
+            "configure_c_nullblk": config.configure_c_nullblk,
+            "cnull": config.cnull,
+            "cnull_lifecycle": config.cnull_lifecycle,
+        },
+        "fio": {
+            "path": config.fio,
+            "launcher": config.fio_launcher,
+            "client": config.fio_client,
+            "runtime": config.runtime,
+            "ramp": config.ramp,
+            "measure_ramp": config.measure_ramp,
+            "ioengine": config.ioengine,
+            "hipri": config.hipri,
+            "extra_args": config.extra_fio_args,
+            "cpus_allowed": config.cpus_allowed,
+            "cpus_allowed_policy": config.cpus_allowed_policy,
+            "nice": config.fio_nice,
+            "rt_priority": config.fio_rt_priority,
+            "use_hugepages": config.use_hugepages,
+            "deterministic_layout": config.deterministic_layout,
+            "thinktime_blocks": config.thinktime_blocks,
+            "startdelay": config.startdelay,
+            "io_size_cap": config.io_size_cap,
+            "verify": config.verify,
+            "verify_separately": config.verify_separately,
+            "capture": config.capture,
+            "latency_breakdown": config.latency_breakdown,
+            "histogram_percentiles": config.histogram_percentiles,
+            "per_job_reporting": config.per_job_reporting,
+            "terse_results": config.terse_results,
+            "background_jobs": config.background_jobs,
+            "inter_sample_reset": config.inter_sample_reset,
+        },
+        "tuning": {
+            "auto_isolate_cpus": config.auto_isolate_cpus,
+            "controller_cpus": config.controller_cpus,
+            "disable_cstates": config.disable_cstates,
+            "amd_pstate_fixed_3ghz": config.amd_pstate_fixed_3ghz,
+            "intel_fixed_freq": config.intel_fixed_freq,
+            "cpu_vendor": config.cpu_vendor,
+            "disable_boost": config.disable_boost,
+            "fixed_freq": config.fixed_freq,
+            "cpufreq_governor_performance": config.cpufreq_governor_performance,
+            "disable_boost_amd": config.disable_boost_amd,
+            "disable_boost_intel": config.disable_boost_intel,
+        },
+        "tracing": {
+            "perf_events": config.perf_events,
+            "blktrace": config.capture_blktrace,
+            "power": config.capture_power,
+        },
+    })
+    .to_string())
+}
+
+/// Cache key of one sample of `entry`. Samples are cached separately, so a
+/// rerun takes as many distinct measurements as the original.
+fn cache_key(
+    config: &config::Config,
+    context: &str,
+    entry: &MatrixEntry,
+    sample: u32,
+) -> Result<String> {
+    let args = fio_args(config, entry, config.runtime, config.ramp, None)?;
+    Ok(cache::key(&format!(
+        "{context}\n{}\n{}\n{sample}",
+        serde_json::to_string(entry)?,
+        args.join(" ")
+    )))
+}
+
+/// Run fio's null engine, which completes IO without a device, at each
+/// jobcount and queue depth of the matrix. This is the IOPS ceiling of fio
+/// and the cpus, to tell device bound results from software bound ones.
+fn measure_overhead(config: &config::Config, batch_dir: &Path) -> Result<()> {
+    const RUNTIME_SECS: u32 = 10;
+
+    let dir = batch_dir.join(results::OVERHEAD_DIR);
+    std::fs::create_dir_all(&dir).context("Failed to create overhead dir")?;
+
+    let mut geometries = Vec::new();
+    for entry in matrix::build_matrix(config) {
+        if !geometries.contains(&(entry.jobcount, entry.queue_depth)) {
+            geometries.push((entry.jobcount, entry.queue_depth));
+        }
+    }
+
+    let mut ceilings = Vec::new();
+    for (jobcount, queue_depth) in geometries {
+        let name = format!("j{jobcount}-qd{queue_depth}");
+        let output_path = dir.join(format!("{name}.json"));
+        let args = vec![
+            String::from("--name=overhead"),
+            String::from("--ioengine=null"),
+            String::from("--size=1g"),
+            String::from("--readwrite=randread"),
+            String::from("--blocksize=4096"),
+            String::from("--gtod_reduce=1"),
+            String::from("--clocksource=cpu"),
+            format!(
+                "--cpus_allowed_policy={}",
+                config.cpus_allowed_policy.fio_name()
+            ),
+            format!("--cpus_allowed={}", cpus_allowed(config, 0, jobcount)?),
+            format!("--numjobs={jobcount}"),
+            format!("--iodepth={queue_depth}"),
+            String::from("--time_based=1"),
+            format!("--runtime={RUNTIME_SECS}"),
+            String::from("--group_reporting"),
+            String::from("--output-format=json"),
+            format!(
+                "--output={}",
+                output_path
+                    .to_str()
+                    .ok_or(anyhow!("path conversion error"))?
+            ),
+        ];
+        log::info!("Measuring overhead at {name}");
+        run_fio(
+            config,
+            args,
+            Some(dir.join(format!("{name}.stdout"))),
+            Some(dir.join(format!("{name}.stderr"))),
+            None,
+            fio_timeout(config, 1, RUNTIME_SECS),
+        )
+        .with_context(|| format!("Null engine run {name} failed"))?;
+
+        let iops = results::parse_file(&output_path)?.total_iops();
+        log::info!("Harness ceiling at {name}: {iops:.0} iops");
+        ceilings.push(results::OverheadCeiling {
+            jobcount,
+            queue_depth,
+            iops,
+        });
+    }
+    results::write_overhead(batch_dir, &ceilings)
+}
+
+fn initial_cleanup(config: &config::Config) -> Result<()> {
+    log::info!("Cleaning up state from previous runs");
+
+    let cleanup = || -> Result<()> {
+        if let Some(dm) = &config.dm_setup {
+            if Path::new(&config.target_path()).exists() {
+                teardown_dm(&dm.name).context("Failed to remove dm device")?;
+            }
+        }
+        if config.configure_c_nullblk && Path::new(CNULL_CONFIGFS).exists() {
+            teardown_cnull().context("Failed to tear down cnull")?;
+        }
+        if module_loaded(config) {
+            unload_module(config).context("Failed to unload module")?;
+        }
+        Ok(())
+    };
+
+    if let Err(e) = cleanup() {
+        if config.strict_initial_cleanup {
+            return Err(e).context("Initial cleanup failed");
+        }
+        log::warn!("Initial cleanup failed, continuing: {e:?}");
+    }
+
+    if module_loaded(config) {
+        let name = config
+            .module
+            .as_deref()
+            .map(module_name)
+            .unwrap_or_default();
+        let msg = format!(
+            "Module {name} is still loaded after initial cleanup, \
+             a previous run may have left it in a wedged state"
+        );
+        if config.strict_initial_cleanup {
+            return Err(anyhow!(msg));
+        }
+        log::error!("{msg}");
+    }
+
+    Ok(())
+}
+
+fn run_single_workload(
+    config: &config::Config,
+    output_dir_path: Option<&Path>,
+    progress: &matrix::Progress,
+) -> Result<()> {
+    let entry = progress.entry;
+    let run_output_id = matrix::run_output_id(config, entry);
+
+    log::info!("Setting up workload: {run_output_id}");
+
+    let run_file_path = |name: &str| -> Option<PathBuf> {
+        output_dir_path.map(|v| {
+            let mut p = PathBuf::from(v);
+            p.push(format!("{run_output_id}{name}"));
+            p
+        })
+    };
+
+    if config.prep {
+        let prep_stdout_path = run_file_path("-prep.stdout");
+        let prep_stderr_path = run_file_path("-prep.stderr");
+
+        let mut command = fio_command(config);
+        command
+            .arg("--name=prep")
+            .arg("--rw=write")
+            .arg("--direct=1")
+            .arg("--bs=4k")
+            .arg(format!("--filename={}", config.block_path()));
+        if !config.allow_file_create {
+            command.arg("--allow_file_create=0");
+        }
+
+        log::info!("Running prep command");
+
+        let prep = || -> Result<()> {
+            command
+                .spawn_captured(
+                    prep_stdout_path.map(File::create).transpose()?,
+                    prep_stderr_path.map(File::create).transpose()?,
+                    fio_tee(config),
+                )?
+                .wait()?
+                .check_status()
+        };
+        prep().context("Prep work failed")?;
+    }
+    let output_path = run_file_path(results::output_suffix(config));
+    let bandwidth_unit = results::BandwidthUnit::parse(&config.bw_unit)?;
+
+    // Started ahead of the ramp, so the foreground meets a steady load
+    let background = config
+        .background_jobs
+        .as_ref()
+        .map(|b| start_background(config, b, run_file_path("-background.json").as_deref()))
+        .transpose()
+        .context("Failed to start background jobs")?;
+
+    // The ramp runs as its own measured job, so the main job starts warm and
+    // skips fio's ramp.
+    let measured_ramp = config.ramp.filter(|r| config.measure_ramp && *r != 0);
+    if let Some(ramp) = measured_ramp {
+        let ramp_output_path = run_file_path(&format!("-ramp{}", results::output_suffix(config)));
+        let mut args = fio_args(config, entry, ramp, None, ramp_output_path.as_deref())?;
+        push_phases(&mut args, &entry.workload);
+        log::info!("Running measured ramp command");
+        run_fio(
+            config,
+            args,
+            run_file_path("-ramp.stdout"),
+            run_file_path("-ramp.stderr"),
+            Some(progress),
+            fio_timeout(config, phase_count(&entry.workload), ramp),
+        )
+        .context("Fio ramp failed")?;
+
+        if let Some(path) = &ramp_output_path {
+            let output =
+                results::parse_output(config, path).context("Failed to parse fio ramp output")?;
+            results::log_summary(&format!("{run_output_id} ramp"), &output, bandwidth_unit);
+        }
+    }
+
+    let ramp = match measured_ramp {
+        Some(_) => None,
+        None => config.ramp,
+    };
+    let mut args = fio_args(config, entry, config.runtime, ramp, output_path.as_deref())?;
+
+    let hist_prefix = run_file_path("-lat").filter(|_| config.capture_latency_trend);
+    if let Some(prefix) = &hist_prefix {
+        args.push(format!(
+            "--write_hist_log={}",
+            prefix.to_str().ok_or(anyhow!("path conversion error"))?
+        ));
+        args.push(format!("--log_hist_msec={}", trend::INTERVAL_MS));
+    }
+    push_phases(&mut args, &entry.workload);
+
+    let meminfo_before = config
+        .capture_meminfo
+        .then(|| meminfo::Snapshot::take(&config.meminfo_counters))
+        .transpose()
+        .context("Failed to snapshot memory counters")?;
+
+    let cstates_before = config
+        .capture_cstate_residency
+        .then(cstates::Snapshot::take)
+        .transpose()
+        .context("Failed to snapshot C-state residency")?;
+
+    let debugfs_module = config.module.as_deref().map(module_name);
+    let debugfs_before = debugfs_module
+        .as_deref()
+        .filter(|_| !config.debugfs_files.is_empty())
+        .map(|module| debugfs::Snapshot::take(module, &config.debugfs_files))
+        .transpose()
+        .context("Failed to snapshot debugfs counters")?;
+
+    let perf = config
+        .perf_events
+        .as_ref()
+        .map(|events| {
+            let cpus = perf_cpus(config, entry, &cpus::online_cpus()?)?;
+            start_perf(events, run_file_path("-perf.txt").as_deref(), &cpus)
+        })
+        .transpose()
+        .context("Failed to start perf")?;
+
+    let blktrace = match (config.capture_blktrace, output_dir_path) {
+        (true, Some(dir)) => {
+            let base = format!("{run_output_id}-blktrace");
+            let child = start_blktrace(&config.block_path(), dir, &base)
+                .context("Failed to start blktrace")?;
+            Some((child, base))
+        }
+        _ => None,
+    };
+
+    let power_path = run_file_path("-turbostat.txt").filter(|_| config.capture_power);
+    let turbostat = power_path
+        .as_deref()
+        .map(power::start_turbostat)
+        .transpose()
+        .context("Failed to start turbostat")?;
+
+    let telemetry = run_file_path(telemetry::TELEMETRY_SUFFIX)
+        .filter(|_| config.telemetry_interval != 0)
+        .map(|path| {
+            let interval = std::time::Duration::from_secs(config.telemetry_interval);
+            telemetry::start(&config.device, interval, &path)
+        })
+        .transpose()
+        .context("Failed to start telemetry")?;
+
+    log::info!("Running workload command");
+
+    let status = run_fio(
+        config,
+        args,
+        run_file_path(".stdout"),
+        run_file_path(".stderr"),
+        Some(progress),
+        fio_timeout(
+            config,
+            phase_count(&entry.workload),
+            config.runtime + ramp.unwrap_or(0),
+        ),
+    );
+
+    // Everything started is stopped whatever failed, the first error is
+    // reported once all of them are
+    let mut stopped = Ok(());
+    if let Some(telemetry) = telemetry {
+        stopped = stopped.and(telemetry.stop().context("Failed to stop telemetry"));
+    }
+
+    if let Some(perf) = perf {
+        stopped = stopped.and(perf.stop().context("Failed to stop perf"));
+    }
+
+    if let Some(turbostat) = turbostat {
+        stopped = stopped.and(turbostat.stop().context("Failed to stop turbostat"));
+    }
+
+    if let (Some((child, base)), Some(dir)) = (blktrace, output_dir_path) {
+        let traced = child
+            .stop()
+            .context("Failed to stop blktrace")
+            .and_then(|()| blkparse_summary(dir, &base).context("Failed to parse blktrace"));
+        stopped = stopped.and(traced);
+    }
+
+    if let Some(background) = background {
+        stopped = stopped.and(background.stop().context("Failed to stop background jobs"));
+    }
+
+    status.context("Fio workload failed")?;
+    stopped?;
+
+    if let (Some(before), Some(path)) = (&meminfo_before, run_file_path("-meminfo.json")) {
+        let after = meminfo::Snapshot::take(&config.meminfo_counters)
+            .context("Failed to snapshot memory counters")?;
+        meminfo::write_delta(&path, before, &after)?;
+    }
+
+    if let (Some(before), Some(path)) = (&cstates_before, run_file_path("-cstates.json")) {
+        let after = cstates::Snapshot::take().context("Failed to snapshot C-state residency")?;
+        cstates::write_delta(&path, before, &after)?;
+    }
+
+    if let (Some(before), Some(module), Some(path)) = (
+        &debugfs_before,
+        &debugfs_module,
+        run_file_path("-debugfs.json"),
+    ) {
+        let after = debugfs::Snapshot::take(module, &config.debugfs_files)
+            .context("Failed to snapshot debugfs counters")?;
+        debugfs::write_delta(&path, before, &after)?;
+    }
+
+    if let (Some(_), Some(dir)) = (&hist_prefix, output_dir_path) {
+        let logs = trend::hist_logs(dir, &format!("{run_output_id}-lat"))?;
+        match trend::analyze(&logs).context("Failed to analyze latency histograms")? {
+            Some(trend) => {
+                log::info!(
+                    "p99 latency {run_output_id}: {} ({:+.0}% over the run)",
+                    match trend.degrading {
+                        true => "degrading",
+                        false => "stable",
+                    },
+                    trend.relative_change * 100.0
+                );
+                if let Some(path) = run_file_path(trend::TREND_SUFFIX) {
+                    trend::write(&path, &trend)?;
+                }
+            }
+            None => log::warn!("Too few latency histogram intervals for {run_output_id}"),
+        }
+    }
+
+    if let Some(path) = &power_path {
+        match power::parse_file(path)? {
+            Some(watts) => log::info!("Package power {run_output_id}: {watts:.1}W"),
+            None => log::warn!("No power samples for {run_output_id}"),
+        }
+    }
+
+    if config.verify && config.verify_separately && entry.workload.writes() {
+        run_verify_phase(config, progress, &run_output_id, run_file_path)?;
+    }
+
+    if let Some(output_path) = &output_path {
+        let output =
+            results::parse_output(config, output_path).context("Failed to parse fio output")?;
+        if config.latency_target.is_some() {
+            results::log_latency_target(&output);
+        }
+        match entry.workload.phases() {
+            Some(_) => {
+                for (phase, output) in output.into_phases() {
+                    let name = format!("{run_output_id} {phase}");
+                    results::log_summary(&name, &output, bandwidth_unit);
+                }
+            }
+            None => results::log_summary(&run_output_id, &output, bandwidth_unit),
+        }
+    }
+
+    Ok(())
+}
+
+fn workload_writes(rw: &str) -> bool {
+    rw.contains("write") || rw.ends_with("rw") || rw.contains("trim")
+}
+
+/// Read back what the measured job wrote and check it against the checksums
+/// it left, so verifying does not show up in the measured numbers.
+fn run_verify_phase(
+    config: &config::Config,
+    progress: &matrix::Progress,
+    run_output_id: &str,
+    run_file_path: impl Fn(&str) -> Option<PathBuf>,
+) -> Result<()> {
+    let entry = progress.entry;
+    log::info!("Running verify phase");
+    let output_path = run_file_path(&format!("-verify{}", results::output_suffix(config)));
+    let mut args = fio_args(config, entry, config.runtime, None, output_path.as_deref())?;
+    args.push(String::from("--verify_only"));
+
+    let status = run_fio(
+        config,
+        args,
+        run_file_path("-verify.stdout"),
+        run_file_path("-verify.stderr"),
+        Some(progress),
+        fio_timeout(config, 1, config.runtime),
+    );
+    let output = output_path
+        .as_deref()
+        .map(|path| results::parse_output(config, path))
+        .transpose()
+        .context("Failed to parse fio verify output")?;
+    let errors = output.as_ref().map(results::FioOutput::total_errors);
+
+    match (status, errors) {
+        (Ok(()), None | Some(0)) => {
+            log::info!("Verify phase {run_output_id}: passed");
+            Ok(())
+        }
+        (status, errors) => {
+            log::error!(
+                "Verify phase {run_output_id}: failed with {} errors",
+                errors
+                    .map(|e| e.to_string())
+                    .unwrap_or_else(|| "unknown".into())
+            );
+            status.context("Fio verify failed")?;
+            Err(anyhow!(
+                "Verify found {} errors",
+                errors.unwrap_or_default()
+            ))
+        }
+    }
+}
+
+/// Arguments shared by every fio job we run: the target, the IO pattern
+/// and how IO is issued. The job is pinned to `entry.jobcount` cpus
+/// starting at `cpu_offset`, and only keeps the latency clock calls with
+/// `latencies`.
+fn fio_base_args(
+    config: &config::Config,
+    entry: &MatrixEntry,
+    filename: &str,
+    cpu_offset: u32,
+    latencies: bool,
+) -> Result<Vec<String>> {
+    let MatrixEntry {
+        block_size,
+        jobcount,
+        workload,
+        queue_depth,
+        ..
+    } = entry;
+    let block_size_bytes = matrix::block_size_bytes(block_size)?;
+    let cpus = cpus_allowed(config, cpu_offset, *jobcount)?;
+    // An explicit set may hold more cpus than jobs, verify checks it has enough
+    if let (config::CpusAllowedPolicy::Split, None) =
+        (config.cpus_allowed_policy, &config.cpus_allowed)
+    {
+        check_split(&cpus, *jobcount)?;
+    }
+
+    // The options of a phased workload are global, its phases are added as
+    // jobs of their own by `push_phases`
+    let mut args = match workload.phases() {
+        Some(_) => Vec::new(),
+        None => vec![
+            String::from("--name=default"),
+            format!("--readwrite={}", workload),
+        ],
+    };
+    args.extend([
+        format!("--filename={filename}"),
+        format!("--gtod_reduce={}", u8::from(!latencies)),
+        String::from("--clocksource=cpu"),
+        format!("--blocksize={}", block_size_bytes),
+        format!(
+            "--cpus_allowed_policy={}",
+            config.cpus_allowed_policy.fio_name()
+        ),
+        format!("--cpus_allowed={cpus}"),
+        format!("--numjobs={}", jobcount),
         format!("--iodepth={}", queue_depth),
-        String::from("--fixedbufs=1"),
-        String::from("--registerfiles=1"),
-        String::from("--nonvectored=1"),
-        //"--iodepth_batch_submit=4"
-        //"--iodepth_batch_complete=4",
-    ];
+    ]);
+    if let Some(batch) = config.iodepth_batch_submit {
+        args.push(format!("--iodepth_batch_submit={batch}"));
+    }
+    if let Some(batch) = config.iodepth_batch_complete {
+        args.push(format!("--iodepth_batch_complete={batch}"));
+    }
 
-    if config.ramp != 0 {
-        args.push(format!("--ramp={}", config.ramp));
+    args.push(format!("--ioengine={}", config.ioengine.fio_name()));
+    if config.ioengine.is_io_uring() {
+        args.extend([
+            String::from("--fixedbufs=1"),
+            String::from("--registerfiles=1"),
+            String::from("--nonvectored=1"),
+        ]);
+    }
+    match config.ioengine {
+        // The char device has no page cache to bypass
+        config::IoEngine::IoUringCmd => args.push(String::from("--cmd_type=nvme")),
+        config::IoEngine::IoUring | config::IoEngine::Libaio | config::IoEngine::Psync => {
+            args.push(String::from("--direct=1"))
+        }
+    }
+
+    if config.hipri {
+        args.push(String::from("--hipri=1"));
+    }
+
+    if !config.allow_file_create {
+        args.push(String::from("--allow_file_create=0"));
+    }
+
+    if config.use_hugepages {
+        args.push(String::from("--iomem=mmaphuge"));
+        args.push(String::from("--hugepage-size=2m"));
+    }
+
+    Ok(args)
+}
+
+fn fio_args(
+    config: &config::Config,
+    entry: &MatrixEntry,
+    runtime: u32,
+    ramp: Option<u32>,
+    output_path: Option<&Path>,
+) -> Result<Vec<String>> {
+    let mut args = fio_base_args(
+        config,
+        entry,
+        &config.target_path(),
+        0,
+        config.clat_percentiles(),
+    )?;
+    args.push(String::from("--time_based=1"));
+    args.push(format!("--runtime={}", runtime));
+
+    match &entry.rate {
+        Some(matrix::Rate::Iops(iops)) => args.push(format!("--rate_iops={iops}")),
+        // In bytes, so fio caps at the rate rate_met checks against
+        Some(matrix::Rate::Bandwidth(bw)) => {
+            args.push(format!("--rate={}", matrix::block_size_bytes(bw)?))
+        }
+        None => {}
+    }
+
+    if let Some(thinktime) = &entry.thinktime {
+        args.push(format!("--thinktime={thinktime}"));
+        if let Some(blocks) = config.thinktime_blocks {
+            args.push(format!("--thinktime_blocks={blocks}"));
+        }
+    }
+
+    if let Some(startdelay) = &config.startdelay {
+        args.push(format!("--startdelay={startdelay}"));
+    }
+
+    if let Some(cap) = &config.io_size_cap {
+        args.push(format!("--io_size={cap}"));
+    }
+
+    if !config.per_job_reporting {
+        args.push(String::from("--group_reporting"));
+    }
+
+    if let Some(ramp) = ramp {
+        args.push(format!("--ramp_time={}", ramp));
+    }
+
+    if config.verify {
+        // With a separate phase the measured job only writes the checksums
+        match config.verify_separately {
+            false => args.push("--do_verify=1".to_string()),
+            true => args.push("--do_verify=0".to_string()),
+        }
+        args.push("--verify=md5".to_string());
+    } else {
+        args.push(String::from("--norandommap"));
+        args.push(String::from("--random_generator=lfsr"));
+    }
+
+    if let Some(latency_target) = &config.latency_target {
+        args.push(format!("--latency_target={}", latency_target.target));
+        if let Some(window) = &latency_target.window {
+            args.push(format!("--latency_window={window}"));
+        }
+        if let Some(percentile) = latency_target.percentile {
+            args.push(format!("--latency_percentile={percentile}"));
+        }
+    }
+
+    if let Some(output_path) = output_path {
+        match config.terse_results {
+            true => {
+                args.push(String::from("--output-format=terse"));
+                args.push(String::from("--terse-version=3"));
+            }
+            false => args.push(String::from("--output-format=json+")),
+        }
+        args.push(format!(
+            "--output={}",
+            output_path
+                .to_str()
+                .ok_or(anyhow!("path conversion error"))?
+        ));
+    }
+
+    // Before the jobs of `push_phases`, so they apply to all of them.
+    // Config::verify rejects those that collide with the options above.
+    args.extend(config.extra_fio_args.iter().cloned());
+
+    Ok(args)
+}
+
+/// Append one job per phase of a phased workload. Options before the first
+/// `--name` are global on the fio command line, so this has to come after
+/// all other options. A `stonewall` also starts a new reporting group, so
+/// every phase reports on its own even with `--group_reporting`.
+fn push_phases(args: &mut Vec<String>, workload: &config::Workload) {
+    for (i, phase) in workload.phases().unwrap_or_default().iter().enumerate() {
+        args.push(format!("--name={}", results::phase_job_name(i, &phase.rw)));
+        args.push(format!("--readwrite={}", phase.rw));
+        if i > 0 {
+            args.push(String::from("--stonewall"));
+        }
+    }
+}
+
+/// Full device passes to bring an SSD to steady state before measuring.
+fn precondition(
+    config: &config::Config,
+    device: Option<&str>,
+    output_dir: Option<&str>,
+) -> Result<()> {
+    const QUEUE_DEPTH: u32 = 32;
+
+    let config = &device.map_or_else(|| config.clone(), |d| config.for_device(d));
+
+    let passes = config.precondition_passes();
+    if config.precondition != config::Precondition::None {
+        log::info!("Preconditioning with {:?}", config.precondition);
+    }
+    let total = passes.len();
+    for (i, pass) in passes.iter().enumerate() {
+        log::info!(
+            "Preconditioning pass {}/{total}: {} bs {} x{}",
+            i + 1,
+            pass.rw,
+            pass.bs,
+            pass.count
+        );
+        let entry = MatrixEntry {
+            block_size: pass.bs.clone(),
+            jobcount: 1,
+            workload: config::Workload::from(pass.rw.as_str()),
+            queue_depth: QUEUE_DEPTH,
+            scheduler: String::from("none"),
+            rate: None,
+            thinktime: None,
+            labels: Vec::new(),
+            device: None,
+        };
+        // The base device, a dm device on top only exists during a config
+        let filename = match config.dm_setup {
+            Some(_) => format!("/dev/{}", config.device),
+            None => config.target_path(),
+        };
+        let mut args = fio_base_args(config, &entry, &filename, 0, false)?;
+        args.push(format!("--loops={}", pass.count));
+
+        let output_file = |suffix: &str| {
+            output_dir.map(|dir| {
+                let name = match device {
+                    Some(device) => format!("precondition-{device}-{i}{suffix}"),
+                    None => format!("precondition-{i}{suffix}"),
+                };
+                PathBuf::from(dir).tap_mut(|p| p.push(name))
+            })
+        };
+        run_fio(
+            config,
+            args,
+            output_file(".stdout"),
+            output_file(".stderr"),
+            None,
+            // Full device passes take as long as the device needs
+            None,
+        )
+        .with_context(|| format!("Preconditioning pass {} failed", i + 1))?;
+    }
+    Ok(())
+}
+
+/// fio, under the `fio_launcher` if one is configured.
+fn fio_command(config: &config::Config) -> Command {
+    match config.fio_launcher.as_deref() {
+        Some([launcher, launcher_args @ ..]) => {
+            let mut command = Command::new(launcher);
+            command.args(launcher_args).arg(&config.fio);
+            command
+        }
+        _ => Command::new(&config.fio),
+    }
+}
+
+/// Run fio with `args`, keeping the remote alive while it runs. Pings carry
+/// `progress` as JSON if given.
+fn run_fio(
+    config: &config::Config,
+    args: Vec<String>,
+    stdout_path: Option<PathBuf>,
+    stderr_path: Option<PathBuf>,
+    progress: Option<&matrix::Progress>,
+    timeout: Option<std::time::Duration>,
+) -> Result<()> {
+    let mut command = fio_command(config);
+
+    match &config.fio_client {
+        Some(host) => {
+            let job_file_path = match &stdout_path {
+                Some(path) => path.with_extension("fio"),
+                None => std::env::temp_dir().join(format!("fio-matrix-{}.fio", std::process::id())),
+            };
+            let (job_file, client_args) = client_job_file(&args);
+            std::fs::write(&job_file_path, job_file)
+                .with_context(|| format!("Failed to write job file {job_file_path:?}"))?;
+            command
+                .args(client_args)
+                .arg(format!("--client={host}"))
+                .arg(&job_file_path);
+        }
+        None => {
+            command.args(args);
+        }
     }
+    // A Ctrl-C lets the run finish and only a second one stops it
+    command
+        .scheduling(config.fio_nice, config.fio_rt_priority)
+        .own_process_group();
 
-    if config.verify {
-        args.push("--do_verify=1".to_string());
-        args.push("--verify=md5".to_string());
+    let stdout = stdout_path.map(File::create).transpose()?;
+    let stderr = stderr_path.map(File::create).transpose()?;
+
+    if config.has_remote() {
+        let body = progress.map(serde_json::to_vec).transpose()?;
+        let ping = || -> Result<()> {
+            remote_fan_out(config, "Ping", |client, target| {
+                let mut request = client.put(target.join("ping")?);
+                if let Some(body) = &body {
+                    request = request
+                        .header(reqwest::header::CONTENT_TYPE, "application/json")
+                        .body(body.clone());
+                }
+                request
+                    .send()?
+                    .error_for_status()
+                    .map(|_ok| ())
+                    .context("Ping failed")
+            })
+        };
+
+        let child = command
+            .spawn_captured(stdout, stderr, fio_tee(config))?
+            .stop_on_drop(std::time::Duration::from_secs(config.fio_kill_grace_secs));
+        let mut last_ping = std::time::Instant::now();
+        let mut ping_failures = 0;
+        wait_fio(child, timeout, || {
+            if std::time::Instant::now() - last_ping > std::time::Duration::from_secs(60) {
+                last_ping = std::time::Instant::now();
+                // Not retried in place, the next one comes a minute later
+                match ping() {
+                    Ok(()) => ping_failures = 0,
+                    Err(e) if ping_failures < config.ping_failures_max => {
+                        ping_failures += 1;
+                        log::warn!(
+                            "Ping failed {ping_failures} of {} times in a row: {e:?}",
+                            config.ping_failures_max
+                        );
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        })
     } else {
-        args.push(String::from("--norandommap"));
-        args.push(String::from("--random_generator=lfsr"));
+        let child = command
+            .spawn_captured(stdout, stderr, fio_tee(config))?
+            .stop_on_drop(std::time::Duration::from_secs(config.fio_kill_grace_secs));
+        wait_fio(child, timeout, || Ok(()))
+    }
+}
+
+fn fio_tee(config: &config::Config) -> command::Tee {
+    use config::LogFioOutput;
+    command::Tee {
+        echo: config.tee_output,
+        log_stdout: config.log_fio_output == LogFioOutput::All,
+        log_stderr: config.log_fio_output != LogFioOutput::Off,
+    }
+}
+
+fn phase_count(workload: &config::Workload) -> u32 {
+    workload.phases().map_or(1, |phases| phases.len() as u32)
+}
+
+/// How long a run of `phases` phases of `secs` seconds each may take, see
+/// `workload_timeout`.
+fn fio_timeout(config: &config::Config, phases: u32, secs: u32) -> Option<std::time::Duration> {
+    const MARGIN_SECS: u64 = 300;
+    match config.workload_timeout {
+        Some(0) => None,
+        Some(timeout) => Some(std::time::Duration::from_secs(timeout.into())),
+        None => Some(std::time::Duration::from_secs(
+            2 * u64::from(phases) * u64::from(secs) + MARGIN_SECS,
+        )),
+    }
+}
+
+/// fio ran into `workload_timeout`.
+#[derive(Debug)]
+struct FioTimeout(std::time::Duration);
+
+impl std::fmt::Display for FioTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "fio timed out after {}s, stopping it", self.0.as_secs())
+    }
+}
+
+impl std::error::Error for FioTimeout {}
+
+/// Wait for fio, calling `tick` in between. When it takes longer than
+/// `timeout` or on a second Ctrl-C, fio is stopped by dropping `child`.
+fn wait_fio(
+    mut child: command::CapturedChild,
+    timeout: Option<std::time::Duration>,
+    mut tick: impl FnMut() -> Result<()>,
+) -> Result<()> {
+    let started = std::time::Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return status.check_status().context("fio exited non-zero");
+        }
+        if interrupt::forced() {
+            return Err(anyhow!("Interrupted again, stopping fio"));
+        }
+        if let Some(timeout) = timeout.filter(|t| started.elapsed() > *t) {
+            return Err(FioTimeout(timeout).into());
+        }
+        tick()?;
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
+
+/// Options that `fio --client` handles itself rather than sending to the
+/// server.
+const CLIENT_OPTIONS: &[&str] = &["--output", "--output-format", "--terse-version"];
+
+/// Split fio command line `args` into a job file for `fio --client` and the
+/// options that stay on the client command line.
+fn client_job_file(args: &[String]) -> (String, Vec<String>) {
+    // Options before the first `--name` are global, as on the command line
+    let mut sections = vec![(String::from("global"), Vec::new())];
+    let mut client_args = Vec::new();
+    for arg in args {
+        let (key, value) = match arg.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        if CLIENT_OPTIONS.contains(&key) {
+            client_args.push(arg.clone());
+            continue;
+        }
+        let options = &mut sections.last_mut().expect("global section").1;
+        match (key.trim_start_matches('-'), value) {
+            ("name", Some(value)) => sections.push((value.to_string(), Vec::new())),
+            (key, Some(value)) => options.push(format!("{key}={value}")),
+            (key, None) => options.push(key.to_string()),
+        }
+    }
+
+    let mut job_file = String::new();
+    for (name, options) in sections {
+        if name == "global" && options.is_empty() {
+            continue;
+        }
+        job_file.push_str(&format!("[{name}]\n"));
+        for option in options {
+            job_file.push_str(&option);
+            job_file.push('\n');
+        }
+    }
+    (job_file, client_args)
+}
+
+/// Write the job file fio would run for each config into `dir`, as
+/// `<run id>.fio`. Outputs are left to whoever runs them.
+fn export_jobfiles(config: &config::Config, dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {dir:?}"))?;
+    let matrix = matrix::build_matrix(config);
+    for entry in &matrix {
+        let mut args = fio_args(config, entry, config.runtime, config.ramp, None)?;
+        push_phases(&mut args, &entry.workload);
+        let (job_file, _) = client_job_file(&args);
+        let path = dir.join(format!("{}.fio", matrix::run_output_id(config, entry)));
+        std::fs::write(&path, job_file).with_context(|| format!("Failed to write {path:?}"))?;
+    }
+    log::info!("Wrote {} job files to {dir:?}", matrix.len());
+    Ok(())
+}
+
+/// The cpus of `jobcount` jobs starting at the `offset`th cpu, or the
+/// explicit `cpus_allowed` as is.
+fn cpus_allowed(config: &config::Config, offset: u32, jobcount: u32) -> Result<String> {
+    if let Some(list) = &config.cpus_allowed {
+        return Ok(list.clone());
+    }
+    if !config.auto_isolate_cpus {
+        return Ok(format!("{offset}-{}", offset + jobcount - 1));
+    }
+
+    let isolated = cpus::isolated_cpus()?;
+    let needed = (offset + jobcount) as usize;
+    if isolated.len() < needed {
+        return Err(anyhow!(
+            "Need {needed} isolated cpus but kernel command line isolates {}: [{}]",
+            isolated.len(),
+            cpus::format_cpu_list(&isolated)
+        ));
+    }
+    Ok(cpus::format_cpu_list(&isolated[offset as usize..needed]))
+}
+
+/// fio's split policy hands out the cpus round robin, so jobs only get a cpu
+/// of their own when there are exactly as many cpus as jobs.
+fn check_split(cpus: &str, jobcount: u32) -> Result<()> {
+    let count = cpus::parse_cpu_list(cpus)?.len();
+    if count != jobcount as usize {
+        return Err(anyhow!(
+            "cpus_allowed_policy split needs one cpu per job, but {jobcount} jobs have \
+             {count} cpus [{cpus}]. Use cpus_allowed_policy Shared if sharing is intended"
+        ));
     }
+    Ok(())
+}
+
+/// Start the steady background load of `background_jobs`. It runs on the
+/// cpus after the largest foreground jobcount until stopped.
+fn start_background(
+    config: &config::Config,
+    background: &config::BackgroundJobs,
+    output_path: Option<&Path>,
+) -> Result<BackgroundChild> {
+    // Far longer than any config, the load is stopped with the foreground
+    const RUNTIME_SECS: u32 = 24 * 60 * 60;
 
-    if config.capture {
-        args.push(String::from("--output-format=json+"));
+    let entry = MatrixEntry {
+        block_size: background.bs.clone(),
+        jobcount: background.jobs,
+        workload: config::Workload::from(background.rw.as_str()),
+        queue_depth: background.iodepth,
+        scheduler: String::from("none"),
+        rate: None,
+        thinktime: None,
+        labels: Vec::new(),
+        device: None,
+    };
+    let cpu_offset = config.jobcounts.iter().copied().max().unwrap_or(0);
+    let mut args = fio_base_args(config, &entry, &config.target_path(), cpu_offset, false)?;
+    args.push(String::from("--time_based=1"));
+    args.push(format!("--runtime={RUNTIME_SECS}"));
+    args.push(String::from("--group_reporting"));
+    args.push(String::from("--norandommap"));
+    args.push(String::from("--random_generator=lfsr"));
+    if let Some(output_path) = output_path {
+        args.push(String::from("--output-format=json"));
         args.push(format!(
             "--output={}",
             output_path
-                .unwrap()
                 .to_str()
                 .ok_or(anyhow!("path conversion error"))?
         ));
     }
 
-    if config.hipri {
-        args.push(String::from("--hipri=1"));
-    }
+    log::info!("Starting background jobs");
+    let mut command = fio_command(config);
+    command
+        .args(args)
+        .stdout(Stdio::null())
+        .scheduling(config.fio_nice, config.fio_rt_priority)
+        .own_process_group();
+    // fio stops its jobs and writes the output when interrupted
+    Ok(BackgroundChild::new(command.spawn()?, libc::SIGINT))
+}
 
-    if config.use_hugepages {
-        args.push(String::from("--iomem=mmaphuge"));
-        args.push(String::from("--hugepage-size=2m"));
+/// The cpus perf runs on for `entry`, those of `online` that fio does not
+/// run on, so perf takes no cpu time from the jobs it counts. All of them
+/// if fio runs on every cpu.
+fn perf_cpus(config: &config::Config, entry: &MatrixEntry, online: &[u32]) -> Result<Vec<u32>> {
+    let mut fio = cpus::parse_cpu_list(&cpus_allowed(config, 0, entry.jobcount)?)?;
+    if let Some(background) = &config.background_jobs {
+        let offset = config.jobcounts.iter().copied().max().unwrap_or(0);
+        fio.extend(cpus::parse_cpu_list(&cpus_allowed(
+            config,
+            offset,
+            background.jobs,
+        )?)?);
+    }
+    let free = online
+        .iter()
+        .copied()
+        .filter(|cpu| !fio.contains(cpu))
+        .collect::<Vec<_>>();
+    if free.is_empty() {
+        log::warn!("fio runs on every cpu, perf shares them");
+        return Ok(online.to_vec());
     }
+    Ok(free)
+}
 
-    let mut command = Command::new(&config.fio);
+/// Count `events` on all cpus from the `cpus` perf itself runs on.
+fn start_perf(events: &[String], output: Option<&Path>, cpus: &[u32]) -> Result<BackgroundChild> {
+    let mut command = Command::new("perf");
+    command
+        .arg("stat")
+        .arg("-a")
+        .arg("-e")
+        .arg(events.join(","))
+        .affinity(cpus::cpu_set(cpus)?)
+        .own_process_group();
+    if let Some(output) = output {
+        command.arg("-o").arg(output);
+    }
+    // perf stat prints its counters when interrupted
+    Ok(BackgroundChild::new(command.spawn()?, libc::SIGINT))
+}
 
-    command.args(args);
+/// Trace `device` into `<dir>/<base>.blktrace.<cpu>` until stopped.
+fn start_blktrace(device: &str, dir: &Path, base: &str) -> Result<BackgroundChild> {
+    let mut command = Command::new("blktrace");
+    command
+        .arg("-d")
+        .arg(device)
+        .arg("-D")
+        .arg(dir)
+        .arg("-o")
+        .arg(base)
+        .own_process_group();
+    // blktrace flushes its buffers when interrupted
+    Ok(BackgroundChild::new(command.spawn()?, libc::SIGINT))
+}
 
-    if config.capture {
-        command
-            .stdout(File::create(stdout_path.unwrap())?)
-            .stderr(File::create(stderr_path.unwrap())?);
-    }
+/// Write the blkparse summary of the traces of `base` to
+/// `<dir>/<base>-summary.txt`, the per event output is skipped.
+fn blkparse_summary(dir: &Path, base: &str) -> Result<()> {
+    let summary = File::create(dir.join(format!("{base}-summary.txt")))?;
+    Command::new("blkparse")
+        .arg("-i")
+        .arg(base)
+        .arg("-D")
+        .arg(dir)
+        .arg("-O")
+        .stdout(summary)
+        .spawn()?
+        .wait()?
+        .check_status()
+}
 
-    log::info!("Running workload command");
+/// Whether a config is set up and not yet torn down, so that the normal,
+/// error and panic paths tear down only once.
+static SET_UP: AtomicBool = AtomicBool::new(false);
 
-    if let Some(target) = &config.remote {
-        let client = reqwest::blocking::Client::new();
-        let ping = || -> Result<()> {
-            client
-                .put(target.join("ping")?)
-                .send()?
-                .error_for_status()
-                .map(|_ok| ())
-                .context("Ping failed")
-        };
+/// Whether the device only appears once cnull or the module is set up.
+fn creates_device(config: &config::Config) -> bool {
+    config.configure_c_nullblk || config.module.is_some()
+}
 
-        let mut child = command.spawn()?;
-        let mut last_ping = std::time::Instant::now();
-        loop {
-            if std::time::Instant::now() - last_ping > std::time::Duration::from_secs(60) {
-                ping()?;
-                last_ping = std::time::Instant::now();
-            }
-            std::thread::sleep(std::time::Duration::from_secs(1));
-            if let Some(ret) = child.try_wait()? {
-                return ret.check_status().context("Fio workload failed");
-            }
-        }
-    } else {
-        command
-            .spawn()?
-            .wait()?
-            .check_status()
-            .context("Fio workload failed")
-    }
+/// Whether the device under `dm_setup` is made again for every config, so
+/// the dm device has to be too. Otherwise it lives for the whole sweep.
+fn dm_per_config(config: &config::Config) -> bool {
+    let cnull = config.configure_c_nullblk
+        && matches!(config.cnull_lifecycle, config::CnullLifecycle::PerConfig);
+    let module = config.module.is_some()
+        && matches!(
+            config.module_reload_policy,
+            config::ModuleReloadPolicy::Always
+        );
+    cnull || module
 }
 
-fn setup(config: &config::Config) -> Result<()> {
+fn setup(config: &config::Config, entry: &MatrixEntry) -> Result<()> {
+    SET_UP.store(true, Ordering::SeqCst);
+
     if let config::ModuleReloadPolicy::Always = config.module_reload_policy {
         load_module(config).context("Load module always")?;
     }
 
     if config.configure_c_nullblk {
-        setup_cnull(&config.device).context("setup cnull")?;
+        if let config::CnullLifecycle::PerConfig = config.cnull_lifecycle {
+            setup_cnull(&config.device, &config.cnull).context("setup cnull")?;
+        }
+    }
+
+    if creates_device(config) && config.fio_client.is_none() {
+        check_block_device(Path::new("/dev"), Path::new(SYSFS_BLOCK), &config.device)?;
+    }
+
+    if config.require_device_fits && config.configure_c_nullblk {
+        check_device_fits(&config.device, std::iter::once(entry)).context("Device too small")?;
+    }
+
+    if let (Some(dm), true) = (&config.dm_setup, dm_per_config(config)) {
+        setup_dm(dm, &config.device).context("setup dm")?;
     }
 
-    set_block_scheduler(&config.device).context("Set block scheduler")?;
-    disable_iostats(&config.device).context("Disable iostats")?;
+    if config.fio_client.is_none() {
+        // Put back by the settings saved for the batch
+        tune_queue(
+            Path::new(SYSFS_BLOCK),
+            &config.device,
+            &entry.scheduler,
+            SysfsRetry::new(config),
+        )?;
+    }
 
     Ok(())
 }
 
+/// Stays pending after a failure so the next call tries again.
 fn teardown(config: &config::Config) -> Result<()> {
+    if !SET_UP.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    if let (Some(dm), true) = (&config.dm_setup, dm_per_config(config)) {
+        teardown_dm(&dm.name)?;
+    }
+
     if config.configure_c_nullblk {
-        teardown_cnull()?;
+        if let config::CnullLifecycle::PerConfig = config.cnull_lifecycle {
+            teardown_cnull()?;
+        }
     }
 
     if let config::ModuleReloadPolicy::Always = config.module_reload_policy {
         unload_module(config)?;
     }
 
+    SET_UP.store(false, Ordering::SeqCst);
     Ok(())
 }
 
+/// A system tool with the path and extra arguments from
+/// `tuning_command_overrides` applied. Extra arguments go first so they can
+/// be global options.
+fn tuning_command(config: &config::Config, name: &str) -> Command {
+    let Some(command_override) = config.tuning_command_overrides.get(name) else {
+        return Command::new(name);
+    };
+    let mut command = match &command_override.path {
+        Some(path) => Command::new(path),
+        None => Command::new(name),
+    };
+    command.args(&command_override.args);
+    command
+}
+
 fn load_module(config: &config::Config) -> Result<()> {
     if let Some(module) = &config.module {
         log::info!("Inserting module: {}", module);
         if config.insmod {
-            Command::new("insmod")
+            tuning_command(config, "insmod")
                 .arg(module)
                 .args(&config.module_args)
                 .spawn()?
@@ -491,7 +3093,7 @@ fn load_module(config: &config::Config) -> Result<()> {
         }
 
         if config.modprobe {
-            Command::new("modprobe")
+            tuning_command(config, "modprobe")
                 .arg(module)
                 .args(&config.module_args)
                 .spawn()?
@@ -507,93 +3109,530 @@ fn unload_module(config: &config::Config) -> Result<()> {
     if let Some(module) = &config.module {
         log::info!("Unloading module: {}", module);
         if config.insmod {
-            Command::new("rmmod")
+            tuning_command(config, "rmmod")
+                .arg(module)
+                .spawn_retry(3, std::time::Duration::from_secs(1))?;
+        }
+
+        if config.modprobe {
+            tuning_command(config, "modprobe")
+                .arg("-r")
                 .arg(module)
                 .spawn_retry(3, std::time::Duration::from_secs(1))?;
         }
+    }
+
+    Ok(())
+}
+
+/// Name the kernel uses for a module under `/sys/module`. For `insmod` the
+/// module is given as a path to the object file.
+fn module_name(module: &str) -> String {
+    Path::new(module)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or(module)
+        .trim_end_matches(".ko")
+        .replace('-', "_")
+}
+
+fn module_loaded(config: &config::Config) -> bool {
+    config
+        .module
+        .as_deref()
+        .map(|m| PathBuf::from("/sys/module").tap_mut(|p| p.push(module_name(m))))
+        .is_some_and(|p| p.exists())
+}
+
+const CNULL_CONFIGFS: &str = "/sys/kernel/config/nullb";
+
+fn device_size_bytes(device: &str) -> Result<u64> {
+    let path = PathBuf::from("/sys/block")
+        .tap_mut(|p| p.push(device))
+        .tap_mut(|p| p.push("size"));
+    let sectors: u64 = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {path:?}"))?
+        .trim()
+        .parse()
+        .with_context(|| format!("Failed to parse {path:?}"))?;
+    Ok(sectors * 512)
+}
+
+/// Bytes in flight across all jobs of an entry.
+fn working_set_bytes(entry: &MatrixEntry) -> Result<u64> {
+    let block_size = matrix::block_size_bytes(&entry.block_size)?;
+    Ok(block_size * u64::from(entry.queue_depth) * u64::from(entry.jobcount))
+}
+
+/// Run the `inter_sample_reset` steps, so a sample does not start with the
+/// garbage collection or caches the previous one left.
+fn reset_device(config: &config::Config) -> Result<()> {
+    // The device itself, not a dm target stacked on top
+    let path = format!("/dev/{}", config.device);
+    for step in &config.inter_sample_reset {
+        log::info!("Resetting {path} before sample: {step:?}");
+        match step {
+            config::SampleReset::Flush if config.device.starts_with("nvme") => {
+                Command::new("nvme")
+                    .arg("flush")
+                    .arg(&path)
+                    .spawn()?
+                    .wait()?
+                    .check_status()
+                    .context("nvme flush failed")?;
+            }
+            config::SampleReset::Flush => {
+                Command::new("blockdev")
+                    .arg("--flushbufs")
+                    .arg(&path)
+                    .spawn()?
+                    .wait()?
+                    .check_status()
+                    .context("blockdev --flushbufs failed")?;
+            }
+            config::SampleReset::DropCaches => {
+                // SAFETY: sync has no preconditions.
+                unsafe { libc::sync() };
+                std::fs::write("/proc/sys/vm/drop_caches", "3\n")
+                    .context("Failed to drop caches")?;
+            }
+            config::SampleReset::Deallocate => {
+                log::warn!("Discarding all data on {path}");
+                Command::new("blkdiscard")
+                    .arg(&path)
+                    .spawn()?
+                    .wait()?
+                    .check_status()
+                    .context("blkdiscard failed")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether any part of a sweep writes to the device.
+fn is_destructive(config: &config::Config) -> bool {
+    config.destructive_reason().is_some()
+}
+
+/// `device`, its partitions and everything stacked on top of them, e.g. dm
+/// devices, as named under `/sys/block`.
+fn device_stack(device: &str) -> Vec<String> {
+    let mut stack = vec![device.to_string()];
+    let mut i = 0;
+    while i < stack.len() {
+        let dir = PathBuf::from("/sys/class/block").tap_mut(|p| p.push(&stack[i]));
+        let partitions = std::fs::read_dir(&dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().join("partition").exists());
+        let holders = std::fs::read_dir(dir.join("holders"))
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.ok());
+        for entry in partitions.chain(holders) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !stack.contains(&name) {
+                stack.push(name);
+            }
+        }
+        i += 1;
+    }
+    stack
+}
+
+/// Mount points in `/proc/mounts` whose source is one of `devices`.
+/// `resolve` maps a source path to its block device name.
+fn mounts_on(
+    mounts: &str,
+    devices: &[String],
+    resolve: impl Fn(&str) -> Option<String>,
+) -> Vec<String> {
+    mounts
+        .lines()
+        .filter_map(|l| {
+            let mut fields = l.split_whitespace();
+            Some((fields.next()?, fields.next()?))
+        })
+        .filter(|(source, _)| source.starts_with("/dev/"))
+        .filter(|(source, _)| resolve(source).is_some_and(|name| devices.contains(&name)))
+        .map(|(_, mount_point)| mount_point.to_string())
+        .collect()
+}
+
+fn check_not_mounted(device: &str) -> Result<()> {
+    let mounts = std::fs::read_to_string("/proc/mounts").context("Failed to read /proc/mounts")?;
+    let stack = device_stack(device);
+    let mounted = mounts_on(&mounts, &stack, |source| {
+        std::fs::canonicalize(source)
+            .ok()?
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+    });
+    if !mounted.is_empty() {
+        return Err(anyhow!(
+            "{device} holds mounted filesystems at {}, set force_destructive to run anyway",
+            mounted.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Block sizes of a device queue, in bytes. An optimal IO size of zero
+/// means the device does not report one.
+struct QueueLimits {
+    logical_block_size: u64,
+    physical_block_size: u64,
+    optimal_io_size: u64,
+}
+
+fn read_queue_limits(sysfs_block: &Path, device: &str) -> Result<QueueLimits> {
+    let queue = sysfs_block.join(device).join("queue");
+    let read = |name: &str| -> Result<u64> {
+        let path = queue.join(name);
+        std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {path:?}"))?
+            .trim()
+            .parse()
+            .with_context(|| format!("Failed to parse {path:?}"))
+    };
+    Ok(QueueLimits {
+        logical_block_size: read("logical_block_size")?,
+        physical_block_size: read("physical_block_size")?,
+        optimal_io_size: read("optimal_io_size")?,
+    })
+}
+
+/// Why each of `block_sizes` would measure something else than intended:
+/// IO below the physical block size is read-modify-write in the device and
+/// IO not in logical blocks is rejected.
+fn misaligned_block_sizes(limits: &QueueLimits, block_sizes: &[String]) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for block_size in block_sizes {
+        let Ok(bytes) = matrix::block_size_bytes(block_size) else {
+            log::info!("Not checking alignment of block size {block_size}");
+            continue;
+        };
+        if bytes < limits.physical_block_size {
+            warnings.push(format!(
+                "Block size {block_size} is below the physical block size of {} bytes",
+                limits.physical_block_size
+            ));
+        }
+        if limits.logical_block_size != 0 && bytes % limits.logical_block_size != 0 {
+            warnings.push(format!(
+                "Block size {block_size} is not a multiple of the logical block size of {} bytes",
+                limits.logical_block_size
+            ));
+        }
+    }
+    warnings
+}
+
+/// Check the device node and its sysfs directory are there, before tuning
+/// starts rather than in the middle of a run.
+fn check_block_device(dev_dir: &Path, sysfs_block: &Path, device: &str) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    let node = dev_dir.join(device);
+    let metadata =
+        std::fs::metadata(&node).with_context(|| format!("Device node {node:?} does not exist"))?;
+    if !metadata.file_type().is_block_device() {
+        return Err(anyhow!("{node:?} is not a block device"));
+    }
+    let sysfs = sysfs_block.join(device);
+    if !sysfs.is_dir() {
+        return Err(anyhow!(
+            "{sysfs:?} does not exist, {device} is not a whole disk"
+        ));
+    }
+    Ok(())
+}
+
+fn check_device_fits<'a>(
+    device: &str,
+    entries: impl Iterator<Item = &'a MatrixEntry>,
+) -> Result<()> {
+    let capacity = device_size_bytes(device)?;
+    let mut too_large = Vec::new();
+    for entry in entries {
+        let working_set = working_set_bytes(entry)?;
+        if working_set > capacity {
+            log::error!("Config {entry} needs {working_set} bytes, {device} has {capacity}");
+            too_large.push(entry.to_string());
+        }
+    }
+
+    if !too_large.is_empty() {
+        return Err(anyhow!(
+            "{} configs do not fit on {device} ({capacity} bytes): {}",
+            too_large.len(),
+            too_large.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+fn setup_cnull(name: &str, cnull: &config::CnullConfig) -> Result<()> {
+    use std::fs::create_dir;
+    let control_path = PathBuf::from(CNULL_CONFIGFS).tap_mut(|p| p.push(name));
+
+    log::info!("Configuring null block at {control_path:?}");
+
+    control_path
+        .clone()
+        .pipe(create_dir)
+        .context("create debugfs folder")?;
+
+    let write_control_file = |name: &str, value: &str| -> Result<()> {
+        control_path
+            .clone()
+            .tap_mut(|p| p.push(name))
+            .pipe(|p| File::options().write(true).open(p))
+            .context("open control file")?
+            .write_all(value.as_bytes())
+            .context("Failed to write control path")
+    };
+
+    let mut values = vec![
+        ("blocksize", cnull.blocksize.to_string()),
+        ("completion_nsec", cnull.completion_nsec.to_string()),
+        ("irqmode", cnull.irqmode.to_string()),
+        ("queue_mode", cnull.queue_mode.to_string()),
+        ("hw_queue_depth", cnull.hw_queue_depth.to_string()),
+        ("memory_backed", u8::from(cnull.memory_backed).to_string()),
+        ("size", cnull.size_mib.to_string()),
+        ("poll_queues", cnull.poll_queues.to_string()),
+    ];
+    if let Some(submit_queues) = cnull.submit_queues {
+        values.push(("submit_queues", submit_queues.to_string()));
+    }
+    for (name, value) in values {
+        write_control_file(name, &value).context(name)?;
+    }
+    write_control_file("power", "1").context("power")?; // Instantiate device
+
+    Ok(())
+}
+
+/// Tears down a null block device that lives for the whole sweep, also when
+/// the sweep fails.
+struct CnullGuard;
+
+impl Drop for CnullGuard {
+    fn drop(&mut self) {
+        if let Err(e) = teardown_cnull() {
+            log::warn!("Failed to tear down cnull: {e:?}");
+        }
+    }
+}
+
+fn teardown_cnull() -> Result<()> {
+    for entry in std::fs::read_dir(CNULL_CONFIGFS)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            std::fs::remove_dir(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn dm_table(dm: &config::DmConfig, device: &str) -> String {
+    dm.table.replace("{device}", &format!("/dev/{device}"))
+}
+
+fn setup_dm(dm: &config::DmConfig, device: &str) -> Result<()> {
+    let table = dm_table(dm, device);
+    log::info!("Creating dm device {} with table {table:?}", dm.name);
+    Command::new("dmsetup")
+        .arg("create")
+        .arg(&dm.name)
+        .arg("--table")
+        .arg(table)
+        .spawn()?
+        .wait()?
+        .check_status()
+}
+
+/// Removes a dm device that lives for the whole sweep, also when the sweep
+/// fails.
+struct DmGuard<'a>(&'a str);
+
+impl Drop for DmGuard<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = teardown_dm(self.0) {
+            log::warn!("Failed to remove dm device: {e:?}");
+        }
+    }
+}
+
+fn teardown_dm(name: &str) -> Result<()> {
+    log::info!("Removing dm device {name}");
+    Command::new("dmsetup")
+        .arg("remove")
+        .arg(name)
+        .spawn_retry(3, std::time::Duration::from_secs(1))
+}
+
+/// Parse the contents of `queue/scheduler`, e.g. `[none] mq-deadline kyber`.
+fn parse_available_schedulers(data: &str) -> Vec<String> {
+    data.split_whitespace()
+        .map(|s| s.trim_start_matches('[').trim_end_matches(']').to_string())
+        .collect()
+}
+
+const SYSFS_BLOCK: &str = "/sys/block";
+
+/// Set `scheduler` and disable iostats on `device` under the `/sys/block`
+/// at `sysfs_block`.
+fn tune_queue(sysfs_block: &Path, device: &str, scheduler: &str, retry: SysfsRetry) -> Result<()> {
+    set_block_scheduler(sysfs_block, device, scheduler, retry)
+        .with_context(|| format!("Set block scheduler of {device}"))?;
+    disable_iostats(sysfs_block, device, retry)
+        .with_context(|| format!("Disable iostats of {device}"))
+}
+
+/// How an attribute shows a written value once it took effect.
+#[derive(Clone, Copy)]
+enum ReadBack {
+    /// Reads back exactly what was written
+    Same,
+    /// Lists the choices with the active one in brackets, as `scheduler`
+    Selected,
+}
 
-        if config.modprobe {
-            Command::new("modprobe")
-                .arg("-r")
-                .arg(module)
-                .spawn_retry(3, std::time::Duration::from_secs(1))?;
+impl ReadBack {
+    fn took(self, written: &str, data: &str) -> bool {
+        match self {
+            ReadBack::Same => data.trim() == written.trim(),
+            ReadBack::Selected => active_choice(data) == Some(written.trim()),
         }
     }
-
-    Ok(())
 }
 
-fn setup_cnull(name: &str) -> Result<()> {
-    use std::fs::create_dir;
-    let control_path = PathBuf::from("/sys/kernel/config/nullb").tap_mut(|p| p.push(name));
+/// The bracketed entry of a choice list, or the only entry without
+/// brackets.
+fn active_choice(data: &str) -> Option<&str> {
+    let mut choices = data.split_whitespace();
+    let active = data
+        .split_whitespace()
+        .find_map(|s| s.strip_prefix('[')?.strip_suffix(']'));
+    match (active, choices.next(), choices.next()) {
+        (Some(active), _, _) => Some(active),
+        (None, Some(only), None) => Some(only),
+        _ => None,
+    }
+}
 
-    log::info!("Configuring null block at {control_path:?}");
+/// How hard `write_sysfs` tries.
+#[derive(Clone, Copy, Debug)]
+struct SysfsRetry {
+    attempts: u32,
+    /// Writes failing with `EBUSY` are retried for this long on top of the
+    /// attempts, for devices still settling after they were created.
+    busy_timeout: std::time::Duration,
+}
 
-    control_path
-        .clone()
-        .pipe(create_dir)
-        .context("create debugfs folder")?;
+impl SysfsRetry {
+    fn new(config: &config::Config) -> Self {
+        Self {
+            attempts: config.sysfs_write_attempts,
+            busy_timeout: std::time::Duration::from_secs(config.sysfs_busy_retry_secs.into()),
+        }
+    }
+}
 
-    let write_control_file = |name: &str, value: &str| -> Result<()> {
-        control_path
-            .clone()
-            .tap_mut(|p| p.push(name))
-            .pipe(|p| File::options().write(true).open(p))
-            .context("open control file")?
-            .write_all(value.as_bytes())
-            .context("Failed to write control path")
+/// Name the likely cause of a failed attribute write, busy, permissions or
+/// a missing device all look alike otherwise.
+fn sysfs_write_error(path: &Path, value: &str, e: std::io::Error) -> anyhow::Error {
+    let cause = match e.raw_os_error() {
+        Some(libc::EBUSY) => " (device busy, it may be mounted, in use or still settling)",
+        Some(libc::EACCES | libc::EPERM) => " (permission denied, tuning needs root)",
+        Some(libc::ENOENT | libc::ENODEV) => " (no such attribute, is the device present?)",
+        _ => "",
     };
+    anyhow::Error::new(e).context(format!("Failed to write {value:?} to {path:?}{cause}"))
+}
 
-    write_control_file("blocksize", "4096").context("blocksize")?;
-    write_control_file("completion_nsec", "0").context("completion_nsec")?;
-    write_control_file("irqmode", "0").context("irqmode")?; // IRQ_NONE
-    write_control_file("queue_mode", "2").context("queue_mode")?; // MQ
-    write_control_file("hw_queue_depth", "256").context("hw_queue_depth")?;
-    write_control_file("memory_backed", "1").context("memory_backed")?;
-    write_control_file("size", "4096").context("size")?; // 4G
-    write_control_file("poll_queues", "0").context("poll_queues")?;
-    write_control_file("power", "1").context("power")?; // Instantiate device
-
-    Ok(())
+fn is_busy(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.raw_os_error() == Some(libc::EBUSY))
 }
 
-fn teardown_cnull() -> Result<()> {
-    for entry in std::fs::read_dir("/sys/kernel/config/nullb")? {
-        let entry = entry?;
-        if entry.file_type()?.is_dir() {
-            std::fs::remove_dir(entry.path())?;
+/// Write `value` to a sysfs or procfs attribute and read it back. Writes
+/// that fail or do not stick are retried, up to `retry.attempts` in total
+/// and for `retry.busy_timeout` while the device is busy.
+fn write_sysfs(path: &Path, value: &str, read_back: ReadBack, retry: SysfsRetry) -> Result<()> {
+    const RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(200);
+
+    let started = std::time::Instant::now();
+    let mut attempt = 1;
+    loop {
+        let result = std::fs::write(path, value)
+            .map_err(|e| sysfs_write_error(path, value, e))
+            .and_then(|_| {
+                let data = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read back {path:?}"))?;
+                match read_back.took(value, &data) {
+                    true => Ok(()),
+                    false => Err(anyhow!(
+                        "{path:?} reads {:?} after writing {value:?}",
+                        data.trim()
+                    )),
+                }
+            });
+        match result {
+            Err(e) if attempt < retry.attempts => {
+                log::warn!("Sysfs write attempt {attempt}/{}: {e:?}", retry.attempts);
+                attempt += 1;
+                std::thread::sleep(RETRY_DELAY);
+            }
+            Err(e) if is_busy(&e) && started.elapsed() < retry.busy_timeout => {
+                log::warn!("{path:?} is busy, retrying");
+                std::thread::sleep(RETRY_DELAY);
+            }
+            result => return result,
         }
     }
-    Ok(())
 }
 
-fn set_block_scheduler(device: &str) -> Result<()> {
-    log::info!("Setting block scheduler");
-    PathBuf::from("/sys/block")
+fn set_block_scheduler(
+    sysfs_block: &Path,
+    device: &str,
+    scheduler: &str,
+    retry: SysfsRetry,
+) -> Result<()> {
+    log::info!("Setting block scheduler to {scheduler}");
+    let path = PathBuf::from(sysfs_block)
         .tap_mut(|p| p.push(device))
         .tap_mut(|p| p.push("queue"))
-        .tap_mut(|p| p.push("scheduler"))
-        .pipe(|p| File::options().write(true).open(p))
-        .context("Failed to open scheduler file for write")?
-        .write_all("none".as_bytes())
-        .context("Failed to write control path")
+        .tap_mut(|p| p.push("scheduler"));
+
+    let available = std::fs::read_to_string(&path)
+        .context("Failed to read available schedulers")?
+        .pipe(|data| parse_available_schedulers(&data));
+    if !available.iter().any(|s| s == scheduler) {
+        return Err(anyhow!(
+            "Scheduler {scheduler} not available for {device}, available: {}",
+            available.join(" ")
+        ));
+    }
+
+    write_sysfs(&path, scheduler, ReadBack::Selected, retry)
 }
 
-fn disable_iostats(device: &str) -> Result<()> {
+fn disable_iostats(sysfs_block: &Path, device: &str, retry: SysfsRetry) -> Result<()> {
     log::info!("Disabling iostats");
-    PathBuf::from("/sys/block")
+    let path = PathBuf::from(sysfs_block)
         .tap_mut(|p| p.push(device))
         .tap_mut(|p| p.push("queue"))
-        .tap_mut(|p| p.push("iostats"))
-        .pipe(|p| File::options().write(true).open(p))
-        .context("Failed to open scheduler file for write")?
-        .write_all("0".as_bytes())
-        .context("Failed to write control path")
+        .tap_mut(|p| p.push("iostats"));
+    write_sysfs(&path, "0", ReadBack::Same, retry)
 }
 
-fn set_governor() -> Result<()> {
+fn set_governor(config: &config::Config) -> Result<()> {
     log::info!("Setting cpupower governor");
-    Command::new("cpupower")
+    tuning_command(config, "cpupower")
         .arg("frequency-set")
         .arg("-g")
         .arg("performance")
@@ -603,44 +3642,140 @@ fn set_governor() -> Result<()> {
         .context("Failed to set cpu frequency governor")
 }
 
-fn amd_pstate_fixed_3ghz() -> Result<()> {
-    std::fs::write("/sys/devices/system/cpu/amd_pstate/status", "guided")?;
-    set_governor()?;
-    disable_boost_amd()?;
+/// Switch amd_pstate to guided mode and cap every policy at `freq` kHz,
+/// with boost off.
+fn amd_pstate_fixed_freq(config: &config::Config, freq: u64) -> Result<()> {
+    let retry = SysfsRetry::new(config);
+    write_sysfs(
+        Path::new("/sys/devices/system/cpu/amd_pstate/status"),
+        "guided",
+        ReadBack::Same,
+        retry,
+    )?;
+    set_governor(config)?;
+    disable_boost_amd(retry)?;
     for entry in glob::glob("/sys/devices/system/cpu/cpufreq/policy*/scaling_max_freq")? {
-        std::fs::write(&entry?, "3000000\n")?
+        write_sysfs(&entry?, &format!("{freq}\n"), ReadBack::Same, retry)?;
     }
     Ok(())
 }
 
-fn disable_boost_amd() -> Result<()> {
-    log::info!("Disabling amd boost");
-    std::fs::write("/sys/devices/system/cpu/cpufreq/boost", "0\n")?;
+/// Set the minimum and maximum frequency of every cpufreq policy to `freq`
+/// kHz, with turbo off.
+fn intel_fixed_freq(freq: u64, retry: SysfsRetry) -> Result<()> {
+    log::info!("Pinning cpu frequency to {freq} kHz");
+    disable_turbo_intel(retry)?;
+    for entry in glob::glob("/sys/devices/system/cpu/cpufreq/policy*")? {
+        let policy = entry?;
+        let (min, max) = (
+            policy.join("scaling_min_freq"),
+            policy.join("scaling_max_freq"),
+        );
+        let current_min = std::fs::read_to_string(&min)
+            .with_context(|| format!("Failed to read {min:?}"))?
+            .trim()
+            .parse::<u64>()
+            .with_context(|| format!("Failed to parse {min:?}"))?;
+        // The maximum cannot go below the minimum, so which one is written
+        // first depends on the direction
+        let order = match freq >= current_min {
+            true => [max, min],
+            false => [min, max],
+        };
+        for path in order {
+            write_sysfs(&path, &format!("{freq}\n"), ReadBack::Same, retry)?;
+        }
+    }
     Ok(())
 }
 
-fn disable_turbo_intel() -> Result<()> {
+fn disable_boost_amd(retry: SysfsRetry) -> Result<()> {
+    log::info!("Disabling amd boost");
+    write_sysfs(
+        Path::new("/sys/devices/system/cpu/cpufreq/boost"),
+        "0\n",
+        ReadBack::Same,
+        retry,
+    )
+}
+
+fn disable_turbo_intel(retry: SysfsRetry) -> Result<()> {
     log::info!("Disabling intel turbo");
-    PathBuf::from("/sys/devices/system/cpu/intel_pstate/no_turbo")
-        .pipe(|p| File::options().write(true).open(p))?
-        .write_all("1\n".as_bytes())
-        .context("Failed to disable turbo boost")
+    write_sysfs(
+        Path::new("/sys/devices/system/cpu/intel_pstate/no_turbo"),
+        "1\n",
+        ReadBack::Same,
+        retry,
+    )
+    .context("Failed to disable turbo boost")
 }
 
-fn set_nr_hugepages(nr: u64) -> Result<()> {
+/// The kernel allocates what it can, a read back short of `nr` means
+/// memory is too fragmented. Retries give compaction another chance, and
+/// if that is not enough caches are dropped and memory compacted for one
+/// last try.
+fn set_nr_hugepages(nr: u64, retry: SysfsRetry) -> Result<()> {
+    const NR_HUGEPAGES: &str = "/proc/sys/vm/nr_hugepages";
+
     log::info!("Setting number of hugepages to {}", nr);
-    PathBuf::from("/proc/sys/vm/nr_hugepages")
-        .pipe(|p| File::options().write(true).open(p))?
-        .write_all(format!("{nr}\n").as_bytes())
-        .context("Failed to set number of hugepages")?;
-
-    let data = std::fs::read("/proc/sys/vm/nr_hugepages")?;
-    let val = std::str::from_utf8(&data)?.trim();
-    if val != &format!("{nr}") {
-        Err(anyhow!("Failed to set number of huge pages"))
-    } else {
-        Ok(())
+    let write = || {
+        write_sysfs(
+            Path::new(NR_HUGEPAGES),
+            &format!("{nr}\n"),
+            ReadBack::Same,
+            retry,
+        )
+    };
+    let obtained = || -> Option<u64> {
+        std::fs::read_to_string(NR_HUGEPAGES)
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    };
+
+    let Err(e) = write() else {
+        return Ok(());
+    };
+    match obtained() {
+        Some(obtained) if obtained < nr => {
+            log::warn!("Got {obtained} of {nr} hugepages, dropping caches and compacting memory");
+        }
+        _ => return Err(e.context(format!("Failed to set number of huge pages to {nr}"))),
+    }
+    std::fs::write("/proc/sys/vm/drop_caches", "3").context("Failed to drop caches")?;
+    std::fs::write("/proc/sys/vm/compact_memory", "1").context("Failed to compact memory")?;
+    write().map_err(|e| match obtained() {
+        Some(obtained) if obtained < nr => anyhow!(
+            "Needed {nr} hugepages but only {obtained} could be allocated, memory is too fragmented"
+        ),
+        _ => e.context(format!("Failed to set number of huge pages to {nr}")),
+    })
+}
+
+/// Allocate `nr` hugepages and check that all of them are there and free.
+fn require_hugepages(nr: u64, retry: SysfsRetry) -> Result<()> {
+    set_nr_hugepages(nr, retry)?;
+    let counters = ["HugePages_Total", "HugePages_Free"].map(String::from);
+    let meminfo = meminfo::Snapshot::take(&counters)?;
+    check_hugepages(
+        nr,
+        meminfo.get("HugePages_Total"),
+        meminfo.get("HugePages_Free"),
+    )
+}
+
+fn check_hugepages(nr: u64, total: Option<u64>, free: Option<u64>) -> Result<()> {
+    let (Some(total), Some(free)) = (total, free) else {
+        return Err(anyhow!("No hugepage counters in /proc/meminfo"));
+    };
+    if total < nr || free < nr {
+        return Err(anyhow!(
+            "Need {nr} free hugepages, have {free} free of {total}"
+        ));
     }
+    log::info!("{free} of {total} hugepages free, {nr} needed");
+    Ok(())
 }
 
 fn calculate_nr_hugepages(config: &config::Config) -> Result<u64> {
@@ -649,18 +3784,14 @@ fn calculate_nr_hugepages(config: &config::Config) -> Result<u64> {
         .iter()
         .max()
         .ok_or(anyhow!("jobcounts empty"))?
-        .clone()
-        .try_into()?;
+        .pipe(|v| u64::from(*v));
 
-    let block_size: Result<Vec<byte_unit::Byte>, _> = config
-        .block_sizes
+    let block_size = config
+        .all_block_sizes()
         .iter()
-        .map(|s| byte_unit::Byte::parse_str(s, true))
-        .collect();
-
-    let block_size: u64 = block_size?
+        .map(|s| matrix::block_size_bytes(s).with_context(|| format!("Invalid block size {s:?}")))
+        .collect::<Result<Vec<_>>>()?
         .into_iter()
-        .map(|b| b.as_u64())
         .max()
         .ok_or(anyhow!("block_sizes empty"))?;
 
@@ -669,10 +3800,11 @@ fn calculate_nr_hugepages(config: &config::Config) -> Result<u64> {
         .iter()
         .max()
         .ok_or(anyhow!("queue_depths empty"))?
-        .clone()
-        .try_into()?;
+        .pipe(|v| u64::from(*v));
 
-    calculate_nr_hugepages_int(queue_depth, block_size, jobcount)
+    // Every device running at once may run its largest config at the same time
+    let devices = config.concurrent_devices() as u64;
+    Ok(devices * calculate_nr_hugepages_int(queue_depth, block_size, jobcount)?)
 }
 
 fn calculate_nr_hugepages_int(queue_depth: u64, block_size: u64, jobcount: u64) -> Result<u64> {
@@ -700,6 +3832,418 @@ fn calculate_nr_hugepages_int(queue_depth: u64, block_size: u64, jobcount: u64)
 #[cfg(test)]
 mod test {
     use super::*;
+    #[test]
+    fn test_resolve_owner() -> Result<()> {
+        assert_eq!(resolve_owner("1000:100")?, (1000, 100));
+        assert_eq!(resolve_owner("root:0")?, (0, 0));
+        assert!(resolve_owner("no-such-user-fio-matrix:0").is_err());
+        assert!(resolve_owner("1000").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tune_queue() -> Result<()> {
+        let root = test_dir("sysfs");
+        let devices = ["nvme0n1", "nvme1n1"];
+        for device in devices {
+            let queue = root.join(device).join("queue");
+            std::fs::create_dir_all(&queue)?;
+            std::fs::write(queue.join("scheduler"), "[none] mq-deadline kyber\n")?;
+            std::fs::write(queue.join("iostats"), "1\n")?;
+        }
+
+        for device in devices {
+            tune_queue(&root, device, "kyber", test_retry(1))?;
+            let queue = root.join(device).join("queue");
+            assert_eq!(std::fs::read_to_string(queue.join("scheduler"))?, "kyber");
+            assert_eq!(std::fs::read_to_string(queue.join("iostats"))?, "0");
+        }
+        assert!(tune_queue(&root, devices[0], "bfq", test_retry(1)).is_err());
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_misaligned_block_sizes() -> Result<()> {
+        let root = test_dir("limits");
+        let queue = root.join("nvme0n1").join("queue");
+        std::fs::create_dir_all(&queue)?;
+        std::fs::write(queue.join("logical_block_size"), "512\n")?;
+        std::fs::write(queue.join("physical_block_size"), "4096\n")?;
+        std::fs::write(queue.join("optimal_io_size"), "0\n")?;
+
+        let limits = read_queue_limits(&root, "nvme0n1")?;
+        assert!(read_queue_limits(&root, "nvme1n1").is_err());
+
+        let block_sizes = ["4k", "1M", "128KiB", "512", "1000", "2kb"].map(String::from);
+        assert_eq!(
+            misaligned_block_sizes(&limits, &block_sizes),
+            vec![
+                "Block size 512 is below the physical block size of 4096 bytes",
+                "Block size 1000 is below the physical block size of 4096 bytes",
+                "Block size 1000 is not a multiple of the logical block size of 512 bytes",
+                "Block size 2kb is below the physical block size of 4096 bytes",
+            ]
+        );
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_ioengine_args() -> Result<()> {
+        let mut config = config::Config::default();
+        let entry = matrix::build_matrix(&config)[0].clone();
+        let args = fio_base_args(&config, &entry, "/dev/nvme0n1", 0, false)?;
+        assert!(args.contains(&"--ioengine=io_uring".to_string()));
+        assert!(args.contains(&"--fixedbufs=1".to_string()));
+
+        config.ioengine = config::IoEngine::Libaio;
+        let args = fio_base_args(&config, &entry, "/dev/nvme0n1", 0, false)?;
+        assert!(args.contains(&"--ioengine=libaio".to_string()));
+        assert!(args.contains(&"--direct=1".to_string()));
+        assert!(!args.iter().any(|a| a.starts_with("--fixedbufs")
+            || a.starts_with("--registerfiles")
+            || a.starts_with("--nonvectored")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_backoff() {
+        let secs = |first: u64, n: usize| {
+            backoff_delays(std::time::Duration::from_secs(first))
+                .take(n)
+                .map(|d| d.as_secs())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(secs(1, 9), [1, 2, 4, 8, 16, 32, 60, 60, 60]);
+        assert_eq!(secs(0, 3), [0, 0, 0]);
+        assert_eq!(secs(90, 3), [90, 90, 90]);
+
+        let config = config::Config {
+            remote_attempts: 3,
+            remote_retry_delay_secs: 0,
+            ..Default::default()
+        };
+        let mut calls = 0;
+        let result: Result<()> = with_backoff(&config, "Test", || {
+            calls += 1;
+            Err(anyhow!("unreachable"))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+
+        calls = 0;
+        let result = with_backoff(&config, "Test", || {
+            calls += 1;
+            match calls {
+                1 => Err(anyhow!("unreachable")),
+                _ => Ok(calls),
+            }
+        });
+        assert_eq!(result.ok(), Some(2));
+    }
+
+    #[test]
+    fn test_gtod_reduce_args() -> Result<()> {
+        let mut config = config::Config::default();
+        let entry = matrix::build_matrix(&config)[0].clone();
+        let args = fio_args(&config, &entry, 10, None, None)?;
+        assert!(args.contains(&"--gtod_reduce=1".to_string()));
+
+        // Nothing reads the percentiles of a plain captured batch
+        config.capture = true;
+        let args = fio_args(&config, &entry, 10, None, None)?;
+        assert!(args.contains(&"--gtod_reduce=1".to_string()));
+
+        config.thresholds = Some(config::Thresholds {
+            global: config::Threshold::default(),
+            workloads: [(
+                "randread".to_string(),
+                config::Threshold {
+                    max_p99_us: Some(100.0),
+                    min_iops: None,
+                },
+            )]
+            .into(),
+        });
+        let args = fio_args(&config, &entry, 10, None, None)?;
+        assert!(args.contains(&"--gtod_reduce=0".to_string()));
+        assert!(!args.contains(&"--gtod_reduce=1".to_string()));
+
+        // The fio_clat gauges, which need capture too
+        let mut config = config::Config {
+            device: "nullb0".into(),
+            prometheus_textfile: Some("fio.prom".into()),
+            ..Default::default()
+        };
+        let e = config.verify().unwrap_err();
+        assert!(e.to_string().contains("requires capture"), "{e}");
+        config.capture = true;
+        let args = fio_args(&config, &entry, 10, None, None)?;
+        assert!(args.contains(&"--gtod_reduce=0".to_string()));
+
+        // The latency bins of the json+ output
+        let mut config = config::Config {
+            device: "nullb0".into(),
+            histogram_percentiles: vec![99.99],
+            ..Default::default()
+        };
+        let e = config.verify().unwrap_err();
+        assert!(e.to_string().contains("requires capture"), "{e}");
+        config.capture = true;
+        let args = fio_args(&config, &entry, 10, None, None)?;
+        assert!(args.contains(&"--gtod_reduce=0".to_string()));
+
+        for config in [
+            config::Config {
+                latency_breakdown: true,
+                ..Default::default()
+            },
+            config::Config {
+                baseline: Some("baseline.csv".into()),
+                ..Default::default()
+            },
+        ] {
+            let args = fio_args(&config, &entry, 10, None, None)?;
+            assert!(args.contains(&"--gtod_reduce=0".to_string()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_extra_fio_args_last() -> Result<()> {
+        let config = config::Config {
+            extra_fio_args: vec!["--rate_iops=500".into()],
+            ..Default::default()
+        };
+        let entry = matrix::build_matrix(&config)[0].clone();
+        let args = fio_args(&config, &entry, 10, None, None)?;
+        assert_eq!(args.last().map(String::as_str), Some("--rate_iops=500"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_context() -> Result<()> {
+        let config = config::Config::default();
+        let context = cache_context(&config)?;
+        for changed in [
+            config::Config {
+                extra_fio_args: vec!["--rate_iops=500".into()],
+                ..Default::default()
+            },
+            config::Config {
+                cpus_allowed: Some("0-3".into()),
+                ..Default::default()
+            },
+            config::Config {
+                ramp: Some(5),
+                ..Default::default()
+            },
+        ] {
+            assert_ne!(cache_context(&changed)?, context);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_wait_fio_timeout() -> Result<()> {
+        let mut config = config::Config::default();
+        assert_eq!(
+            fio_timeout(&config, 2, 30),
+            Some(std::time::Duration::from_secs(420))
+        );
+        config.workload_timeout = Some(0);
+        assert_eq!(fio_timeout(&config, 2, 30), None);
+
+        let child = Command::new("sleep")
+            .arg("30")
+            .spawn_captured(None, None, Default::default())?
+            .stop_on_drop(std::time::Duration::from_secs(1));
+        let e = wait_fio(
+            child,
+            Some(std::time::Duration::from_millis(200)),
+            || Ok(()),
+        )
+        .unwrap_err();
+        assert!(e.downcast_ref::<FioTimeout>().is_some(), "{e:?}");
+
+        let child = Command::new("false").spawn_captured(None, None, Default::default())?;
+        let e = wait_fio(child, None, || Ok(())).unwrap_err();
+        assert_eq!(e.to_string(), "fio exited non-zero");
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_block_device() -> Result<()> {
+        let root = test_dir("block-device");
+        let (dev, sysfs) = (root.join("dev"), root.join("sys"));
+        std::fs::create_dir_all(&dev)?;
+        std::fs::create_dir_all(sysfs.join("nvme0n1"))?;
+
+        let e = check_block_device(&dev, &sysfs, "nvme0n1").unwrap_err();
+        assert!(
+            format!("{e:#}").contains("dev/nvme0n1\" does not exist"),
+            "{e:#}"
+        );
+        std::fs::write(dev.join("nvme0n1"), "")?;
+        let e = check_block_device(&dev, &sysfs, "nvme0n1").unwrap_err();
+        assert!(e.to_string().contains("is not a block device"), "{e:#}");
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_fio_command() {
+        let mut config = config::Config::default();
+        assert_eq!(fio_command(&config).get_program(), "fio");
+
+        config.fio_launcher = Some(vec!["numactl".into(), "--membind=0".into()]);
+        let command = fio_command(&config);
+        assert_eq!(command.get_program(), "numactl");
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            ["--membind=0", "fio"]
+        );
+    }
+
+    #[test]
+    fn test_check_free_space() -> Result<()> {
+        let dir = std::env::temp_dir();
+        assert!(free_space(&dir)? > 0);
+        check_free_space(&dir, 0)?;
+        assert!(check_free_space(&dir, u64::MAX).is_err());
+        Ok(())
+    }
+
+    fn test_retry(attempts: u32) -> SysfsRetry {
+        SysfsRetry {
+            attempts,
+            busy_timeout: std::time::Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn test_check_hugepages() {
+        assert!(check_hugepages(8, Some(8), Some(8)).is_ok());
+        assert!(check_hugepages(8, Some(16), Some(4)).is_err());
+        assert!(check_hugepages(8, Some(4), Some(4)).is_err());
+        assert!(check_hugepages(8, None, None).is_err());
+    }
+
+    #[test]
+    fn test_write_sysfs() -> Result<()> {
+        assert_eq!(
+            active_choice("none [mq-deadline] kyber\n"),
+            Some("mq-deadline")
+        );
+        assert_eq!(active_choice("kyber"), Some("kyber"));
+        assert_eq!(active_choice("none kyber"), None);
+        assert!(ReadBack::Same.took("0\n", "0"));
+        assert!(!ReadBack::Same.took("512\n", "480\n"));
+
+        let path = test_dir("attr");
+        write_sysfs(&path, "1\n", ReadBack::Same, test_retry(1))?;
+        assert_eq!(std::fs::read_to_string(&path)?, "1\n");
+        std::fs::remove_file(&path)?;
+
+        // A directory cannot be written, every attempt fails
+        let dir = std::env::temp_dir();
+        assert!(write_sysfs(&dir, "1", ReadBack::Same, test_retry(2)).is_err());
+
+        let missing = test_dir("none").join("attr");
+        let err = write_sysfs(&missing, "1", ReadBack::Same, test_retry(1)).unwrap_err();
+        assert!(err.to_string().contains("is the device present"), "{err}");
+        assert!(!is_busy(&err));
+        let busy = sysfs_write_error(&path, "1", std::io::Error::from_raw_os_error(libc::EBUSY));
+        assert!(busy.to_string().contains("device busy"), "{busy}");
+        assert!(is_busy(&busy));
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_time() {
+        let time = batch_time(Path::new("/out/output-nightly-brave-fox-2026-10-01-0930")).unwrap();
+        assert_eq!(time.to_string(), "2026-10-01 09:30:00");
+        assert!(batch_time(Path::new("/out/output-brave-fox")).is_none());
+        assert!(batch_time(Path::new("x")).is_none());
+    }
+
+    #[test]
+    fn test_check_split() {
+        assert!(check_split("0-3", 4).is_ok());
+        assert!(check_split("2,4,6", 3).is_ok());
+        assert!(check_split("0-1", 4).is_err());
+        assert!(check_split("0-7", 4).is_err());
+    }
+
+    #[test]
+    fn test_export_jobfiles() -> Result<()> {
+        let dir = test_dir("jobfiles");
+        let config = config::Config::default();
+        export_jobfiles(&config, &dir)?;
+
+        let entry = &matrix::build_matrix(&config)[0];
+        let path = dir.join(format!("{}.fio", matrix::run_output_id(&config, entry)));
+        let job_file = std::fs::read_to_string(path)?;
+        assert!(
+            job_file.contains(&format!("runtime={}\n", config.runtime)),
+            "{job_file}"
+        );
+        assert!(!job_file.contains("--"), "{job_file}");
+        assert!(job_file.contains("allow_file_create=0\n"), "{job_file}");
+        assert_eq!(
+            std::fs::read_dir(&dir)?.count(),
+            matrix::build_matrix(&config).len()
+        );
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_job_file() {
+        let args = [
+            "--name=default",
+            "--filename=/dev/nvme0n1",
+            "--group_reporting",
+            "--output-format=json+",
+            "--output=/tmp/out.json",
+        ]
+        .map(String::from);
+        let (job_file, client_args) = client_job_file(&args);
+        assert_eq!(
+            job_file,
+            "[default]\nfilename=/dev/nvme0n1\ngroup_reporting\n"
+        );
+        assert_eq!(
+            client_args,
+            ["--output-format=json+", "--output=/tmp/out.json"]
+        );
+
+        let mut args = vec![String::from("--filename=/dev/nvme0n1")];
+        push_phases(
+            &mut args,
+            &config::Workload::Phased {
+                name: "lifecycle".into(),
+                phases: vec![
+                    config::Phase { rw: "write".into() },
+                    config::Phase {
+                        rw: "randread".into(),
+                    },
+                ],
+            },
+        );
+        let (job_file, _) = client_job_file(&args);
+        assert_eq!(
+            job_file,
+            "[global]\nfilename=/dev/nvme0n1\n[1-write]\nreadwrite=write\n\
+             [2-randread]\nreadwrite=randread\nstonewall\n"
+        );
+    }
+
     #[test]
     fn test_calculate_nr_hugepages_int() -> Result<()> {
         assert_eq!(calculate_nr_hugepages_int(128, 32 * 2u64.pow(10), 6)?, 24);
@@ -714,12 +4258,18 @@ mod test {
     }
 
     #[test]
+    #[allow(clippy::field_reassign_with_default)]
     fn tets_calculate_nr_hugepages() -> Result<()> {
         let mut config = config::Config::default();
         config.block_sizes = vec!["16 MiB".into()];
         config.queue_depths = vec![128];
         config.jobcounts = vec![6];
         assert_eq!(calculate_nr_hugepages(&config)?, 6 * 1026);
+        // The same size to fio
+        config.block_sizes = vec!["16m".into()];
+        assert_eq!(calculate_nr_hugepages(&config)?, 6 * 1026);
+        config.block_sizes = vec!["16mm".into()];
+        assert!(calculate_nr_hugepages(&config).is_err());
 
         config.block_sizes = vec!["512".into(), "16MiB".into()];
         config.queue_depths = vec![1, 128];
@@ -730,6 +4280,174 @@ mod test {
         config.queue_depths = vec![1, 128];
         config.jobcounts = vec![1, 6];
         assert_eq!(calculate_nr_hugepages(&config)?, 6 * 1026);
+
+        config.devices = vec!["nvme0n1".into(), "nvme1n1".into()];
+        assert_eq!(calculate_nr_hugepages(&config)?, 6 * 1026);
+        config.parallel_devices = true;
+        assert_eq!(calculate_nr_hugepages(&config)?, 2 * 6 * 1026);
+        config.devices = vec!["nvme0n1".into()];
+        assert_eq!(calculate_nr_hugepages(&config)?, 6 * 1026);
+        Ok(())
+    }
+
+    pub(crate) fn test_dir(name: &str) -> PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!("fio-matrix-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    #[test]
+    fn test_parse_available_schedulers() {
+        assert_eq!(
+            parse_available_schedulers("[none] mq-deadline kyber\n"),
+            vec!["none", "mq-deadline", "kyber"]
+        );
+    }
+
+    #[test]
+    fn test_dm_table() {
+        let dm = config::DmConfig {
+            name: "linear0".into(),
+            table: "0 8388608 linear {device} 0".into(),
+        };
+        assert_eq!(dm_table(&dm, "nullb0"), "0 8388608 linear /dev/nullb0 0");
+    }
+
+    #[test]
+    fn test_perf_cpus() -> Result<()> {
+        let mut config = config::Config {
+            jobcounts: vec![2, 4],
+            ..Default::default()
+        };
+        let entry = &matrix::build_matrix(&config)[0];
+        assert_eq!(
+            perf_cpus(&config, entry, &[0, 1, 2, 3, 4, 5])?,
+            [2, 3, 4, 5]
+        );
+        assert_eq!(perf_cpus(&config, entry, &[0, 1])?, [0, 1]);
+
+        config.background_jobs = Some(config::BackgroundJobs {
+            rw: "randread".into(),
+            bs: "4k".into(),
+            jobs: 1,
+            iodepth: 1,
+        });
+        assert_eq!(perf_cpus(&config, entry, &[0, 1, 2, 3, 4, 5])?, [2, 3, 5]);
+
+        config.cpus_allowed = Some("4-5".into());
+        config.background_jobs = None;
+        assert_eq!(
+            perf_cpus(&config, entry, &[0, 1, 2, 3, 4, 5])?,
+            [0, 1, 2, 3]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dm_per_config() {
+        let mut config = config::Config {
+            configure_c_nullblk: true,
+            cnull_lifecycle: config::CnullLifecycle::Once,
+            module: Some("cnull".into()),
+            module_reload_policy: config::ModuleReloadPolicy::Once,
+            ..Default::default()
+        };
+        assert!(!dm_per_config(&config));
+        config.cnull_lifecycle = config::CnullLifecycle::PerConfig;
+        assert!(dm_per_config(&config));
+        config.configure_c_nullblk = false;
+        config.module_reload_policy = config::ModuleReloadPolicy::Always;
+        assert!(dm_per_config(&config));
+    }
+
+    #[test]
+    fn test_archive_run_outputs() -> Result<()> {
+        let dir = test_dir("retry");
+        std::fs::create_dir_all(&dir)?;
+        for name in [
+            "a-qd1.json",
+            "a-qd1-prep.stdout",
+            "a-qd1.log",
+            "a-qd16.json",
+            "other.json",
+        ] {
+            std::fs::write(dir.join(name), "")?;
+        }
+
+        archive_run_outputs(&dir, "a-qd1", 1)?;
+        std::fs::write(dir.join("a-qd1.json"), "")?;
+        archive_run_outputs(&dir, "a-qd1", 2)?;
+        let mut left = std::fs::read_dir(&dir)?
+            .map(|e| Ok(e?.file_name().into_string().unwrap()))
+            .collect::<Result<Vec<_>>>()?;
+        left.sort();
+        assert_eq!(
+            left,
+            vec![
+                "a-qd1.attempt1-prep.stdout",
+                "a-qd1.attempt1.json",
+                "a-qd1.attempt2.json",
+                "a-qd1.log",
+                "a-qd16.json",
+                "other.json"
+            ]
+        );
+        assert!(run_outputs(&dir, "a-qd1")?.is_empty());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_mounts_on() {
+        let mounts = "/dev/nvme0n1p2 / ext4 rw 0 0\n\
+                      proc /proc proc rw 0 0\n\
+                      /dev/mapper/data /data xfs rw 0 0\n\
+                      /dev/sdb1 /mnt ext4 rw 0 0\n";
+        let resolve = |source: &str| match source {
+            "/dev/mapper/data" => Some("dm-0".to_string()),
+            s => s.strip_prefix("/dev/").map(String::from),
+        };
+        let stack = vec!["nvme0n1".into(), "nvme0n1p2".into(), "dm-0".into()];
+        assert_eq!(mounts_on(mounts, &stack, resolve), vec!["/", "/data"]);
+        assert!(mounts_on(mounts, &["nullb0".into()], resolve).is_empty());
+    }
+
+    #[test]
+    fn test_module_name() {
+        assert_eq!(module_name("null_blk"), "null_blk");
+        assert_eq!(module_name("/lib/modules/rnull-mod.ko"), "rnull_mod");
+    }
+
+    #[test]
+    fn test_get_run_dir_unique() -> Result<()> {
+        let prefix = test_dir("run-dir-unique");
+        let prefix_str = prefix.to_str().unwrap();
+
+        let dirs = (0..100)
+            .map(|_| get_run_dir(prefix_str))
+            .collect::<Result<Vec<_>>>()?;
+
+        let unique = dirs.iter().collect::<std::collections::HashSet<_>>();
+        assert_eq!(unique.len(), dirs.len());
+        assert!(dirs.iter().all(|d| d.is_dir()));
+
+        std::fs::remove_dir_all(&prefix)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_sample_dir() -> Result<()> {
+        let prefix = test_dir("sample-dir");
+        let prefix_str = prefix.to_str().unwrap();
+
+        let dir = get_sample_dir(prefix_str, 3)?;
+        assert_eq!(dir, prefix.join("sample-0003"));
+        assert!(dir.is_dir());
+        assert_eq!(get_sample_dir(prefix_str, 3)?, dir);
+
+        std::fs::remove_dir_all(&prefix)?;
         Ok(())
     }
 }
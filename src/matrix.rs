@@ -0,0 +1,819 @@
+use crate::config;
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use tap::Tap;
+
+/// One point of the benchmark matrix.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct MatrixEntry {
+    pub(crate) block_size: String,
+    pub(crate) jobcount: u32,
+    pub(crate) workload: config::Workload,
+    pub(crate) queue_depth: u32,
+    pub(crate) scheduler: String,
+
+    /// Per job rate limit, unlimited if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) rate: Option<Rate>,
+
+    /// Pause between bursts of IO, as fio's `--thinktime`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) thinktime: Option<String>,
+
+    /// From the `label_rules` the entry meets.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) labels: Vec<String>,
+
+    /// One of `devices`, unset when sweeping `device` alone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) device: Option<String>,
+}
+
+impl std::fmt::Display for MatrixEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "qd:{} bs:{} jobs:{} wl:{} sched:{}",
+            self.queue_depth, self.block_size, self.jobcount, self.workload, self.scheduler
+        )?;
+        if let Some(rate) = &self.rate {
+            write!(f, " rate:{rate}")?;
+        }
+        if let Some(thinktime) = &self.thinktime {
+            write!(f, " think:{thinktime}")?;
+        }
+        if let Some(device) = &self.device {
+            write!(f, " dev:{device}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Offered load of a fixed rate run, as fio's `--rate_iops` or `--rate`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Rate {
+    Iops(u64),
+    Bandwidth(String),
+}
+
+impl std::fmt::Display for Rate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rate::Iops(iops) => write!(f, "{iops}iops"),
+            Rate::Bandwidth(bw) => write!(f, "{bw}"),
+        }
+    }
+}
+
+/// The rate dimension of the matrix, a single unlimited entry if no rates
+/// are configured.
+fn rates(config: &config::Config) -> Vec<Option<Rate>> {
+    let rates = config
+        .rate_iops
+        .iter()
+        .map(|iops| Some(Rate::Iops(*iops)))
+        .chain(
+            config
+                .rate_bandwidths
+                .iter()
+                .map(|bw| Some(Rate::Bandwidth(bw.clone()))),
+        )
+        .collect::<Vec<_>>();
+    match rates.is_empty() {
+        true => vec![None],
+        false => rates,
+    }
+}
+
+/// The think time dimension of the matrix, a single entry without pauses
+/// if none are configured.
+fn thinktimes(config: &config::Config) -> Vec<Option<String>> {
+    match config.thinktimes.is_empty() {
+        true => vec![None],
+        false => config.thinktimes.iter().cloned().map(Some).collect(),
+    }
+}
+
+fn label_matches(rule: &config::LabelRule, entry: &MatrixEntry) -> bool {
+    let size = |block_size: &str| block_size_bytes(block_size).ok();
+    let block_size = size(&entry.block_size);
+    rule.workload.as_deref().is_none_or(|workload| {
+        glob::Pattern::new(workload).is_ok_and(|p| p.matches(entry.workload.rw()))
+    }) && rule
+        .min_block_size
+        .as_deref()
+        .is_none_or(|min| block_size.zip(size(min)).is_some_and(|(b, min)| b >= min))
+        && rule
+            .max_block_size
+            .as_deref()
+            .is_none_or(|max| block_size.zip(size(max)).is_some_and(|(b, max)| b <= max))
+}
+
+/// The labels of the rules `entry` meets, in rule order.
+fn labels(config: &config::Config, entry: &MatrixEntry) -> Vec<String> {
+    config
+        .label_rules
+        .iter()
+        .filter(|rule| label_matches(rule, entry))
+        .map(|rule| rule.label.clone())
+        .collect()
+}
+
+/// A matrix entry scheduled for a specific sample.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct PlannedRun {
+    pub(crate) sample: u32,
+
+    #[serde(flatten)]
+    pub(crate) entry: MatrixEntry,
+}
+
+/// Where the matrix run currently is, sent along with remote pings.
+#[derive(Debug, Serialize)]
+pub(crate) struct Progress<'a> {
+    /// Zero based position in the plan.
+    pub(crate) index: u64,
+    pub(crate) total: u64,
+    pub(crate) sample: u32,
+    pub(crate) entry: &'a MatrixEntry,
+}
+
+pub(crate) const FAILED_FILE: &str = "failed.json";
+
+/// Where the sweep is, for `status_file`. Times are in seconds, the
+/// estimate extrapolates the mean time of the configs done so far.
+#[derive(Debug, Serialize)]
+pub(crate) struct Status<'a> {
+    /// UTC, RFC 3339.
+    pub(crate) updated_at: String,
+    pub(crate) finished: bool,
+    /// Configs done, which is the index of the current one.
+    pub(crate) completed: u64,
+    pub(crate) total: u64,
+    pub(crate) failed: usize,
+    pub(crate) current: Option<&'a PlannedRun>,
+    pub(crate) elapsed_secs: f64,
+    pub(crate) eta_secs: Option<f64>,
+}
+
+impl<'a> Status<'a> {
+    pub(crate) fn new(
+        completed: u64,
+        total: u64,
+        failed: usize,
+        current: Option<&'a PlannedRun>,
+        elapsed: std::time::Duration,
+    ) -> Self {
+        let elapsed_secs = elapsed.as_secs_f64();
+        Self {
+            updated_at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            finished: current.is_none(),
+            completed,
+            total,
+            failed,
+            current,
+            elapsed_secs,
+            eta_secs: (completed > 0)
+                .then(|| elapsed_secs / completed as f64 * total.saturating_sub(completed) as f64),
+        }
+    }
+}
+
+/// Renamed into place, so readers never see a partial file.
+pub(crate) fn write_status(path: &Path, status: &Status) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let file = std::fs::File::create(&tmp).with_context(|| format!("Failed to create {tmp:?}"))?;
+    serde_json::to_writer_pretty(file, status).context("Failed to write status")?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Failed to rename {tmp:?}"))
+}
+
+pub(crate) const TIMELINE_FILE: &str = "timeline.json";
+
+/// Wall clock span of one attempt at a matrix entry. Times are UTC in RFC
+/// 3339, the duration is from the monotonic clock.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct TimelineEntry {
+    pub(crate) sample: u32,
+    pub(crate) entry: MatrixEntry,
+    pub(crate) start: String,
+    pub(crate) end: String,
+    pub(crate) duration_secs: f64,
+    pub(crate) succeeded: bool,
+}
+
+impl TimelineEntry {
+    /// An attempt started at `start` and `started` that just ended.
+    pub(crate) fn ended(
+        sample: u32,
+        entry: &MatrixEntry,
+        start: chrono::DateTime<chrono::Utc>,
+        started: std::time::Instant,
+        succeeded: bool,
+    ) -> Self {
+        let format = |t: chrono::DateTime<chrono::Utc>| {
+            t.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+        };
+        Self {
+            sample,
+            entry: entry.clone(),
+            start: format(start),
+            end: format(chrono::Utc::now()),
+            duration_secs: started.elapsed().as_secs_f64(),
+            succeeded,
+        }
+    }
+}
+
+/// Rewritten after every config, so an aborted sweep keeps its timeline.
+pub(crate) fn write_timeline(batch_dir: &Path, timeline: &[TimelineEntry]) -> Result<()> {
+    let path = PathBuf::from(batch_dir).join(TIMELINE_FILE);
+    let file =
+        std::fs::File::create(&path).with_context(|| format!("Failed to create {path:?}"))?;
+    serde_json::to_writer_pretty(file, timeline).context("Failed to write timeline")
+}
+
+/// The timeline of a batch being resumed, empty if it has none.
+pub(crate) fn read_timeline(batch_dir: &Path) -> Result<Vec<TimelineEntry>> {
+    let path = batch_dir.join(TIMELINE_FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let file = std::fs::File::open(&path).with_context(|| format!("Failed to open {path:?}"))?;
+    serde_json::from_reader(file).with_context(|| format!("Failed to parse {path:?}"))
+}
+
+/// Prefix of every output file of an entry within a run directory.
+pub(crate) fn run_output_id(config: &config::Config, entry: &MatrixEntry) -> String {
+    let MatrixEntry {
+        block_size,
+        jobcount,
+        workload,
+        queue_depth,
+        scheduler,
+        rate,
+        thinktime,
+        labels: _,
+        device,
+    } = entry;
+    let mut run_output_id = format!(
+        "j{jobcount}-r{runtime}-w{workload}-bs{block_size}-qd{queue_depth}-s{scheduler}",
+        runtime = config.runtime,
+    );
+    if let Some(ramp) = config.ramp {
+        run_output_id.push_str(&format!("-ramp{ramp}"));
+    }
+    if let Some(latency_target) = &config.latency_target {
+        run_output_id.push_str(&format!("-lt{}", latency_target.target));
+    }
+    if let Some(rate) = rate {
+        run_output_id.push_str(&format!("-rate{rate}"));
+    }
+    if let Some(thinktime) = thinktime {
+        run_output_id.push_str(&format!("-tt{thinktime}"));
+        if let Some(blocks) = config.thinktime_blocks {
+            run_output_id.push_str(&format!("x{blocks}"));
+        }
+    }
+    if let Some(startdelay) = &config.startdelay {
+        run_output_id.push_str(&format!("-sd{startdelay}"));
+    }
+    if let Some(device) = device {
+        run_output_id.push_str(&format!("-d{device}"));
+    }
+    run_output_id
+}
+
+/// The block size in bytes, as passed to fio. Units are read the way fio
+/// reads them by default: `k`, `m`, `g`, `t` and `p` are powers of 1024 in
+/// any case, with or without a trailing `b`. The IEC `KiB` spellings are
+/// powers of 1024 as well, fio only gets the byte count.
+pub(crate) fn block_size_bytes(block_size: &str) -> Result<u64> {
+    let value = block_size.trim();
+    let digits = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let number: u64 = value[..digits]
+        .parse()
+        .with_context(|| format!("Bad size {block_size:?}"))?;
+    let unit = value[digits..].trim_start().to_ascii_lowercase();
+    let unit = unit.strip_suffix('b').unwrap_or(&unit);
+    let unit = match unit.strip_suffix('i') {
+        Some(prefix) if !prefix.is_empty() => prefix,
+        _ => unit,
+    };
+    let shift = match unit {
+        "" => 0,
+        "k" => 10,
+        "m" => 20,
+        "g" => 30,
+        "t" => 40,
+        "p" => 50,
+        _ => return Err(anyhow!("Bad unit in size {block_size:?}")),
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or(anyhow!("Size {block_size:?} out of range"))
+}
+
+fn same_block_size(a: &str, b: &str) -> bool {
+    match (block_size_bytes(a), block_size_bytes(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Spellings of a block size that is already in the matrix under another
+/// name, paired with the name used.
+pub(crate) fn duplicate_block_sizes(config: &config::Config) -> Vec<(String, String)> {
+    let mut canonical: Vec<String> = Vec::new();
+    let mut duplicates = Vec::new();
+    for block_size in config.all_block_sizes() {
+        match canonical.iter().find(|c| same_block_size(c, &block_size)) {
+            Some(c) => duplicates.push((block_size, c.clone())),
+            None => canonical.push(block_size),
+        }
+    }
+    duplicates
+}
+
+/// Block sizes with byte equivalent spellings collapsed into the first one.
+fn unique_block_sizes(config: &config::Config) -> Vec<String> {
+    let duplicates = duplicate_block_sizes(config);
+    config
+        .all_block_sizes()
+        .into_iter()
+        .filter(|b| !duplicates.iter().any(|(d, _)| d == b))
+        .collect()
+}
+
+pub(crate) fn build_matrix(config: &config::Config) -> Vec<MatrixEntry> {
+    use itertools::Itertools;
+    // Overrides can change the other dimensions, e.g. the schedulers
+    if !config.devices.is_empty() {
+        return config
+            .devices
+            .iter()
+            .flat_map(|device| {
+                build_matrix(&config.for_device(device))
+                    .into_iter()
+                    .map(|entry| MatrixEntry {
+                        device: Some(device.clone()),
+                        ..entry
+                    })
+            })
+            .collect();
+    }
+    unique_block_sizes(config)
+        .into_iter()
+        .cartesian_product(config.jobcounts.clone())
+        .cartesian_product(config.workloads.clone())
+        .filter(|((block_size, _), workload)| {
+            workload
+                .block_sizes(&config.block_sizes)
+                .iter()
+                .any(|b| same_block_size(b, block_size))
+        })
+        .cartesian_product(config.queue_depths.clone())
+        .cartesian_product(config.schedulers.clone())
+        .cartesian_product(rates(config))
+        .cartesian_product(thinktimes(config))
+        .map(
+            |(
+                (((((block_size, jobcount), workload), queue_depth), scheduler), rate),
+                thinktime,
+            )| {
+                MatrixEntry {
+                    block_size,
+                    jobcount,
+                    workload,
+                    queue_depth,
+                    scheduler,
+                    rate,
+                    thinktime,
+                    labels: Vec::new(),
+                    device: None,
+                }
+            },
+        )
+        .map(|entry| MatrixEntry {
+            labels: labels(config, &entry),
+            ..entry
+        })
+        .collect()
+}
+
+/// Every matrix entry once per sample, in the order they should run.
+pub(crate) fn build_plan(config: &config::Config) -> Vec<PlannedRun> {
+    use rand::seq::SliceRandom;
+    use rand::SeedableRng;
+    let mut matrix = build_matrix(config);
+    if config.order == config::MatrixOrder::Reversed {
+        matrix.reverse();
+    }
+    let mut rng = rand::rngs::StdRng::seed_from_u64(config.order_seed.unwrap_or(0));
+    (0..config.samples)
+        .flat_map(|sample| {
+            if config.order == config::MatrixOrder::Shuffled {
+                matrix.shuffle(&mut rng);
+            }
+            matrix
+                .iter()
+                .cloned()
+                .map(move |entry| PlannedRun { sample, entry })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+fn failed_path(batch_dir: &Path) -> PathBuf {
+    PathBuf::from(batch_dir).tap_mut(|p| p.push(FAILED_FILE))
+}
+
+pub(crate) fn write_failed(batch_dir: &Path, failed: &[PlannedRun]) -> Result<()> {
+    let path = failed_path(batch_dir);
+    log::info!("Writing {} failed configs to {path:?}", failed.len());
+    let file =
+        std::fs::File::create(&path).with_context(|| format!("Failed to create {path:?}"))?;
+    serde_json::to_writer_pretty(file, failed).context("Failed to write failed configs")
+}
+
+pub(crate) fn read_failed(batch_dir: &Path) -> Result<Vec<PlannedRun>> {
+    let path = failed_path(batch_dir);
+    let file = std::fs::File::open(&path).with_context(|| format!("Failed to open {path:?}"))?;
+    serde_json::from_reader(file).with_context(|| format!("Failed to parse {path:?}"))
+}
+
+pub(crate) const CHECKPOINT_FILE: &str = "progress.json";
+
+/// What of a batch is done, for `resume`. It is written after every config
+/// that finished, so output files of a config that was cut short are not
+/// taken for a result.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Checkpoint {
+    pub(crate) completed: Vec<PlannedRun>,
+
+    /// Name of the run directory of every sample begun, a resumed sample
+    /// writes into the same one.
+    pub(crate) run_dirs: BTreeMap<u32, String>,
+}
+
+pub(crate) fn write_checkpoint(batch_dir: &Path, checkpoint: &Checkpoint) -> Result<()> {
+    let path = batch_dir.join(CHECKPOINT_FILE);
+    let tmp = batch_dir.join(format!("{CHECKPOINT_FILE}.tmp"));
+    let file = std::fs::File::create(&tmp).with_context(|| format!("Failed to create {tmp:?}"))?;
+    serde_json::to_writer_pretty(file, checkpoint).context("Failed to write checkpoint")?;
+    std::fs::rename(&tmp, &path).with_context(|| format!("Failed to rename {tmp:?}"))
+}
+
+/// Without a checkpoint the batch stopped before any config was done.
+pub(crate) fn read_checkpoint(batch_dir: &Path) -> Result<Checkpoint> {
+    let path = batch_dir.join(CHECKPOINT_FILE);
+    if !path.exists() {
+        return Ok(Checkpoint::default());
+    }
+    let file = std::fs::File::open(&path).with_context(|| format!("Failed to open {path:?}"))?;
+    serde_json::from_reader(file).with_context(|| format!("Failed to parse {path:?}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_build_matrix_per_workload_block_sizes() {
+        let config = config::Config {
+            block_sizes: vec!["4k".into()],
+            jobcounts: vec![1],
+            queue_depths: vec![1, 8],
+            workloads: vec![
+                config::Workload::from("randread"),
+                config::Workload::Detailed {
+                    rw: "read".into(),
+                    block_sizes: Some(vec!["1M".into()]),
+                },
+            ],
+            ..Default::default()
+        };
+
+        let matrix = build_matrix(&config);
+        let pairs = matrix
+            .iter()
+            .map(|e| (e.workload.rw(), e.block_size.as_str(), e.queue_depth))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pairs,
+            vec![
+                ("randread", "4k", 1),
+                ("randread", "4k", 8),
+                ("read", "1M", 1),
+                ("read", "1M", 8),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_matrix_dedups_block_sizes() {
+        let config = config::Config {
+            block_sizes: vec![
+                "4KiB".into(),
+                "4096".into(),
+                "4 KiB".into(),
+                "1MiB".into(),
+                "4k".into(),
+                "4KB".into(),
+            ],
+            workloads: vec![config::Workload::Detailed {
+                rw: "read".into(),
+                block_sizes: Some(vec!["4096".into(), "1048576".into(), "4KB".into()]),
+            }],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            duplicate_block_sizes(&config),
+            vec![
+                ("4096".to_string(), "4KiB".to_string()),
+                ("4 KiB".to_string(), "4KiB".to_string()),
+                ("4k".to_string(), "4KiB".to_string()),
+                ("4KB".to_string(), "4KiB".to_string()),
+                ("1048576".to_string(), "1MiB".to_string()),
+            ]
+        );
+        let block_sizes = build_matrix(&config)
+            .into_iter()
+            .map(|e| e.block_size)
+            .collect::<Vec<_>>();
+        assert_eq!(block_sizes, vec!["4KiB", "1MiB"]);
+    }
+
+    #[test]
+    fn test_block_size_bytes() -> Result<()> {
+        for (block_size, bytes) in [
+            ("512", 512),
+            ("4k", 4096),
+            ("4K", 4096),
+            ("4kb", 4096),
+            ("4KiB", 4096),
+            ("128 k", 128 << 10),
+            ("1M", 1 << 20),
+            ("2g", 2 << 30),
+            ("512b", 512),
+        ] {
+            assert_eq!(block_size_bytes(block_size)?, bytes, "{block_size}");
+        }
+        for bad in ["", "4kk", "k", "4i", "1.5m", "4x"] {
+            assert!(block_size_bytes(bad).is_err(), "accepted {bad:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_matrix_rates() {
+        let mut config = config::Config::default();
+        assert_eq!(build_matrix(&config)[0].rate, None);
+
+        config.rate_iops = vec![1000, 2000];
+        config.rate_bandwidths = vec!["100m".into()];
+        let matrix = build_matrix(&config);
+        assert_eq!(
+            matrix.iter().map(|e| e.rate.clone()).collect::<Vec<_>>(),
+            vec![
+                Some(Rate::Iops(1000)),
+                Some(Rate::Iops(2000)),
+                Some(Rate::Bandwidth("100m".into())),
+            ]
+        );
+        assert!(run_output_id(&config, &matrix[0]).ends_with("-rate1000iops"));
+    }
+
+    #[test]
+    fn test_build_matrix_thinktimes() {
+        let mut config = config::Config::default();
+        assert_eq!(build_matrix(&config)[0].thinktime, None);
+
+        config.thinktimes = vec!["100us".into(), "1ms".into()];
+        config.thinktime_blocks = Some(8);
+        let matrix = build_matrix(&config);
+        assert_eq!(
+            matrix
+                .iter()
+                .map(|e| e.thinktime.clone())
+                .collect::<Vec<_>>(),
+            vec![Some("100us".into()), Some("1ms".into())]
+        );
+        assert!(run_output_id(&config, &matrix[1]).ends_with("-tt1msx8"));
+
+        config.startdelay = Some("1s-5s".into());
+        assert!(run_output_id(&config, &matrix[1]).ends_with("-tt1msx8-sd1s-5s"));
+    }
+
+    #[test]
+    fn test_build_matrix_devices() -> anyhow::Result<()> {
+        let mut config = config::Config {
+            devices: vec!["nvme0n1".into(), "nvme1n1".into()],
+            schedulers: vec!["none".into()],
+            force_destructive: true,
+            ..Default::default()
+        };
+        config.device_overrides.insert(
+            "nvme1n1".into(),
+            config::DeviceOverride {
+                schedulers: Some(vec!["none".into(), "mq-deadline".into()]),
+                ..Default::default()
+            },
+        );
+        config.verify()?;
+        let matrix = build_matrix(&config);
+        assert_eq!(
+            matrix
+                .iter()
+                .map(|e| (e.device.as_deref().unwrap(), e.scheduler.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("nvme0n1", "none"),
+                ("nvme1n1", "none"),
+                ("nvme1n1", "mq-deadline")
+            ]
+        );
+        assert!(run_output_id(&config, &matrix[0]).ends_with("-dnvme0n1"));
+        assert_ne!(
+            run_output_id(&config, &matrix[0]),
+            run_output_id(&config, &matrix[1])
+        );
+
+        config
+            .device_overrides
+            .insert("nvme2n1".into(), config::DeviceOverride::default());
+        assert!(config.verify().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_matrix_labels() {
+        let rule = |label: &str, workload: Option<&str>, max: Option<&str>| config::LabelRule {
+            label: label.into(),
+            workload: workload.map(String::from),
+            min_block_size: None,
+            max_block_size: max.map(String::from),
+        };
+        let config = config::Config {
+            block_sizes: vec!["4k".into(), "64KiB".into(), "1M".into()],
+            workloads: vec![
+                config::Workload::from("randread"),
+                config::Workload::from("read"),
+            ],
+            label_rules: vec![
+                rule("small-random", Some("rand*"), Some("64k")),
+                // Sizes compare in fio's bytes, 1024k is 1M and 64k is 64KiB
+                rule("large", None, None).tap_mut(|r| r.min_block_size = Some("1024k".into())),
+                rule("all", None, None),
+            ],
+            ..Default::default()
+        };
+        let labels = build_matrix(&config)
+            .into_iter()
+            .map(|e| {
+                (
+                    e.workload.rw().to_string(),
+                    e.block_size,
+                    e.labels.join(","),
+                )
+            })
+            .collect::<Vec<_>>();
+        let expected = [
+            ("randread", "4k", "small-random,all"),
+            ("read", "4k", "all"),
+            ("randread", "64KiB", "small-random,all"),
+            ("read", "64KiB", "all"),
+            ("randread", "1M", "large,all"),
+            ("read", "1M", "large,all"),
+        ]
+        .map(|(w, b, l)| (w.to_string(), b.to_string(), l.to_string()));
+        assert_eq!(labels, expected);
+    }
+
+    #[test]
+    fn test_timeline_entry() -> Result<()> {
+        let config = config::Config::default();
+        let entry = &build_matrix(&config)[0];
+        let start = chrono::DateTime::parse_from_rfc3339("2024-03-01T12:00:00.5+01:00")?.to_utc();
+        let timeline = TimelineEntry::ended(2, entry, start, std::time::Instant::now(), true);
+        assert_eq!(timeline.start, "2024-03-01T11:00:00.500Z");
+        assert!(timeline.end.ends_with('Z') && timeline.end > timeline.start);
+        assert!(timeline.duration_secs < 1.0);
+
+        let dir = crate::test::test_dir("timeline");
+        std::fs::create_dir_all(&dir)?;
+        assert!(read_timeline(&dir)?.is_empty());
+        write_timeline(&dir, &[timeline])?;
+        let read = read_timeline(&dir)?;
+        assert_eq!((read.len(), read[0].sample), (1, 2));
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_status() -> Result<()> {
+        let plan = build_plan(&config::Config::default());
+        let elapsed = std::time::Duration::from_secs(30);
+        let status = Status::new(0, 4, 0, Some(&plan[0]), elapsed);
+        assert_eq!(status.eta_secs, None);
+        assert!(!status.finished);
+        let status = Status::new(3, 4, 1, Some(&plan[0]), elapsed);
+        assert_eq!(status.eta_secs, Some(10.0));
+
+        let path = crate::test::test_dir("status.json");
+        write_status(&path, &Status::new(4, 4, 1, None, elapsed))?;
+        let written: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+        assert_eq!(written["finished"], true);
+        assert_eq!(written["eta_secs"], 0.0);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_plan_order() {
+        let mut config = config::Config {
+            block_sizes: vec!["4k".into(), "64k".into(), "1m".into()],
+            jobcounts: vec![1, 2],
+            samples: 3,
+            ..Default::default()
+        };
+        let matrix = build_matrix(&config);
+        let sample = |plan: &[PlannedRun], sample: u32| {
+            plan.iter()
+                .filter(|run| run.sample == sample)
+                .map(|run| run.entry.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sample(&build_plan(&config), 2), matrix);
+
+        config.order = config::MatrixOrder::Reversed;
+        let reversed = matrix.iter().rev().cloned().collect::<Vec<_>>();
+        assert_eq!(sample(&build_plan(&config), 0), reversed);
+
+        config.order = config::MatrixOrder::Shuffled;
+        config.order_seed = Some(42);
+        let plan = build_plan(&config);
+        assert_eq!(plan, build_plan(&config));
+        for i in 0..3 {
+            let mut shuffled = sample(&plan, i);
+            assert_eq!(shuffled.len(), matrix.len());
+            shuffled.sort_by_key(|e| matrix.iter().position(|m| m == e));
+            assert_eq!(shuffled, matrix);
+        }
+        assert_ne!(sample(&plan, 0), sample(&plan, 1));
+
+        config.order_seed = Some(43);
+        assert_ne!(build_plan(&config), plan);
+    }
+
+    #[test]
+    fn test_failed_roundtrip() -> Result<()> {
+        let dir = crate::test::test_dir("failed");
+        std::fs::create_dir_all(&dir)?;
+
+        let config = config::Config {
+            samples: 2,
+            ..Default::default()
+        };
+        let plan = build_plan(&config);
+        write_failed(&dir, &plan)?;
+        assert_eq!(read_failed(&dir)?, plan);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoint_roundtrip() -> Result<()> {
+        let dir = crate::test::test_dir("checkpoint");
+        std::fs::create_dir_all(&dir)?;
+        assert!(read_checkpoint(&dir)?.completed.is_empty());
+
+        let config = config::Config {
+            samples: 2,
+            ..Default::default()
+        };
+        let checkpoint = Checkpoint {
+            completed: build_plan(&config)[..1].to_vec(),
+            run_dirs: BTreeMap::from([(0, "sample-0000".into())]),
+        };
+        write_checkpoint(&dir, &checkpoint)?;
+        let read = read_checkpoint(&dir)?;
+        assert_eq!(read.completed, checkpoint.completed);
+        assert_eq!(read.run_dirs, checkpoint.run_dirs);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
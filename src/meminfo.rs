@@ -0,0 +1,143 @@
+use anyhow::Context;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub(crate) const DEFAULT_COUNTERS: &[&str] = &[
+    "MemFree",
+    "Cached",
+    "Dirty",
+    "Writeback",
+    "HugePages_Total",
+    "HugePages_Free",
+    "pgscan_kswapd",
+    "pgscan_direct",
+    "pgsteal_kswapd",
+    "pgsteal_direct",
+];
+
+/// Selected counters of `/proc/meminfo` and `/proc/vmstat`. The names of
+/// the two files do not overlap, so they share one namespace.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Snapshot(BTreeMap<String, u64>);
+
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct CounterDelta {
+    pub(crate) before: u64,
+    pub(crate) after: u64,
+    pub(crate) delta: i64,
+}
+
+/// `/proc/meminfo` lines look like `Dirty:   1234 kB`, values are kept in
+/// the unit of the file.
+fn parse_meminfo(data: &str) -> BTreeMap<String, u64> {
+    data.lines()
+        .filter_map(|l| l.split_once(':'))
+        .filter_map(|(k, v)| {
+            let value = v.split_whitespace().next()?.parse().ok()?;
+            Some((k.trim().to_string(), value))
+        })
+        .collect()
+}
+
+fn parse_vmstat(data: &str) -> BTreeMap<String, u64> {
+    data.lines()
+        .filter_map(|l| l.split_once(' '))
+        .filter_map(|(k, v)| Some((k.to_string(), v.trim().parse().ok()?)))
+        .collect()
+}
+
+impl Snapshot {
+    pub(crate) fn take(counters: &[String]) -> Result<Self> {
+        let meminfo =
+            std::fs::read_to_string("/proc/meminfo").context("Failed to read /proc/meminfo")?;
+        let vmstat =
+            std::fs::read_to_string("/proc/vmstat").context("Failed to read /proc/vmstat")?;
+        Ok(Self::from_parsed(
+            parse_meminfo(&meminfo)
+                .into_iter()
+                .chain(parse_vmstat(&vmstat))
+                .collect(),
+            counters,
+        ))
+    }
+
+    fn from_parsed(all: BTreeMap<String, u64>, counters: &[String]) -> Self {
+        Self(
+            all.into_iter()
+                .filter(|(k, _)| counters.contains(k))
+                .collect(),
+        )
+    }
+
+    pub(crate) fn get(&self, counter: &str) -> Option<u64> {
+        self.0.get(counter).copied()
+    }
+
+    pub(crate) fn delta(&self, after: &Snapshot) -> BTreeMap<String, CounterDelta> {
+        delta(&self.0, &after.0)
+    }
+}
+
+/// Counters present in both snapshots.
+pub(crate) fn delta(
+    before: &BTreeMap<String, u64>,
+    after: &BTreeMap<String, u64>,
+) -> BTreeMap<String, CounterDelta> {
+    before
+        .iter()
+        .filter_map(|(k, before)| {
+            let after = *after.get(k)?;
+            Some((
+                k.clone(),
+                CounterDelta {
+                    before: *before,
+                    after,
+                    delta: after as i64 - *before as i64,
+                },
+            ))
+        })
+        .collect()
+}
+
+pub(crate) fn write_delta(path: &Path, before: &Snapshot, after: &Snapshot) -> Result<()> {
+    let file = std::fs::File::create(path).with_context(|| format!("Failed to create {path:?}"))?;
+    serde_json::to_writer_pretty(file, &before.delta(after)).context("Failed to write meminfo")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_delta() {
+        let counters = vec!["Dirty".to_string(), "pgscan_direct".to_string()];
+        let parse = |meminfo: &str, vmstat: &str| {
+            Snapshot::from_parsed(
+                parse_meminfo(meminfo)
+                    .into_iter()
+                    .chain(parse_vmstat(vmstat))
+                    .collect(),
+                &counters,
+            )
+        };
+        let before = parse(
+            "MemFree:  100 kB\nDirty:     40 kB\nHugePages_Total:       0\n",
+            "nr_dirty 10\npgscan_direct 5\n",
+        );
+        let after = parse("MemFree:  90 kB\nDirty:     10 kB\n", "pgscan_direct 8\n");
+
+        let delta = before.delta(&after);
+        assert_eq!(delta.len(), 2);
+        assert_eq!(
+            delta["Dirty"],
+            CounterDelta {
+                before: 40,
+                after: 10,
+                delta: -30
+            }
+        );
+        assert_eq!(delta["pgscan_direct"].delta, 3);
+    }
+}
@@ -0,0 +1,271 @@
+use crate::command::CheckExitCode;
+use crate::command::Command;
+use crate::config::Config;
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Serialize;
+use std::io::Read;
+use std::path::Path;
+use std::process::Stdio;
+
+/// Options that make a kernel noticeably slower than a production build.
+const DEBUG_CONFIGS: &[&str] = &[
+    "CONFIG_KASAN",
+    "CONFIG_KCSAN",
+    "CONFIG_PROVE_LOCKING",
+    "CONFIG_DEBUG_PAGEALLOC",
+    "CONFIG_DEBUG_OBJECTS",
+    "CONFIG_DEBUG_KMEMLEAK",
+];
+
+#[derive(Serialize, Debug)]
+pub(crate) struct SystemInfo {
+    pub(crate) hostname: String,
+    pub(crate) kernel_release: String,
+    pub(crate) kernel_version: String,
+    pub(crate) machine: String,
+    pub(crate) distro: Option<String>,
+    /// `None` if the kernel config is not available.
+    pub(crate) debug_kernel: Option<bool>,
+}
+
+/// Everything about a batch that is not a result, written to
+/// `metadata.json` in the batch directory before the first config runs.
+#[derive(Serialize, Debug)]
+pub(crate) struct Metadata {
+    /// When the batch started, in UTC.
+    pub(crate) started_at: String,
+    pub(crate) system: SystemInfo,
+    /// What `fio --version` prints.
+    pub(crate) fio_version: String,
+    /// Tracers running next to the measured workloads, their overhead is
+    /// part of the results.
+    pub(crate) tracers: Vec<&'static str>,
+    /// Whether fio ran with gtod_reduce, which measures no latencies and
+    /// more IOPS than without.
+    pub(crate) gtod_reduce: bool,
+    /// Drive wear over the batch, with `capture_smart`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) smart: Option<crate::smart::SmartDelta>,
+    /// File in the batch directory holding the kernel config, with
+    /// `capture_kernel_config`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) kernel_config: Option<&'static str>,
+    /// With `capture_device_identity`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) device_identity: Option<crate::identity::DeviceIdentity>,
+    /// With all files, command line options and overrides applied.
+    pub(crate) config: Config,
+}
+
+impl Metadata {
+    pub(crate) fn collect(config: &Config) -> Result<Self> {
+        let tracers = [
+            ("perf", config.perf_events.is_some()),
+            ("turbostat", config.capture_power),
+            ("blktrace", config.capture_blktrace),
+        ];
+        Ok(Self {
+            started_at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            system: SystemInfo::collect()?,
+            fio_version: fio_version(config)?,
+            tracers: tracers
+                .into_iter()
+                .filter_map(|(name, enabled)| enabled.then_some(name))
+                .collect(),
+            gtod_reduce: !config.clat_percentiles(),
+            smart: None,
+            kernel_config: None,
+            device_identity: None,
+            config: config.redacted(),
+        })
+    }
+
+    /// Copy the kernel config into the batch directory. A missing config
+    /// is only a warning, not every kernel exposes it.
+    pub(crate) fn save_kernel_config(&mut self, batch_dir: &Path) -> Result<()> {
+        let config = match read_kernel_config(&self.system.kernel_release) {
+            Ok(config) => config,
+            Err(e) => {
+                log::warn!("Not saving kernel config: {e:?}");
+                return Ok(());
+            }
+        };
+        let path = batch_dir.join(KERNEL_CONFIG_FILE);
+        std::fs::write(&path, config).with_context(|| format!("Failed to write {path:?}"))?;
+        self.kernel_config = Some(KERNEL_CONFIG_FILE);
+        Ok(())
+    }
+
+    pub(crate) fn write(&self, batch_dir: &Path) -> Result<()> {
+        let path = batch_dir.join(FILE);
+        let file =
+            std::fs::File::create(&path).with_context(|| format!("Failed to create {path:?}"))?;
+        serde_json::to_writer_pretty(file, self).context("Failed to write metadata")
+    }
+}
+
+/// Also checks that fio can be run at all, and is no older than
+/// `min_fio_version`, before anything is set up.
+fn fio_version(config: &Config) -> Result<String> {
+    let fio = &config.fio;
+    let output = Command::new(fio)
+        .arg("--version")
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {fio:?}, is fio installed?"))?
+        .wait_with_output()?;
+    output
+        .status
+        .check_status()
+        .with_context(|| format!("{fio:?} --version failed"))?;
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if let Some(min) = &config.min_fio_version {
+        let too_old = match (parse_fio_version(&version), parse_fio_version(min)) {
+            (Some(found), Some(min)) => found < min,
+            (None, _) => return Err(anyhow!("Cannot parse the fio version {version:?}")),
+            (_, None) => return Err(anyhow!("Invalid min_fio_version {min:?}")),
+        };
+        if too_old {
+            return Err(anyhow!(
+                "{fio:?} is {version}, but min_fio_version is {min}"
+            ));
+        }
+    }
+    Ok(version)
+}
+
+/// The numeric parts of a version like `fio-3.36` or `3.36-12-gabcdef`.
+pub(crate) fn parse_fio_version(version: &str) -> Option<Vec<u32>> {
+    let version = version.trim();
+    let version = version.strip_prefix("fio-").unwrap_or(version);
+    let end = version
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(version.len());
+    version[..end]
+        .split('.')
+        .map(|part| part.parse().ok())
+        .collect()
+}
+
+const FILE: &str = "metadata.json";
+const KERNEL_CONFIG_FILE: &str = "kernel.config";
+
+/// The kernel a batch ran on, from its `metadata.json`.
+pub(crate) fn kernel_release(batch_dir: &Path) -> Result<String> {
+    let path = batch_dir.join(FILE);
+    let file = std::fs::File::open(&path).with_context(|| format!("Failed to open {path:?}"))?;
+    let metadata: serde_json::Value = serde_json::from_reader(std::io::BufReader::new(file))
+        .with_context(|| format!("Failed to parse {path:?}"))?;
+    metadata["system"]["kernel_release"]
+        .as_str()
+        .map(String::from)
+        .ok_or(anyhow!("No kernel_release in {path:?}"))
+}
+
+fn c_chars(chars: &[libc::c_char]) -> String {
+    let bytes = chars
+        .iter()
+        .take_while(|c| **c != 0)
+        .map(|c| *c as u8)
+        .collect::<Vec<_>>();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+impl SystemInfo {
+    pub(crate) fn collect() -> Result<Self> {
+        // SAFETY: utsname is plain old data and uname only writes into it.
+        let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+        if unsafe { libc::uname(&mut uts) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("uname failed");
+        }
+        let kernel_release = c_chars(&uts.release);
+
+        let distro = match std::fs::read_to_string("/etc/os-release") {
+            Ok(data) => parse_os_release(&data),
+            Err(e) => {
+                log::warn!("Failed to read /etc/os-release: {e}");
+                None
+            }
+        };
+
+        let debug_kernel = match read_kernel_config(&kernel_release) {
+            Ok(config) => Some(is_debug_config(&config)),
+            Err(e) => {
+                log::warn!("Kernel config not available: {e:?}");
+                None
+            }
+        };
+
+        Ok(Self {
+            hostname: c_chars(&uts.nodename),
+            kernel_release,
+            kernel_version: c_chars(&uts.version),
+            machine: c_chars(&uts.machine),
+            distro,
+            debug_kernel,
+        })
+    }
+}
+
+/// The `PRETTY_NAME` of an os-release file, falling back to `NAME`.
+fn parse_os_release(data: &str) -> Option<String> {
+    let value = |key: &str| {
+        data.lines()
+            .filter_map(|l| l.split_once('='))
+            .find(|(k, _)| k.trim() == key)
+            .map(|(_, v)| v.trim().trim_matches('"').to_string())
+    };
+    value("PRETTY_NAME").or_else(|| value("NAME"))
+}
+
+fn read_kernel_config(kernel_release: &str) -> Result<String> {
+    if let Ok(file) = std::fs::File::open("/proc/config.gz") {
+        let mut config = String::new();
+        libflate::gzip::Decoder::new(file)?.read_to_string(&mut config)?;
+        return Ok(config);
+    }
+    let path = format!("/boot/config-{kernel_release}");
+    std::fs::read_to_string(&path).map_err(|e| anyhow!("Failed to read {path}: {e}"))
+}
+
+fn is_debug_config(config: &str) -> bool {
+    config.lines().any(|l| {
+        l.split_once('=')
+            .is_some_and(|(k, v)| v == "y" && DEBUG_CONFIGS.contains(&k))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_os_release() {
+        assert_eq!(
+            parse_os_release("NAME=\"NixOS\"\nPRETTY_NAME=\"NixOS 24.05 (Uakari)\"\n").as_deref(),
+            Some("NixOS 24.05 (Uakari)")
+        );
+        assert_eq!(parse_os_release("NAME=Arch\n").as_deref(), Some("Arch"));
+        assert_eq!(parse_os_release(""), None);
+    }
+
+    #[test]
+    fn test_is_debug_config() {
+        assert!(is_debug_config("CONFIG_BLOCK=y\nCONFIG_KASAN=y\n"));
+        assert!(!is_debug_config(
+            "CONFIG_BLOCK=y\n# CONFIG_KASAN is not set\nCONFIG_DEBUG_KERNEL=y\n"
+        ));
+    }
+
+    #[test]
+    fn test_parse_fio_version() {
+        assert_eq!(parse_fio_version("fio-3.36\n"), Some(vec![3, 36]));
+        assert_eq!(parse_fio_version("fio-3.36-12-gabcdef"), Some(vec![3, 36]));
+        assert_eq!(parse_fio_version("3.9"), Some(vec![3, 9]));
+        assert_eq!(parse_fio_version("fio-"), None);
+        assert_eq!(parse_fio_version("unknown"), None);
+        assert!(parse_fio_version("fio-3.9") < parse_fio_version("3.30"));
+    }
+}
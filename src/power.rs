@@ -0,0 +1,75 @@
+use crate::command::BackgroundChild;
+use crate::command::CheckExitCode;
+use crate::command::Command;
+use anyhow::Context;
+use anyhow::Result;
+use std::path::Path;
+
+pub(crate) const BASELINE_FILE: &str = "power-baseline.txt";
+
+fn turbostat(output: &Path) -> Command {
+    let mut command = Command::new("turbostat");
+    command
+        .arg("--quiet")
+        .arg("--Summary")
+        .arg("--show")
+        .arg("PkgWatt")
+        .arg("--interval")
+        .arg("1")
+        .arg("--out")
+        .arg(output)
+        .own_process_group();
+    command
+}
+
+/// Sample package power once a second until stopped.
+pub(crate) fn start_turbostat(output: &Path) -> Result<BackgroundChild> {
+    // turbostat prints its last interval when interrupted
+    Ok(BackgroundChild::new(
+        turbostat(output).spawn()?,
+        libc::SIGINT,
+    ))
+}
+
+/// Package power of the otherwise idle machine, averaged over `secs`.
+pub(crate) fn measure_baseline(secs: u32, output: &Path) -> Result<f64> {
+    log::info!("Measuring idle power for {secs}s");
+    turbostat(output)
+        .arg("--num_iterations")
+        .arg(secs.to_string())
+        .spawn()?
+        .wait()?
+        .check_status()
+        .context("turbostat failed")?;
+    let watts = parse_file(output)?.with_context(|| format!("No power samples in {output:?}"))?;
+    log::info!("Idle power baseline: {watts:.1}W");
+    Ok(watts)
+}
+
+/// Mean of the `PkgWatt` samples, skipping the repeated headers.
+pub(crate) fn parse_turbostat(data: &str) -> Option<f64> {
+    let samples = data
+        .lines()
+        .filter_map(|l| l.trim().parse::<f64>().ok())
+        .collect::<Vec<_>>();
+    (!samples.is_empty()).then(|| samples.iter().sum::<f64>() / samples.len() as f64)
+}
+
+pub(crate) fn parse_file(path: &Path) -> Result<Option<f64>> {
+    let data = std::fs::read_to_string(path).with_context(|| format!("Failed to read {path:?}"))?;
+    Ok(parse_turbostat(&data))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_turbostat() {
+        assert_eq!(
+            parse_turbostat("PkgWatt\n10.50\nPkgWatt\n11.50\n"),
+            Some(11.0)
+        );
+        assert_eq!(parse_turbostat("PkgWatt\n"), None);
+    }
+}
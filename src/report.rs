@@ -0,0 +1,428 @@
+use crate::config::Config;
+use crate::results::Aggregate;
+use crate::results::BandwidthUnit;
+use anyhow::Result;
+use std::fmt::Write;
+
+const CHART_WIDTH: f64 = 640.0;
+const CHART_HEIGHT: f64 = 320.0;
+const MARGIN: f64 = 50.0;
+const COLORS: &[&str] = &[
+    "#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd", "#8c564b", "#e377c2", "#7f7f7f",
+];
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Render a self contained HTML page with a results table and one IOPS over
+/// queue depth chart per workload and block size.
+pub(crate) fn render_html(config: &Config, aggregates: &[Aggregate]) -> Result<String> {
+    let unit = BandwidthUnit::parse(&config.bw_unit)?;
+    let mut html = String::new();
+
+    writeln!(html, "<!DOCTYPE html>")?;
+    writeln!(
+        html,
+        "<html><head><meta charset=\"utf-8\"><title>fio-matrix report</title>"
+    )?;
+    writeln!(
+        html,
+        "<style>body{{font-family:sans-serif}}table{{border-collapse:collapse}}\
+         td,th{{border:1px solid #ccc;padding:2px 6px;text-align:right}}</style>"
+    )?;
+    writeln!(html, "</head><body>")?;
+    writeln!(
+        html,
+        "<h1>fio-matrix report: {}</h1>",
+        escape(&config.device)
+    )?;
+
+    let mut groups: Vec<(String, String)> = Vec::new();
+    for aggregate in aggregates {
+        let key = (
+            aggregate.entry.workload.to_string(),
+            aggregate.entry.block_size.clone(),
+        );
+        if !groups.contains(&key) {
+            groups.push(key);
+        }
+    }
+
+    for (workload, block_size) in &groups {
+        let group = aggregates
+            .iter()
+            .filter(|a| a.entry.workload.rw() == workload && &a.entry.block_size == block_size)
+            .collect::<Vec<_>>();
+        writeln!(
+            html,
+            "<h2>{} bs {}</h2>",
+            escape(workload),
+            escape(block_size)
+        )?;
+        render_chart(&mut html, &group)?;
+    }
+
+    writeln!(html, "<h2>Results</h2><table>")?;
+    writeln!(
+        html,
+        "<tr><th>workload</th><th>bs</th><th>jobs</th><th>qd</th><th>scheduler</th>\
+         <th>samples</th><th>IOPS</th><th>IOPS stddev</th><th>job stddev</th><th>bw {}</th><th>p99 us</th></tr>",
+        escape(&unit.label())
+    )?;
+    for a in aggregates {
+        writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>\
+             <td>{:.0}</td><td>{:.0}</td><td>{:.0}</td><td>{:.2}</td><td>{}</td></tr>",
+            escape(a.entry.workload.rw()),
+            escape(&a.entry.block_size),
+            a.entry.jobcount,
+            a.entry.queue_depth,
+            escape(&a.entry.scheduler),
+            a.samples,
+            a.iops_mean,
+            a.iops_stddev,
+            a.job_iops_stddev_mean,
+            unit.convert(a.bw_bytes_mean as u64),
+            a.p99_ns_mean
+                .map(|p| format!("{:.1}", p / 1000.0))
+                .unwrap_or_default(),
+        )?;
+    }
+    writeln!(html, "</table></body></html>")?;
+
+    Ok(html)
+}
+
+/// One CSV per workload, block size, jobcount, scheduler, rate and think
+/// time, rows ordered by queue depth, for throughput over latency plots.
+/// Returns file names with contents.
+pub(crate) fn render_curves(
+    config: &Config,
+    aggregates: &[Aggregate],
+) -> Result<Vec<(String, String)>> {
+    let unit = BandwidthUnit::parse(&config.bw_unit)?;
+    let mut curves: Vec<(String, Vec<&Aggregate>)> = Vec::new();
+    for a in aggregates {
+        let rate = a
+            .entry
+            .rate
+            .as_ref()
+            .map(|r| format!("-rate{r}"))
+            .unwrap_or_default();
+        let thinktime = a
+            .entry
+            .thinktime
+            .as_ref()
+            .map(|t| format!("-tt{t}"))
+            .unwrap_or_default();
+        let name = format!(
+            "{}-bs{}-jobs{}-{}{rate}{thinktime}.csv",
+            a.entry.workload, a.entry.block_size, a.entry.jobcount, a.entry.scheduler
+        );
+        match curves.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, curve)) => curve.push(a),
+            None => curves.push((name, vec![a])),
+        }
+    }
+
+    let us = |ns: Option<f64>| {
+        ns.map(|ns| format!("{:.1}", ns / 1000.0))
+            .unwrap_or_default()
+    };
+    curves
+        .into_iter()
+        .map(|(name, mut curve)| {
+            curve.sort_by_key(|a| a.entry.queue_depth);
+            let mut csv = format!("qd,iops,bw_{},p50_us,p99_us,labels\n", unit.label());
+            for a in curve {
+                writeln!(
+                    csv,
+                    "{},{:.0},{:.2},{},{},{}",
+                    a.entry.queue_depth,
+                    a.iops_mean,
+                    unit.convert(a.bw_bytes_mean as u64),
+                    us(a.p50_ns_mean),
+                    us(a.p99_ns_mean),
+                    a.entry.labels.join(";")
+                )?;
+            }
+            Ok((name, csv))
+        })
+        .collect()
+}
+
+fn escape_label(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Gauges per matrix entry in the Prometheus text exposition format.
+/// Latencies are in seconds and bandwidth in bytes per second, as
+/// Prometheus expects base units.
+pub(crate) fn render_prometheus(config: &Config, aggregates: &[Aggregate]) -> Result<String> {
+    type Value = fn(&Aggregate) -> Option<f64>;
+    let metrics: &[(&str, &str, Value)] = &[
+        ("fio_iops", "Mean IOPS over samples", |a| Some(a.iops_mean)),
+        (
+            "fio_iops_stddev",
+            "Standard deviation of IOPS over samples",
+            |a| Some(a.iops_stddev),
+        ),
+        (
+            "fio_bandwidth_bytes_per_second",
+            "Mean bandwidth over samples",
+            |a| Some(a.bw_bytes_mean),
+        ),
+        ("fio_clat_p50_seconds", "Mean p50 completion latency", |a| {
+            a.p50_ns_mean.map(|ns| ns / 1e9)
+        }),
+        ("fio_clat_p99_seconds", "Mean p99 completion latency", |a| {
+            a.p99_ns_mean.map(|ns| ns / 1e9)
+        }),
+        ("fio_power_watts", "Mean package power", |a| {
+            a.power_watts_mean
+        }),
+    ];
+
+    let labels = |a: &Aggregate| {
+        let mut labels = vec![
+            ("device", config.device.clone()),
+            ("workload", a.entry.workload.to_string()),
+            ("block_size", a.entry.block_size.clone()),
+            ("jobs", a.entry.jobcount.to_string()),
+            ("queue_depth", a.entry.queue_depth.to_string()),
+            ("scheduler", a.entry.scheduler.clone()),
+        ];
+        if let Some(rate) = &a.entry.rate {
+            labels.push(("rate", rate.to_string()));
+        }
+        if let Some(thinktime) = &a.entry.thinktime {
+            labels.push(("thinktime", thinktime.clone()));
+        }
+        labels
+            .iter()
+            .map(|(k, v)| format!("{k}=\"{}\"", escape_label(v)))
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+
+    let mut text = String::new();
+    for (name, help, value) in metrics {
+        let samples = aggregates
+            .iter()
+            .filter_map(|a| Some((labels(a), value(a)?)))
+            .collect::<Vec<_>>();
+        if samples.is_empty() {
+            continue;
+        }
+        writeln!(text, "# HELP {name} {help}")?;
+        writeln!(text, "# TYPE {name} gauge")?;
+        for (labels, value) in samples {
+            writeln!(text, "{name}{{{labels}}} {value}")?;
+        }
+    }
+    Ok(text)
+}
+
+/// Line chart with one series per jobcount and scheduler. Queue depths are
+/// spaced evenly since they are usually powers of two.
+fn render_chart(html: &mut String, group: &[&Aggregate]) -> Result<()> {
+    let mut queue_depths = group
+        .iter()
+        .map(|a| a.entry.queue_depth)
+        .collect::<Vec<_>>();
+    queue_depths.sort_unstable();
+    queue_depths.dedup();
+
+    let mut series: Vec<(u32, &str)> = Vec::new();
+    for a in group {
+        let key = (a.entry.jobcount, a.entry.scheduler.as_str());
+        if !series.contains(&key) {
+            series.push(key);
+        }
+    }
+
+    let max_iops = group
+        .iter()
+        .map(|a| a.iops_mean)
+        .fold(0.0, f64::max)
+        .max(1.0);
+    let plot_width = CHART_WIDTH - 2.0 * MARGIN;
+    let plot_height = CHART_HEIGHT - 2.0 * MARGIN;
+    let x = |qd: u32| {
+        let i = queue_depths.iter().position(|q| *q == qd).unwrap_or(0);
+        let steps = (queue_depths.len().max(2) - 1) as f64;
+        MARGIN + plot_width * i as f64 / steps
+    };
+    let y = |iops: f64| CHART_HEIGHT - MARGIN - plot_height * iops / max_iops;
+
+    writeln!(
+        html,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{CHART_WIDTH}\" height=\"{CHART_HEIGHT}\">"
+    )?;
+    writeln!(
+        html,
+        "<line x1=\"{MARGIN}\" y1=\"{b}\" x2=\"{r}\" y2=\"{b}\" stroke=\"black\"/>\
+         <line x1=\"{MARGIN}\" y1=\"{MARGIN}\" x2=\"{MARGIN}\" y2=\"{b}\" stroke=\"black\"/>",
+        b = CHART_HEIGHT - MARGIN,
+        r = CHART_WIDTH - MARGIN,
+    )?;
+    for qd in &queue_depths {
+        writeln!(
+            html,
+            "<text x=\"{:.1}\" y=\"{:.1}\" font-size=\"10\" text-anchor=\"middle\">{qd}</text>",
+            x(*qd),
+            CHART_HEIGHT - MARGIN + 14.0
+        )?;
+    }
+    writeln!(
+        html,
+        "<text x=\"{:.1}\" y=\"{:.1}\" font-size=\"10\" text-anchor=\"middle\">queue depth</text>\
+         <text x=\"4\" y=\"{:.1}\" font-size=\"10\">{:.0} IOPS</text>",
+        CHART_WIDTH / 2.0,
+        CHART_HEIGHT - 8.0,
+        MARGIN - 6.0,
+        max_iops
+    )?;
+
+    for (i, (jobcount, scheduler)) in series.iter().enumerate() {
+        let color = COLORS[i % COLORS.len()];
+        let mut points = group
+            .iter()
+            .filter(|a| a.entry.jobcount == *jobcount && a.entry.scheduler == *scheduler)
+            .map(|a| (a.entry.queue_depth, a.iops_mean))
+            .collect::<Vec<_>>();
+        points.sort_by_key(|(qd, _)| *qd);
+        let polyline = points
+            .iter()
+            .map(|(qd, iops)| format!("{:.1},{:.1}", x(*qd), y(*iops)))
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(
+            html,
+            "<polyline points=\"{polyline}\" fill=\"none\" stroke=\"{color}\" stroke-width=\"2\"/>"
+        )?;
+        writeln!(
+            html,
+            "<text x=\"{:.1}\" y=\"{:.1}\" font-size=\"10\" fill=\"{color}\">jobs {jobcount} {}</text>",
+            CHART_WIDTH - MARGIN - 96.0,
+            MARGIN + 12.0 * i as f64,
+            escape(scheduler)
+        )?;
+    }
+    writeln!(html, "</svg>")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::matrix::MatrixEntry;
+
+    #[test]
+    fn test_render_curves() -> Result<()> {
+        let aggregate = |jobcount: u32, queue_depth: u32| Aggregate {
+            entry: MatrixEntry {
+                block_size: "4k".into(),
+                jobcount,
+                workload: "randread".into(),
+                queue_depth,
+                scheduler: "none".into(),
+                rate: None,
+                thinktime: None,
+                labels: Vec::new(),
+                device: None,
+            },
+            samples: 1,
+            iops_mean: 1000.0 * queue_depth as f64,
+            iops_stddev: 0.0,
+            job_iops_stddev_mean: 0.0,
+            bw_bytes_mean: 1048576.0,
+            p50_ns_mean: Some(1500.0),
+            p99_ns_mean: None,
+            power_watts_mean: None,
+            net_power_watts_mean: None,
+            rate_met: None,
+            iops_pct_of_max: None,
+            bw_pct_of_max: None,
+            latency_degrading: None,
+            histogram_percentiles_ns: Vec::new(),
+            harness_ceiling_iops: None,
+            latency_breakdown: None,
+            spread: Default::default(),
+        };
+        let config = Config {
+            bw_unit: "MiB".into(),
+            ..Default::default()
+        };
+        let curves = render_curves(
+            &config,
+            &[aggregate(1, 8), aggregate(2, 1), aggregate(1, 1)],
+        )?;
+
+        assert_eq!(curves.len(), 2);
+        assert_eq!(curves[0].0, "randread-bs4k-jobs1-none.csv");
+        assert_eq!(
+            curves[0].1,
+            "qd,iops,bw_MiB/s,p50_us,p99_us,labels\n1,1000,1.00,1.5,,\n8,8000,1.00,1.5,,\n"
+        );
+        assert_eq!(curves[1].0, "randread-bs4k-jobs2-none.csv");
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_prometheus() -> Result<()> {
+        let aggregate = Aggregate {
+            entry: MatrixEntry {
+                block_size: "4k".into(),
+                jobcount: 1,
+                workload: "randread".into(),
+                queue_depth: 2,
+                scheduler: "none".into(),
+                rate: None,
+                thinktime: None,
+                labels: Vec::new(),
+                device: None,
+            },
+            samples: 1,
+            iops_mean: 1000.0,
+            iops_stddev: 0.0,
+            job_iops_stddev_mean: 0.0,
+            bw_bytes_mean: 4096000.0,
+            p50_ns_mean: None,
+            p99_ns_mean: Some(12500.0),
+            power_watts_mean: None,
+            net_power_watts_mean: None,
+            rate_met: None,
+            iops_pct_of_max: None,
+            bw_pct_of_max: None,
+            latency_degrading: None,
+            histogram_percentiles_ns: Vec::new(),
+            harness_ceiling_iops: None,
+            latency_breakdown: None,
+            spread: Default::default(),
+        };
+        let config = Config {
+            device: "nvme\"0\\n1".into(),
+            ..Default::default()
+        };
+        let text = render_prometheus(&config, &[aggregate])?;
+        let labels = r#"device="nvme\"0\\n1",workload="randread",block_size="4k",jobs="1",queue_depth="2",scheduler="none""#;
+        assert!(
+            text.contains(&format!("fio_iops{{{labels}}} 1000\n")),
+            "{text}"
+        );
+        assert!(text.contains(&format!("fio_clat_p99_seconds{{{labels}}} 0.0000125\n")));
+        assert!(text.contains("# TYPE fio_iops gauge\n"));
+        assert!(!text.contains("fio_clat_p50_seconds"));
+        assert!(!text.contains("fio_power_watts"));
+        Ok(())
+    }
+}
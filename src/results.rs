@@ -0,0 +1,2246 @@
+use crate::config::Config;
+use crate::config::Thresholds;
+use crate::config::Workload;
+use crate::matrix::MatrixEntry;
+use crate::matrix::Rate;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct FioOutput {
+    /// Named `client_stats` in the output of `fio --client`.
+    #[serde(alias = "client_stats")]
+    pub(crate) jobs: Vec<FioJob>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct FioJob {
+    pub(crate) jobname: String,
+
+    #[serde(default)]
+    pub(crate) read: FioStats,
+
+    #[serde(default)]
+    pub(crate) write: FioStats,
+
+    /// Queue depth fio settled on when running with `--latency_target`.
+    #[serde(default)]
+    pub(crate) latency_depth: Option<u32>,
+
+    #[serde(default)]
+    pub(crate) latency_target: Option<u64>,
+
+    #[serde(default)]
+    pub(crate) latency_percentile: Option<f64>,
+
+    #[serde(default)]
+    pub(crate) latency_window: Option<u64>,
+
+    /// First error the job hit, e.g. a verify failure, zero if none.
+    #[serde(default)]
+    pub(crate) error: i32,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct FioStats {
+    #[serde(default)]
+    pub(crate) iops: f64,
+
+    #[serde(default)]
+    pub(crate) bw_bytes: u64,
+
+    /// Submission latency. Like the other latencies, zero with
+    /// gtod_reduce.
+    #[serde(default)]
+    pub(crate) slat_ns: FioLatency,
+
+    #[serde(default)]
+    pub(crate) clat_ns: FioLatency,
+
+    /// Submission plus completion latency.
+    #[serde(default)]
+    pub(crate) lat_ns: FioLatency,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct FioLatency {
+    #[serde(default)]
+    pub(crate) mean: f64,
+
+    /// Keyed by fio's formatting of the percentile, e.g. `"99.000000"`.
+    #[serde(default)]
+    pub(crate) percentile: BTreeMap<String, f64>,
+}
+
+impl FioLatency {
+    pub(crate) fn percentile(&self, p: f64) -> Option<f64> {
+        self.percentile
+            .iter()
+            .find(|(k, _)| k.parse::<f64>().is_ok_and(|v| (v - p).abs() < 1e-9))
+            .map(|(_, v)| *v)
+    }
+}
+
+impl FioOutput {
+    /// IOPS summed over all jobs and directions.
+    pub(crate) fn total_iops(&self) -> f64 {
+        self.jobs.iter().map(|j| j.read.iops + j.write.iops).sum()
+    }
+
+    /// IOPS of each job, one entry per job unless fio ran with
+    /// `--group_reporting`.
+    pub(crate) fn job_iops(&self) -> Vec<f64> {
+        self.jobs
+            .iter()
+            .map(|j| j.read.iops + j.write.iops)
+            .collect()
+    }
+
+    pub(crate) fn total_bw_bytes(&self) -> u64 {
+        self.jobs
+            .iter()
+            .map(|j| j.read.bw_bytes + j.write.bw_bytes)
+            .sum()
+    }
+
+    /// Number of jobs that reported an error.
+    pub(crate) fn total_errors(&self) -> usize {
+        self.jobs.iter().filter(|j| j.error != 0).count()
+    }
+
+    /// Split the output of a phased workload by phase, in run order.
+    pub(crate) fn into_phases(self) -> Vec<(String, FioOutput)> {
+        let mut phases: Vec<(String, FioOutput)> = Vec::new();
+        for job in self.jobs {
+            match phases.iter_mut().find(|(name, _)| *name == job.jobname) {
+                Some((_, output)) => output.jobs.push(job),
+                None => phases.push((job.jobname.clone(), FioOutput { jobs: vec![job] })),
+            }
+        }
+        phases
+    }
+
+    /// Mean of one latency over all jobs and directions weighted by their
+    /// IOPS, if fio measured it.
+    pub(crate) fn mean_latency(&self, latency: fn(&FioStats) -> &FioLatency) -> Option<f64> {
+        let (sum, iops) = self
+            .jobs
+            .iter()
+            .flat_map(|j| [&j.read, &j.write])
+            .filter(|s| s.iops > 0.0 && latency(s).mean > 0.0)
+            .fold((0.0, 0.0), |(sum, iops), s| {
+                (sum + latency(s).mean * s.iops, iops + s.iops)
+            });
+        (iops > 0.0).then(|| sum / iops)
+    }
+
+    /// The worst completion latency percentile over all jobs and directions.
+    pub(crate) fn max_clat_percentile(&self, p: f64) -> Option<f64> {
+        self.jobs
+            .iter()
+            .flat_map(|j| [j.read.clat_ns.percentile(p), j.write.clat_ns.percentile(p)])
+            .flatten()
+            .reduce(f64::max)
+    }
+}
+
+/// The completion latency histograms of a json+ output, parsed apart from
+/// [`FioOutput`] as they are large and only needed for
+/// `histogram_percentiles`.
+#[derive(Deserialize, Debug)]
+pub(crate) struct HistogramOutput {
+    #[serde(alias = "client_stats")]
+    jobs: Vec<HistogramJob>,
+}
+
+#[derive(Deserialize, Debug)]
+struct HistogramJob {
+    jobname: String,
+
+    #[serde(default)]
+    read: HistogramStats,
+
+    #[serde(default)]
+    write: HistogramStats,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct HistogramStats {
+    #[serde(default)]
+    clat_ns: HistogramBins,
+}
+
+/// Completions per bin, keyed by the latency in ns the bin stands for.
+#[derive(Deserialize, Debug, Default)]
+struct HistogramBins {
+    #[serde(default)]
+    bins: BTreeMap<String, u64>,
+}
+
+impl HistogramOutput {
+    /// Completion latency percentile `p` in ns over all directions of the
+    /// jobs named `jobname`, or all jobs. Exact to the bin width, which
+    /// stays within a few percent of the latency.
+    pub(crate) fn percentile(&self, jobname: Option<&str>, p: f64) -> Result<Option<f64>> {
+        let mut bins = BTreeMap::<u64, u64>::new();
+        for job in &self.jobs {
+            if jobname.is_some_and(|name| name != job.jobname) {
+                continue;
+            }
+            for (latency, count) in job.read.clat_ns.bins.iter().chain(&job.write.clat_ns.bins) {
+                let latency = latency
+                    .parse::<u64>()
+                    .with_context(|| format!("Invalid histogram bin {latency:?}"))?;
+                *bins.entry(latency).or_default() += count;
+            }
+        }
+        let total = bins.values().sum::<u64>();
+        if total == 0 {
+            return Ok(None);
+        }
+        let target = ((total as f64 * p / 100.0).ceil() as u64).max(1);
+        let mut seen = 0;
+        Ok(bins.into_iter().find_map(|(latency, count)| {
+            seen += count;
+            (seen >= target).then_some(latency as f64)
+        }))
+    }
+}
+
+pub(crate) fn parse_histogram_file(path: &Path) -> Result<HistogramOutput> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+    serde_json::from_reader(std::io::BufReader::new(file))
+        .with_context(|| format!("Failed to parse histograms of {path:?}"))
+}
+
+/// fio job name of the phase at `index` of a phased workload.
+pub(crate) fn phase_job_name(index: usize, rw: &str) -> String {
+    format!("{}-{rw}", index + 1)
+}
+
+/// Unit used when reporting bandwidth, e.g. `MiB`, `MB` or `Gbit`. Values
+/// are per second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct BandwidthUnit(byte_unit::Unit);
+
+impl BandwidthUnit {
+    pub(crate) fn parse(unit: &str) -> Result<Self> {
+        byte_unit::Unit::parse_str(unit, false, true)
+            .map(Self)
+            .map_err(|e| anyhow::anyhow!("Invalid bandwidth unit {unit:?}: {e}"))
+    }
+
+    pub(crate) fn convert(&self, bytes_per_sec: u64) -> f64 {
+        (bytes_per_sec as f64 * 8.0) / self.0.as_bits_u128() as f64
+    }
+
+    pub(crate) fn label(&self) -> String {
+        format!("{}/s", self.0)
+    }
+}
+
+/// Parse a fio json(+) output file. The file is streamed rather than read
+/// into memory, and everything not in [`FioOutput`], such as the json+
+/// latency histograms, is skipped without being materialized.
+pub(crate) fn parse_file(path: &Path) -> Result<FioOutput> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+    serde_json::from_reader(std::io::BufReader::new(file))
+        .with_context(|| format!("Failed to parse {path:?}"))
+}
+
+/// Suffix of the file with fio's main output for an entry.
+pub(crate) fn output_suffix(config: &Config) -> &'static str {
+    match config.terse_results {
+        true => ".terse",
+        false => ".json",
+    }
+}
+
+/// Parse fio output in the format `config` runs fio with.
+pub(crate) fn parse_output(config: &Config, path: &Path) -> Result<FioOutput> {
+    match config.terse_results {
+        true => {
+            let data = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {path:?}"))?;
+            parse_terse(&data).with_context(|| format!("Failed to parse {path:?}"))
+        }
+        false => parse_file(path),
+    }
+}
+
+/// Fields of one direction in terse version 3, relative to its start.
+mod terse {
+    pub(super) const BW_KIB: usize = 1;
+    pub(super) const IOPS: usize = 2;
+    pub(super) const SLAT_MEAN: usize = 6;
+    pub(super) const CLAT_MEAN: usize = 10;
+    pub(super) const CLAT_PERCENTILES: std::ops::Range<usize> = 12..32;
+    pub(super) const LAT_MEAN: usize = 34;
+    pub(super) const LEN: usize = 41;
+    pub(super) const READ: usize = 5;
+    pub(super) const WRITE: usize = READ + LEN;
+}
+
+/// Parse `--output-format=terse --terse-version=3` output, one line per
+/// job. Terse version 3 needs fio 2.0 or later; from 3.x the default
+/// terse version is 3 as well. Latencies in this format are in
+/// microseconds and bandwidth in KiB/s.
+pub(crate) fn parse_terse(data: &str) -> Result<FioOutput> {
+    let stats = |fields: &[&str], start: usize| -> Result<FioStats> {
+        let field = |i: usize| {
+            fields
+                .get(start + i)
+                .copied()
+                .ok_or_else(|| anyhow::anyhow!("Missing terse field {}", start + i))
+        };
+        let mut percentile = BTreeMap::new();
+        for i in terse::CLAT_PERCENTILES {
+            // `99.000000%=1234`, empty or `0%=0` for unused slots
+            if let Some((p, v)) = field(i)?.split_once("%=") {
+                if p.parse::<f64>()? != 0.0 {
+                    percentile.insert(p.to_string(), v.parse::<f64>()? * 1000.0);
+                }
+            }
+        }
+        let mean = |i: usize| -> Result<f64> { Ok(field(i)?.parse::<f64>()? * 1000.0) };
+        Ok(FioStats {
+            iops: field(terse::IOPS)?.parse()?,
+            bw_bytes: field(terse::BW_KIB)?.parse::<u64>()? * 1024,
+            slat_ns: FioLatency {
+                mean: mean(terse::SLAT_MEAN)?,
+                ..Default::default()
+            },
+            clat_ns: FioLatency {
+                mean: mean(terse::CLAT_MEAN)?,
+                percentile,
+            },
+            lat_ns: FioLatency {
+                mean: mean(terse::LAT_MEAN)?,
+                ..Default::default()
+            },
+        })
+    };
+
+    let jobs = data
+        .lines()
+        .filter(|l| l.starts_with("3;"))
+        .map(|line| {
+            let fields = line.split(';').collect::<Vec<_>>();
+            Ok(FioJob {
+                jobname: fields.get(2).copied().unwrap_or_default().to_string(),
+                error: fields.get(4).copied().unwrap_or("0").parse()?,
+                read: stats(&fields, terse::READ)?,
+                write: stats(&fields, terse::WRITE)?,
+                ..Default::default()
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(FioOutput { jobs })
+}
+
+/// Where the outputs of `entry` are in `run_dir`. With `parallel_devices`
+/// each device has a directory of its own in there.
+pub(crate) fn entry_dir(run_dir: &Path, entry: &MatrixEntry) -> PathBuf {
+    match &entry.device {
+        Some(device) if run_dir.join(device).is_dir() => run_dir.join(device),
+        _ => run_dir.to_path_buf(),
+    }
+}
+
+/// The outputs of every config of the matrix found in `run_dir`. Outputs
+/// that do not parse are skipped with a warning.
+pub(crate) fn parse_run_dir(config: &Config, run_dir: &Path) -> Vec<(MatrixEntry, FioOutput)> {
+    let mut outputs = Vec::new();
+    for entry in crate::matrix::build_matrix(config) {
+        let run_output_id = crate::matrix::run_output_id(config, &entry);
+        let path =
+            entry_dir(run_dir, &entry).join(format!("{run_output_id}{}", output_suffix(config)));
+        if !path.exists() {
+            continue;
+        }
+        match parse_output(config, &path) {
+            Ok(output) => outputs.push((entry, output)),
+            Err(e) => log::warn!("Skipping {path:?}: {e:?}"),
+        }
+    }
+    outputs
+}
+
+/// One line per config of a finished sample.
+pub(crate) fn log_sample_summary(config: &Config, sample: u32, run_dir: &Path) -> Result<()> {
+    let unit = BandwidthUnit::parse(&config.bw_unit)?;
+    for (entry, output) in parse_run_dir(config, run_dir) {
+        log::info!(
+            "Sample #{sample} {entry}: iops {:.0} bw {:.2} {}{}",
+            output.total_iops(),
+            unit.convert(output.total_bw_bytes()),
+            unit.label(),
+            clat_fields(&[
+                ("mean", output.mean_latency(|s| &s.clat_ns)),
+                ("p99", output.max_clat_percentile(99.0)),
+                ("p99.9", output.max_clat_percentile(99.9)),
+            ]),
+        );
+    }
+    Ok(())
+}
+
+/// The completion latencies fio reported, in us, leaving out those it did
+/// not. Empty if it reported none.
+fn clat_fields(latencies: &[(&str, Option<f64>)]) -> String {
+    let fields = latencies
+        .iter()
+        .filter_map(|(name, ns)| Some(format!(" {name} {:.1}us", (*ns)? / 1000.0)))
+        .collect::<String>();
+    match fields.is_empty() {
+        true => fields,
+        false => format!(" clat{fields}"),
+    }
+}
+
+/// IOPS, bandwidth and the median and p99 completion latency of `output`
+/// on one line.
+pub(crate) fn brief_summary(output: &FioOutput, unit: BandwidthUnit) -> String {
+    format!(
+        "iops {:.0} bw {:.2} {}{}",
+        output.total_iops(),
+        unit.convert(output.total_bw_bytes()),
+        unit.label(),
+        clat_fields(&[
+            ("p50", output.max_clat_percentile(50.0)),
+            ("p99", output.max_clat_percentile(99.0)),
+        ]),
+    )
+}
+
+pub(crate) fn log_summary(name: &str, output: &FioOutput, unit: BandwidthUnit) {
+    for job in &output.jobs {
+        let (read, write) = (job.read.bw_bytes, job.write.bw_bytes);
+        log::info!(
+            "Result {name}: iops {:.0} read {:.2} {label} write {:.2} {label} total {:.2} {label}",
+            job.read.iops + job.write.iops,
+            unit.convert(read),
+            unit.convert(write),
+            unit.convert(read + write),
+            label = unit.label(),
+        );
+    }
+
+    let job_iops = output.job_iops();
+    if job_iops.len() > 1 {
+        let min = job_iops.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = job_iops.iter().copied().fold(0.0, f64::max);
+        log::info!(
+            "Job spread {name}: {} jobs iops min {min:.0} max {max:.0} stddev {:.0}",
+            job_iops.len(),
+            stddev(&job_iops),
+        );
+    }
+}
+
+pub(crate) fn log_latency_target(output: &FioOutput) {
+    for job in &output.jobs {
+        log::info!(
+            "Latency target result for {}: depth {} at {:.0} IOPS (target {}us p{} window {}us)",
+            job.jobname,
+            job.latency_depth
+                .map(|d| d.to_string())
+                .unwrap_or_else(|| "unknown".into()),
+            job.read.iops + job.write.iops,
+            job.latency_target.unwrap_or_default(),
+            job.latency_percentile.unwrap_or_default(),
+            job.latency_window.unwrap_or_default(),
+        );
+    }
+}
+
+/// A parsed result of one entry in one sample.
+#[derive(Debug)]
+pub(crate) struct SampleResult {
+    pub(crate) entry: MatrixEntry,
+    pub(crate) output: FioOutput,
+    /// Mean package power while the workload ran.
+    pub(crate) power_watts: Option<f64>,
+    /// Whether the p99 rose over the run, with `capture_latency_trend`.
+    pub(crate) latency_degrading: Option<bool>,
+    /// `histogram_percentiles` with their latency in ns, if the output
+    /// has histograms.
+    pub(crate) histogram_percentiles_ns: Vec<(f64, f64)>,
+}
+
+/// The results of a batch directory, matched up with the config that
+/// produced them.
+pub(crate) struct Batch {
+    pub(crate) config: Config,
+    pub(crate) samples: Vec<SampleResult>,
+    pub(crate) power_baseline_watts: Option<f64>,
+    pub(crate) overhead: Vec<OverheadCeiling>,
+}
+
+/// Outputs of the smoke test, not part of the results.
+pub(crate) const SMOKE_DIR: &str = "smoke";
+
+/// Outputs of the null engine runs of `measure_overhead`.
+pub(crate) const OVERHEAD_DIR: &str = "overhead";
+
+/// The ceilings measured by `measure_overhead`, in the batch directory.
+pub(crate) const OVERHEAD_FILE: &str = "overhead.json";
+
+/// IOPS of fio's null engine at one job geometry, which no device can
+/// beat on the same machine.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct OverheadCeiling {
+    pub(crate) jobcount: u32,
+    pub(crate) queue_depth: u32,
+    pub(crate) iops: f64,
+}
+
+pub(crate) fn write_overhead(batch_dir: &Path, ceilings: &[OverheadCeiling]) -> Result<()> {
+    let path = batch_dir.join(OVERHEAD_FILE);
+    let file =
+        std::fs::File::create(&path).with_context(|| format!("Failed to create {path:?}"))?;
+    serde_json::to_writer_pretty(file, ceilings).context("Failed to write overhead ceilings")
+}
+
+fn read_overhead(batch_dir: &Path) -> Result<Vec<OverheadCeiling>> {
+    let path = batch_dir.join(OVERHEAD_FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let data =
+        std::fs::read_to_string(&path).with_context(|| format!("Failed to read {path:?}"))?;
+    serde_json::from_str(&data).with_context(|| format!("Failed to parse {path:?}"))
+}
+
+pub(crate) const NDJSON_FILE: &str = "results.ndjson";
+
+/// Run directories of a batch, one per sample. They sort chronologically.
+fn run_dirs(batch_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut run_dirs = std::fs::read_dir(batch_dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_dir() && !p.ends_with(SMOKE_DIR) && !p.ends_with(OVERHEAD_DIR))
+        .collect::<Vec<_>>();
+    run_dirs.sort();
+    Ok(run_dirs)
+}
+
+/// One line of `results.ndjson`. The output is fio's json as is, or the
+/// text of a terse output.
+#[derive(Serialize, Deserialize)]
+struct NdjsonRecord {
+    sample: usize,
+    /// Path of the directory holding the other outputs of the sample,
+    /// relative to the batch directory.
+    run_dir: String,
+    entry: MatrixEntry,
+    output: serde_json::Value,
+}
+
+/// Collect the fio outputs of a batch into [`NDJSON_FILE`], optionally
+/// removing them. Returns the number of outputs collected.
+pub(crate) fn write_ndjson(config: &Config, batch_dir: &Path, remove_raw: bool) -> Result<usize> {
+    use std::io::Write;
+    let matrix = crate::matrix::build_matrix(config);
+    let path = batch_dir.join(NDJSON_FILE);
+    let file =
+        std::fs::File::create(&path).with_context(|| format!("Failed to create {path:?}"))?;
+    let mut writer = std::io::BufWriter::new(file);
+    let mut collected = Vec::new();
+    for (sample, run_dir) in run_dirs(batch_dir)?.iter().enumerate() {
+        for entry in &matrix {
+            let run_output_id = crate::matrix::run_output_id(config, entry);
+            let entry_dir = entry_dir(run_dir, entry);
+            let output_path = entry_dir.join(format!("{run_output_id}{}", output_suffix(config)));
+            if !output_path.exists() {
+                continue;
+            }
+            let data = std::fs::read_to_string(&output_path)
+                .with_context(|| format!("Failed to read {output_path:?}"))?;
+            let output = match config.terse_results {
+                true => serde_json::Value::String(data),
+                false => serde_json::from_str(&data)
+                    .with_context(|| format!("Failed to parse {output_path:?}"))?,
+            };
+            let record = NdjsonRecord {
+                sample,
+                run_dir: entry_dir
+                    .strip_prefix(batch_dir)
+                    .ok()
+                    .and_then(|n| n.to_str())
+                    .ok_or(anyhow::anyhow!("Invalid run dir {entry_dir:?}"))?
+                    .to_string(),
+                entry: entry.clone(),
+                output,
+            };
+            serde_json::to_writer(&mut writer, &record)?;
+            writeln!(writer)?;
+            collected.push(output_path);
+        }
+    }
+    writer.flush()?;
+
+    // Only once the whole file is written
+    if remove_raw {
+        for path in &collected {
+            std::fs::remove_file(path).with_context(|| format!("Failed to remove {path:?}"))?;
+        }
+    }
+    Ok(collected.len())
+}
+
+pub(crate) const SUMMARY_CSV_FILE: &str = "summary.csv";
+
+/// Completion latency percentiles of the summary rows.
+const SUMMARY_PERCENTILES: [f64; 3] = [50.0, 99.0, 99.9];
+
+/// Picks the statistics of one direction out of a job.
+type Direction = fn(&FioJob) -> &FioStats;
+
+const DIRECTIONS: [(&str, Direction); 2] = [("read", |j| &j.read), ("write", |j| &j.write)];
+
+/// IOPS and bandwidth of one direction summed over `jobs`.
+fn direction_totals(jobs: &[FioJob], stats: Direction) -> (f64, u64) {
+    (
+        jobs.iter().map(|j| stats(j).iops).sum(),
+        jobs.iter().map(|j| stats(j).bw_bytes).sum(),
+    )
+}
+
+/// The highest completion latency percentile `p` of one direction over
+/// `jobs`, in ns.
+fn worst_percentile(jobs: &[FioJob], stats: Direction, p: f64) -> Option<f64> {
+    jobs.iter()
+        .filter_map(|j| stats(j).clat_ns.percentile(p))
+        .reduce(f64::max)
+}
+
+/// Write [`SUMMARY_CSV_FILE`] with one row per sample and config of the
+/// batch. The columns do not depend on the results, so summaries of two
+/// batches diff cleanly, and metrics fio did not report are left empty.
+/// Returns the number of rows written.
+pub(crate) fn write_summary_csv(config: &Config, batch_dir: &Path) -> Result<usize> {
+    use std::fmt::Write;
+    let unit = BandwidthUnit::parse(&config.bw_unit)?;
+
+    let mut csv = String::from(
+        "sample,device,workload,block_size,jobcount,queue_depth,scheduler,rate,thinktime",
+    );
+    for (direction, _) in DIRECTIONS {
+        write!(csv, ",{direction}_iops,{direction}_bw_{}", unit.label())?;
+        for p in SUMMARY_PERCENTILES {
+            write!(csv, ",{direction}_p{p}_us")?;
+        }
+    }
+    csv.push('\n');
+
+    let outputs = load_outputs(config, batch_dir)?;
+    let mut any_percentile = false;
+    for loaded in &outputs {
+        let entry = &loaded.entry;
+        write!(
+            csv,
+            "{},{},{},{},{},{},{},{},{}",
+            loaded.sample,
+            entry.device.as_deref().unwrap_or(&config.device),
+            entry.workload,
+            entry.block_size,
+            entry.jobcount,
+            entry.queue_depth,
+            entry.scheduler,
+            entry
+                .rate
+                .as_ref()
+                .map(|r| r.to_string())
+                .unwrap_or_default(),
+            entry.thinktime.as_deref().unwrap_or_default(),
+        )?;
+        for (_, stats) in DIRECTIONS {
+            let jobs = &loaded.output.jobs;
+            let (iops, bw) = direction_totals(jobs, stats);
+            write!(csv, ",{iops:.0},{:.2}", unit.convert(bw))?;
+            for p in SUMMARY_PERCENTILES {
+                let worst = worst_percentile(jobs, stats, p);
+                match worst {
+                    Some(ns) => write!(csv, ",{:.1}", ns / 1000.0)?,
+                    None => csv.push(','),
+                }
+                any_percentile |= worst.is_some();
+            }
+        }
+        csv.push('\n');
+    }
+    // Batches run with gtod_reduce
+    if !outputs.is_empty() && !any_percentile {
+        log::warn!("No latency percentiles in {batch_dir:?} to summarize");
+    }
+
+    let path = batch_dir.join(SUMMARY_CSV_FILE);
+    std::fs::write(&path, csv).with_context(|| format!("Failed to write {path:?}"))?;
+    Ok(outputs.len())
+}
+
+/// A fio output of a batch with the run directory it belongs to.
+struct LoadedOutput {
+    sample: usize,
+    run_dir: PathBuf,
+    entry: MatrixEntry,
+    output: FioOutput,
+    /// With `histogram_percentiles` only.
+    histograms: Option<HistogramOutput>,
+}
+
+/// The fio outputs of a batch, from [`NDJSON_FILE`] if there is one.
+fn load_outputs(config: &Config, batch_dir: &Path) -> Result<Vec<LoadedOutput>> {
+    let histograms = !config.histogram_percentiles.is_empty();
+    let ndjson = batch_dir.join(NDJSON_FILE);
+    if ndjson.exists() {
+        let data = std::fs::read_to_string(&ndjson)
+            .with_context(|| format!("Failed to read {ndjson:?}"))?;
+        return data
+            .lines()
+            .enumerate()
+            .map(|(i, line)| {
+                let parse = || -> Result<_> {
+                    let record: NdjsonRecord = serde_json::from_str(line)?;
+                    let (output, histograms) = match record.output {
+                        serde_json::Value::String(terse) => (parse_terse(&terse)?, None),
+                        output => (
+                            serde_json::from_value(output.clone())?,
+                            histograms
+                                .then(|| serde_json::from_value(output))
+                                .transpose()?,
+                        ),
+                    };
+                    Ok(LoadedOutput {
+                        sample: record.sample,
+                        run_dir: batch_dir.join(record.run_dir),
+                        entry: record.entry,
+                        output,
+                        histograms,
+                    })
+                };
+                parse().with_context(|| format!("Failed to parse line {} of {ndjson:?}", i + 1))
+            })
+            .collect();
+    }
+
+    let mut outputs = Vec::new();
+    for (sample, run_dir) in run_dirs(batch_dir)?.into_iter().enumerate() {
+        for (entry, output) in parse_run_dir(config, &run_dir) {
+            let run_output_id = crate::matrix::run_output_id(config, &entry);
+            let entry_dir = entry_dir(&run_dir, &entry);
+            let path = entry_dir.join(format!("{run_output_id}{}", output_suffix(config)));
+            let histograms = match histograms {
+                true => parse_histogram_file(&path)
+                    .inspect_err(|e| log::warn!("Ignoring histograms: {e:?}"))
+                    .ok(),
+                false => None,
+            };
+            outputs.push(LoadedOutput {
+                sample,
+                run_dir: entry_dir,
+                entry: entry.clone(),
+                output,
+                histograms,
+            });
+        }
+    }
+    Ok(outputs)
+}
+
+impl Batch {
+    pub(crate) fn load(batch_dir: &Path) -> Result<Self> {
+        let config = Config::load(&PathBuf::from(batch_dir).join("config.toml"))?;
+
+        let read_power = |path: &Path| -> Option<f64> {
+            if !path.exists() {
+                return None;
+            }
+            crate::power::parse_file(path)
+                .inspect_err(|e| log::warn!("Ignoring {path:?}: {e:?}"))
+                .ok()
+                .flatten()
+        };
+
+        let read_trend = |path: &Path| -> Option<bool> {
+            if !path.exists() {
+                return None;
+            }
+            crate::trend::read(path)
+                .inspect_err(|e| log::warn!("Ignoring {path:?}: {e:?}"))
+                .ok()
+                .map(|t| t.degrading)
+        };
+
+        let mut samples = Vec::new();
+        for loaded in load_outputs(&config, batch_dir)? {
+            let LoadedOutput {
+                sample: _,
+                run_dir,
+                entry,
+                output,
+                histograms,
+            } = loaded;
+            let run_output_id = crate::matrix::run_output_id(&config, &entry);
+            let power_watts = read_power(&run_dir.join(format!("{run_output_id}-turbostat.txt")));
+            let latency_degrading =
+                read_trend(&run_dir.join(format!("{run_output_id}{}", crate::trend::TREND_SUFFIX)));
+            // Phases become entries of their own, `name.1-write` and so on
+            let outputs = match entry.workload.phases() {
+                Some(_) => output
+                    .into_phases()
+                    .into_iter()
+                    .map(|(phase, output)| {
+                        let workload = format!("{}.{phase}", entry.workload);
+                        let entry = MatrixEntry {
+                            workload: Workload::from(workload.as_str()),
+                            ..entry.clone()
+                        };
+                        (entry, output, Some(phase))
+                    })
+                    .collect(),
+                None => vec![(entry, output, None)],
+            };
+            for (entry, output, phase) in outputs {
+                let mut histogram_percentiles_ns = Vec::new();
+                if let Some(histograms) = &histograms {
+                    for p in &config.histogram_percentiles {
+                        if let Some(ns) = histograms.percentile(phase.as_deref(), *p)? {
+                            histogram_percentiles_ns.push((*p, ns));
+                        }
+                    }
+                }
+                samples.push(SampleResult {
+                    entry,
+                    output,
+                    power_watts,
+                    latency_degrading,
+                    histogram_percentiles_ns,
+                });
+            }
+        }
+
+        log::info!("Loaded {} results from {batch_dir:?}", samples.len());
+        Ok(Self {
+            config,
+            samples,
+            power_baseline_watts: read_power(&batch_dir.join(crate::power::BASELINE_FILE)),
+            overhead: read_overhead(batch_dir)?,
+        })
+    }
+
+    pub(crate) fn aggregate(&self) -> Result<Vec<Aggregate>> {
+        let mut aggregates = aggregate(
+            &self.samples,
+            self.power_baseline_watts,
+            DeviceLimits::from_config(&self.config)?,
+        );
+        for aggregate in &mut aggregates {
+            aggregate.harness_ceiling_iops = self
+                .overhead
+                .iter()
+                .find(|c| {
+                    c.jobcount == aggregate.entry.jobcount
+                        && c.queue_depth == aggregate.entry.queue_depth
+                })
+                .map(|c| c.iops);
+        }
+        Ok(aggregates)
+    }
+}
+
+/// Statistics of one matrix entry across samples.
+#[derive(Debug, Serialize)]
+pub(crate) struct Aggregate {
+    pub(crate) entry: MatrixEntry,
+    pub(crate) samples: usize,
+    pub(crate) iops_mean: f64,
+    pub(crate) iops_stddev: f64,
+    /// Mean over samples of the standard deviation between jobs, zero with
+    /// group reporting.
+    pub(crate) job_iops_stddev_mean: f64,
+    pub(crate) bw_bytes_mean: f64,
+    pub(crate) p50_ns_mean: Option<f64>,
+    pub(crate) p99_ns_mean: Option<f64>,
+    pub(crate) power_watts_mean: Option<f64>,
+    /// Power above the idle baseline of the batch.
+    pub(crate) net_power_watts_mean: Option<f64>,
+    /// Whether fixed rate runs reached their offered load.
+    pub(crate) rate_met: Option<bool>,
+    /// Mean IOPS in percent of the rated device maximum.
+    pub(crate) iops_pct_of_max: Option<f64>,
+    pub(crate) bw_pct_of_max: Option<f64>,
+    /// Whether the p99 rose over the run in any sample.
+    pub(crate) latency_degrading: Option<bool>,
+    /// Mean over samples of each `histogram_percentiles` in ns.
+    pub(crate) histogram_percentiles_ns: Vec<(f64, f64)>,
+    /// IOPS of the null engine at the same jobcount and queue depth.
+    pub(crate) harness_ceiling_iops: Option<f64>,
+    /// Only when fio measured latencies, printed with `latency_breakdown`.
+    pub(crate) latency_breakdown: Option<LatencyBreakdown>,
+    /// Where the means and IOPS stddev above come from, written to
+    /// `aggregate.json` rather than repeated in the summary.
+    #[serde(skip)]
+    pub(crate) spread: Spreads,
+}
+
+/// Whether fio reported latency percentiles for any of `aggregates`, it
+/// does not in batches run with gtod_reduce.
+pub(crate) fn has_percentiles(aggregates: &[Aggregate]) -> bool {
+    aggregates.iter().any(|a| a.p99_ns_mean.is_some())
+}
+
+impl Aggregate {
+    /// The metrics whose coefficient of variation is above `max_cv`.
+    pub(crate) fn noisy(&self, max_cv: f64) -> Vec<(&'static str, f64)> {
+        let s = &self.spread;
+        [
+            ("iops", Some(s.iops)),
+            ("bw", Some(s.bw_bytes)),
+            ("p50", s.p50_ns),
+            ("p99", s.p99_ns),
+        ]
+        .into_iter()
+        .filter_map(|(name, spread)| Some((name, spread?.cv)))
+        .filter(|(_, cv)| *cv > max_cv)
+        .collect()
+    }
+}
+
+/// Distribution of one metric across samples.
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq)]
+pub(crate) struct Spread {
+    pub(crate) mean: f64,
+    pub(crate) median: f64,
+    pub(crate) stddev: f64,
+    /// Coefficient of variation, the stddev relative to the mean.
+    pub(crate) cv: f64,
+}
+
+impl Spread {
+    fn of(values: &[f64]) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        let mut sorted = values.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let mid = sorted.len() / 2;
+        let median = match sorted.len() % 2 {
+            0 => (sorted[mid - 1] + sorted[mid]) / 2.0,
+            _ => sorted[mid],
+        };
+        let (mean, stddev) = (mean(values), stddev(values));
+        Some(Self {
+            mean,
+            median,
+            stddev,
+            cv: if mean == 0.0 { 0.0 } else { stddev / mean },
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq)]
+pub(crate) struct Spreads {
+    pub(crate) iops: Spread,
+    pub(crate) bw_bytes: Spread,
+    pub(crate) p50_ns: Option<Spread>,
+    pub(crate) p99_ns: Option<Spread>,
+}
+
+/// Means over samples of the mean latencies in ns.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub(crate) struct LatencyBreakdown {
+    pub(crate) slat_ns_mean: f64,
+    pub(crate) clat_ns_mean: f64,
+    pub(crate) lat_ns_mean: f64,
+}
+
+impl LatencyBreakdown {
+    fn of(outputs: &[&FioOutput]) -> Option<Self> {
+        let means = |latency: fn(&FioStats) -> &FioLatency| {
+            let values = outputs
+                .iter()
+                .filter_map(|o| o.mean_latency(latency))
+                .collect::<Vec<_>>();
+            (!values.is_empty()).then(|| mean(&values))
+        };
+        Some(Self {
+            slat_ns_mean: means(|s| &s.slat_ns)?,
+            clat_ns_mean: means(|s| &s.clat_ns)?,
+            lat_ns_mean: means(|s| &s.lat_ns)?,
+        })
+    }
+}
+
+/// Rated maxima of the device under test, if configured.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct DeviceLimits {
+    pub(crate) max_iops: Option<f64>,
+    pub(crate) max_bw_bytes: Option<f64>,
+}
+
+impl DeviceLimits {
+    pub(crate) fn from_config(config: &Config) -> Result<Self> {
+        let max_bw_bytes = config
+            .device_max_bw
+            .as_deref()
+            .map(|bw| {
+                byte_unit::Byte::parse_str(bw, true)
+                    .map(|b| b.as_u64() as f64)
+                    .map_err(|e| anyhow::anyhow!("Invalid device_max_bw {bw:?}: {e}"))
+            })
+            .transpose()?;
+        Ok(Self {
+            max_iops: config.device_max_iops,
+            max_bw_bytes,
+        })
+    }
+}
+
+fn percent_of(value: f64, max: Option<f64>) -> Option<f64> {
+    max.filter(|m| *m > 0.0).map(|m| value / m * 100.0)
+}
+
+/// fio falls slightly short of the rate it is asked for.
+const RATE_TOLERANCE: f64 = 0.98;
+
+/// Compare the achieved mean of each direction the workload does against
+/// the offered load of all jobs. fio applies `--rate` and `--rate_iops` to
+/// reads and writes separately, so a mixed workload can meet the rate in
+/// total and still fall short in one direction. Each direction is given as
+/// its mean iops and bandwidth.
+fn rate_met(entry: &MatrixEntry, read: (f64, f64), write: (f64, f64)) -> Option<bool> {
+    let rate = entry.rate.as_ref()?;
+    let jobs = f64::from(entry.jobcount);
+    let rws = match entry.workload.phases() {
+        Some(phases) => phases.iter().map(|p| p.rw.as_str()).collect(),
+        None => vec![entry.workload.rw()],
+    };
+    let reads = rws
+        .iter()
+        .any(|rw| rw.contains("read") || rw.ends_with("rw"));
+    let writes = rws
+        .iter()
+        .any(|rw| rw.contains("write") || rw.ends_with("rw"));
+    let directions = [(reads, read), (writes, write)]
+        .into_iter()
+        .filter_map(|(does, means)| does.then_some(means))
+        .collect::<Vec<_>>();
+    if directions.is_empty() {
+        return None;
+    }
+    let offered = match rate {
+        Rate::Iops(iops) => *iops as f64,
+        Rate::Bandwidth(bw) => crate::matrix::block_size_bytes(bw).ok()? as f64,
+    };
+    Some(directions.into_iter().all(|(iops, bw_bytes)| {
+        let achieved = match rate {
+            Rate::Iops(_) => iops,
+            Rate::Bandwidth(_) => bw_bytes,
+        };
+        achieved >= offered * jobs * RATE_TOLERANCE
+    }))
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+fn stddev(values: &[f64]) -> f64 {
+    if values.len() < 2 {
+        return 0.0;
+    }
+    let m = mean(values);
+    (values.iter().map(|v| (v - m).powi(2)).sum::<f64>() / (values.len() - 1) as f64).sqrt()
+}
+
+/// Group results by entry, in the order entries first appear.
+pub(crate) fn aggregate(
+    results: &[SampleResult],
+    power_baseline_watts: Option<f64>,
+    limits: DeviceLimits,
+) -> Vec<Aggregate> {
+    let mut groups: Vec<(&MatrixEntry, Vec<&SampleResult>)> = Vec::new();
+    for result in results {
+        match groups.iter_mut().find(|(e, _)| **e == result.entry) {
+            Some((_, group)) => group.push(result),
+            None => groups.push((&result.entry, vec![result])),
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(entry, group)| {
+            let outputs = group.iter().map(|r| &r.output).collect::<Vec<_>>();
+            let power = group
+                .iter()
+                .filter_map(|r| r.power_watts)
+                .collect::<Vec<_>>();
+            let power_watts_mean = (!power.is_empty()).then(|| mean(&power));
+            let iops = outputs.iter().map(|o| o.total_iops()).collect::<Vec<_>>();
+            let bw = outputs
+                .iter()
+                .map(|o| o.total_bw_bytes() as f64)
+                .collect::<Vec<_>>();
+            let p50 = outputs
+                .iter()
+                .filter_map(|o| o.max_clat_percentile(50.0))
+                .collect::<Vec<_>>();
+            let p99 = outputs
+                .iter()
+                .filter_map(|o| o.max_clat_percentile(99.0))
+                .collect::<Vec<_>>();
+            let spread = Spreads {
+                iops: Spread::of(&iops).unwrap_or_default(),
+                bw_bytes: Spread::of(&bw).unwrap_or_default(),
+                p50_ns: Spread::of(&p50),
+                p99_ns: Spread::of(&p99),
+            };
+            Aggregate {
+                entry: entry.clone(),
+                samples: outputs.len(),
+                iops_mean: spread.iops.mean,
+                iops_stddev: spread.iops.stddev,
+                job_iops_stddev_mean: mean(
+                    &outputs
+                        .iter()
+                        .map(|o| stddev(&o.job_iops()))
+                        .collect::<Vec<_>>(),
+                ),
+                bw_bytes_mean: spread.bw_bytes.mean,
+                p50_ns_mean: spread.p50_ns.map(|s| s.mean),
+                p99_ns_mean: spread.p99_ns.map(|s| s.mean),
+                power_watts_mean,
+                net_power_watts_mean: power_watts_mean
+                    .zip(power_baseline_watts)
+                    .map(|(power, baseline)| power - baseline),
+                rate_met: rate_met(
+                    entry,
+                    direction_means(&outputs, |j| &j.read),
+                    direction_means(&outputs, |j| &j.write),
+                ),
+                iops_pct_of_max: percent_of(spread.iops.mean, limits.max_iops),
+                bw_pct_of_max: percent_of(spread.bw_bytes.mean, limits.max_bw_bytes),
+                latency_degrading: group
+                    .iter()
+                    .filter_map(|r| r.latency_degrading)
+                    .reduce(|a, b| a || b),
+                histogram_percentiles_ns: histogram_percentile_means(&group),
+                harness_ceiling_iops: None,
+                latency_breakdown: LatencyBreakdown::of(&outputs),
+                spread,
+            }
+        })
+        .collect()
+}
+
+/// Mean iops and bandwidth of one direction over the samples.
+fn direction_means(outputs: &[&FioOutput], stats: Direction) -> (f64, f64) {
+    let totals = outputs
+        .iter()
+        .map(|o| {
+            o.jobs.iter().map(stats).fold((0.0, 0.0), |(iops, bw), s| {
+                (iops + s.iops, bw + s.bw_bytes as f64)
+            })
+        })
+        .collect::<Vec<_>>();
+    let (iops, bw): (Vec<_>, Vec<_>) = totals.into_iter().unzip();
+    (mean(&iops), mean(&bw))
+}
+
+/// Mean of each percentile over the samples that have it, in the order of
+/// the first sample.
+fn histogram_percentile_means(group: &[&SampleResult]) -> Vec<(f64, f64)> {
+    let mut percentiles: Vec<(f64, Vec<f64>)> = Vec::new();
+    for (p, ns) in group.iter().flat_map(|r| &r.histogram_percentiles_ns) {
+        match percentiles.iter_mut().find(|(q, _)| q == p) {
+            Some((_, values)) => values.push(*ns),
+            None => percentiles.push((*p, vec![*ns])),
+        }
+    }
+    percentiles
+        .into_iter()
+        .map(|(p, values)| (p, mean(&values)))
+        .collect()
+}
+
+pub(crate) const SUMMARY_FILE: &str = "summary.json";
+pub(crate) const AGGREGATE_FILE: &str = "aggregate.json";
+
+/// Write the aggregated results of a batch into `summary.json` next to them.
+pub(crate) fn write_summary(batch_dir: &Path, aggregates: &[Aggregate]) -> Result<PathBuf> {
+    let path = PathBuf::from(batch_dir).join(SUMMARY_FILE);
+    let file =
+        std::fs::File::create(&path).with_context(|| format!("Failed to create {path:?}"))?;
+    serde_json::to_writer_pretty(file, aggregates).context("Failed to write summary")?;
+    Ok(path)
+}
+
+/// The spread of one entry across samples, as in `aggregate.json`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) struct EntrySpread {
+    pub(crate) entry: MatrixEntry,
+    pub(crate) samples: usize,
+    #[serde(flatten)]
+    pub(crate) spread: Spreads,
+}
+
+impl From<&Aggregate> for EntrySpread {
+    fn from(aggregate: &Aggregate) -> Self {
+        Self {
+            entry: aggregate.entry.clone(),
+            samples: aggregate.samples,
+            spread: aggregate.spread,
+        }
+    }
+}
+
+/// Write only the spread across samples of each entry into
+/// `aggregate.json`.
+pub(crate) fn write_aggregate(batch_dir: &Path, aggregates: &[Aggregate]) -> Result<PathBuf> {
+    let path = PathBuf::from(batch_dir).join(AGGREGATE_FILE);
+    let file =
+        std::fs::File::create(&path).with_context(|| format!("Failed to create {path:?}"))?;
+    let spreads = aggregates.iter().map(EntrySpread::from).collect::<Vec<_>>();
+    serde_json::to_writer_pretty(file, &spreads).context("Failed to write aggregate")?;
+    Ok(path)
+}
+
+/// The spreads of a previous batch, from its `aggregate.json` or its
+/// directory. Batches from before `aggregate.json` existed are aggregated
+/// again from their results.
+pub(crate) fn load_baseline(path: &Path) -> Result<Vec<EntrySpread>> {
+    let file = match path.is_dir() {
+        true => path.join(AGGREGATE_FILE),
+        false => path.to_path_buf(),
+    };
+    if file.exists() {
+        let data =
+            std::fs::read_to_string(&file).with_context(|| format!("Failed to read {file:?}"))?;
+        return serde_json::from_str(&data).with_context(|| format!("Failed to parse {file:?}"));
+    }
+    let aggregates = Batch::load(path)
+        .with_context(|| format!("Failed to load baseline batch {path:?}"))?
+        .aggregate()?;
+    Ok(aggregates.iter().map(EntrySpread::from).collect())
+}
+
+/// An entry of this batch against the baseline, matched by their display
+/// form so labels do not matter.
+#[derive(Debug)]
+pub(crate) enum BaselineRow<'a> {
+    Matched {
+        baseline: &'a EntrySpread,
+        current: &'a EntrySpread,
+    },
+    Added(&'a EntrySpread),
+    Removed(&'a EntrySpread),
+}
+
+impl BaselineRow<'_> {
+    fn entry(&self) -> &MatrixEntry {
+        match self {
+            BaselineRow::Matched { current, .. } => &current.entry,
+            BaselineRow::Added(spread) | BaselineRow::Removed(spread) => &spread.entry,
+        }
+    }
+
+    /// Percent changes of the mean IOPS, bandwidth, p50 and p99 latency.
+    fn changes(&self) -> [Option<f64>; 4] {
+        let BaselineRow::Matched { baseline, current } = self else {
+            return [None; 4];
+        };
+        let change = |baseline: Option<Spread>, current: Option<Spread>| {
+            let (baseline, current) = (baseline?.mean, current?.mean);
+            (baseline != 0.0).then(|| (current - baseline) / baseline * 100.0)
+        };
+        let (b, c) = (&baseline.spread, &current.spread);
+        [
+            change(Some(b.iops), Some(c.iops)),
+            change(Some(b.bw_bytes), Some(c.bw_bytes)),
+            change(b.p50_ns, c.p50_ns),
+            change(b.p99_ns, c.p99_ns),
+        ]
+    }
+}
+
+/// Entries of `current` in their order, then those only in `baseline`.
+pub(crate) fn compare_baseline<'a>(
+    baseline: &'a [EntrySpread],
+    current: &'a [EntrySpread],
+) -> Vec<BaselineRow<'a>> {
+    let key = |spread: &EntrySpread| spread.entry.to_string();
+    let mut rows = current
+        .iter()
+        .map(|c| match baseline.iter().find(|b| key(b) == key(c)) {
+            Some(b) => BaselineRow::Matched {
+                baseline: b,
+                current: c,
+            },
+            None => BaselineRow::Added(c),
+        })
+        .collect::<Vec<_>>();
+    rows.extend(
+        baseline
+            .iter()
+            .filter(|b| !current.iter().any(|c| key(c) == key(b)))
+            .map(BaselineRow::Removed),
+    );
+    rows
+}
+
+pub(crate) const COMPARISON_CSV_FILE: &str = "comparison.csv";
+
+/// Write [`COMPARISON_CSV_FILE`] with the percent change of each entry
+/// against the baseline. Added and removed entries have no changes.
+pub(crate) fn write_comparison_csv(batch_dir: &Path, rows: &[BaselineRow]) -> Result<PathBuf> {
+    use std::fmt::Write;
+    let mut csv = String::from(
+        "status,device,workload,block_size,jobcount,queue_depth,scheduler,rate,thinktime,\
+         iops_change_pct,bw_change_pct,p50_change_pct,p99_change_pct\n",
+    );
+    for row in rows {
+        let entry = row.entry();
+        write!(
+            csv,
+            "{},{},{},{},{},{},{},{},{}",
+            match row {
+                BaselineRow::Matched { .. } => "matched",
+                BaselineRow::Added(_) => "added",
+                BaselineRow::Removed(_) => "removed",
+            },
+            entry.device.as_deref().unwrap_or_default(),
+            entry.workload,
+            entry.block_size,
+            entry.jobcount,
+            entry.queue_depth,
+            entry.scheduler,
+            entry
+                .rate
+                .as_ref()
+                .map(|r| r.to_string())
+                .unwrap_or_default(),
+            entry.thinktime.as_deref().unwrap_or_default(),
+        )?;
+        for change in row.changes() {
+            match change {
+                Some(pct) => write!(csv, ",{pct:.2}")?,
+                None => csv.push(','),
+            }
+        }
+        csv.push('\n');
+    }
+    let path = batch_dir.join(COMPARISON_CSV_FILE);
+    std::fs::write(&path, csv).with_context(|| format!("Failed to write {path:?}"))?;
+    Ok(path)
+}
+
+/// Matched entries whose IOPS or bandwidth fell, or whose p99 latency
+/// rose, by more than `max_pct` percent. A matched entry without a p99 on
+/// either side is one too, as its latency cannot be checked.
+pub(crate) fn regressions(rows: &[BaselineRow], max_pct: f64) -> Vec<String> {
+    let mut regressions = Vec::new();
+    for row in rows {
+        if let BaselineRow::Matched { baseline, current } = row {
+            if baseline.spread.p99_ns.is_none() || current.spread.p99_ns.is_none() {
+                regressions.push(format!("{}: no p99 latency to compare", row.entry()));
+            }
+        }
+        let [iops, bw, _, p99] = row.changes();
+        let checks = [
+            ("IOPS fell", iops.map(|pct| -pct)),
+            ("bandwidth fell", bw.map(|pct| -pct)),
+            ("p99 rose", p99),
+        ];
+        for (what, pct) in checks {
+            if let Some(pct) = pct.filter(|pct| *pct > max_pct) {
+                regressions.push(format!(
+                    "{}: {what} {pct:.1}%, more than {max_pct:.1}%",
+                    row.entry()
+                ));
+            }
+        }
+    }
+    regressions
+}
+
+/// The aggregates sorted by entry, with nothing from the batch directory,
+/// its name or when it ran. Two batches of the same matrix differ only in
+/// the measured values.
+pub(crate) fn canonical_summary(aggregates: &[Aggregate]) -> Result<String> {
+    let mut sorted = aggregates.iter().collect::<Vec<_>>();
+    sorted.sort_by_cached_key(|a| {
+        let e = &a.entry;
+        (
+            e.workload.to_string(),
+            crate::matrix::block_size_bytes(&e.block_size).unwrap_or(u64::MAX),
+            e.jobcount,
+            e.queue_depth,
+            e.scheduler.clone(),
+            e.to_string(),
+        )
+    });
+    let mut data = serde_json::to_string_pretty(&sorted).context("Failed to write summary")?;
+    data.push('\n');
+    Ok(data)
+}
+
+/// Describe every aggregate that misses its threshold.
+pub(crate) fn check_thresholds(thresholds: &Thresholds, aggregates: &[Aggregate]) -> Vec<String> {
+    let mut violations = Vec::new();
+    for aggregate in aggregates {
+        let threshold = thresholds.for_workload(aggregate.entry.workload.rw());
+        if let Some(min_iops) = threshold.min_iops {
+            if aggregate.iops_mean < min_iops {
+                violations.push(format!(
+                    "{}: iops {:.0} below minimum {min_iops:.0}",
+                    aggregate.entry, aggregate.iops_mean
+                ));
+            }
+        }
+        if let Some(max_p99_us) = threshold.max_p99_us {
+            match aggregate.p99_ns_mean.map(|p| p / 1000.0) {
+                Some(p99_us) if p99_us > max_p99_us => violations.push(format!(
+                    "{}: p99 {p99_us:.1}us above maximum {max_p99_us:.1}us",
+                    aggregate.entry
+                )),
+                Some(_) => {}
+                None => violations.push(format!("{}: no p99 latency reported", aggregate.entry)),
+            }
+        }
+    }
+    violations
+}
+
+/// One group of batches in a comparison of a matrix entry.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ComparisonRow {
+    pub(crate) label: String,
+    pub(crate) batches: usize,
+    pub(crate) iops_mean: f64,
+    pub(crate) p99_ns_mean: Option<f64>,
+}
+
+/// Group the aggregates of labelled batches by matrix entry, and within an
+/// entry by label. Both keep the order they first appear in.
+pub(crate) fn comparison(
+    batches: &[(String, Vec<Aggregate>)],
+) -> Vec<(String, Vec<ComparisonRow>)> {
+    type Labels<'a> = Vec<(&'a str, Vec<&'a Aggregate>)>;
+    let mut entries: Vec<(String, Labels)> = Vec::new();
+    for (label, aggregates) in batches {
+        for aggregate in aggregates {
+            let key = aggregate.entry.to_string();
+            let labels = match entries.iter_mut().position(|(k, _)| *k == key) {
+                Some(i) => &mut entries[i].1,
+                None => {
+                    entries.push((key, Vec::new()));
+                    &mut entries.last_mut().expect("just pushed").1
+                }
+            };
+            match labels.iter_mut().find(|(l, _)| l == label) {
+                Some((_, group)) => group.push(aggregate),
+                None => labels.push((label, vec![aggregate])),
+            }
+        }
+    }
+
+    entries
+        .into_iter()
+        .map(|(key, labels)| {
+            let rows = labels
+                .into_iter()
+                .map(|(label, group)| {
+                    let p99 = group
+                        .iter()
+                        .filter_map(|a| a.p99_ns_mean)
+                        .collect::<Vec<_>>();
+                    ComparisonRow {
+                        label: label.to_string(),
+                        batches: group.len(),
+                        iops_mean: mean(&group.iter().map(|a| a.iops_mean).collect::<Vec<_>>()),
+                        p99_ns_mean: (!p99.is_empty()).then(|| mean(&p99)),
+                    }
+                })
+                .collect();
+            (key, rows)
+        })
+        .collect()
+}
+
+pub(crate) fn print_comparison(comparison: &[(String, Vec<ComparisonRow>)]) {
+    for (entry, rows) in comparison {
+        println!("{entry}:");
+        for row in rows {
+            println!(
+                "  {} (batches {}): iops {:.0} p99 {}",
+                row.label,
+                row.batches,
+                row.iops_mean,
+                row.p99_ns_mean
+                    .map(|p| format!("{:.1}us", p / 1000.0))
+                    .unwrap_or_else(|| "-".into()),
+            );
+        }
+    }
+}
+
+pub(crate) fn print_summary(config: &Config, aggregates: &[Aggregate]) -> Result<()> {
+    let unit = BandwidthUnit::parse(&config.bw_unit)?;
+    let of_max = |pct: Option<f64>| {
+        pct.map(|p| format!(" ({p:.0}% of rated)"))
+            .unwrap_or_default()
+    };
+    for aggregate in aggregates {
+        println!(
+            "{}{}: samples {} iops {:.0}{}{} (stddev {:.0}, job stddev {:.0}) bw {:.2} {}{} p99 {}{}{}{}{}{}",
+            aggregate.entry,
+            match aggregate.entry.labels.is_empty() {
+                true => String::new(),
+                false => format!(" [{}]", aggregate.entry.labels.join(", ")),
+            },
+            aggregate.samples,
+            aggregate.iops_mean,
+            of_max(aggregate.iops_pct_of_max),
+            aggregate
+                .harness_ceiling_iops
+                .map(|c| format!(" (ceiling {c:.0})"))
+                .unwrap_or_default(),
+            aggregate.iops_stddev,
+            aggregate.job_iops_stddev_mean,
+            unit.convert(aggregate.bw_bytes_mean as u64),
+            unit.label(),
+            of_max(aggregate.bw_pct_of_max),
+            aggregate
+                .p99_ns_mean
+                .map(|p| format!("{:.1}us", p / 1000.0))
+                .unwrap_or_else(|| "-".into()),
+            aggregate
+                .histogram_percentiles_ns
+                .iter()
+                .map(|(p, ns)| format!(" p{p} {:.1}us", ns / 1000.0))
+                .collect::<String>(),
+            aggregate
+                .latency_breakdown
+                .filter(|_| config.latency_breakdown)
+                .map(|l| format!(
+                    " lat {:.1}us (slat {:.1}us clat {:.1}us)",
+                    l.lat_ns_mean / 1000.0,
+                    l.slat_ns_mean / 1000.0,
+                    l.clat_ns_mean / 1000.0
+                ))
+                .unwrap_or_default(),
+            match (aggregate.power_watts_mean, aggregate.net_power_watts_mean) {
+                (Some(raw), Some(net)) => format!(" power {raw:.1}W (net {net:.1}W)"),
+                (Some(raw), None) => format!(" power {raw:.1}W"),
+                _ => String::new(),
+            },
+            match aggregate.rate_met {
+                Some(false) => " (rate not met)",
+                _ => "",
+            },
+            match aggregate.latency_degrading {
+                Some(true) => " (p99 degrading)",
+                _ => "",
+            },
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_device_limits() -> Result<()> {
+        let config = Config {
+            device_max_iops: Some(500_000.0),
+            device_max_bw: Some("2GB".into()),
+            ..Default::default()
+        };
+        let limits = DeviceLimits::from_config(&config)?;
+        assert_eq!(percent_of(400_000.0, limits.max_iops), Some(80.0));
+        assert_eq!(percent_of(1e9, limits.max_bw_bytes), Some(50.0));
+        let limits = DeviceLimits::from_config(&Config::default())?;
+        assert_eq!(percent_of(1e9, limits.max_bw_bytes), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rate_met() {
+        let entry = |rate, workload: &str| MatrixEntry {
+            block_size: "4k".into(),
+            jobcount: 2,
+            workload: workload.into(),
+            queue_depth: 1,
+            scheduler: "none".into(),
+            rate,
+            thinktime: None,
+            labels: Vec::new(),
+            device: None,
+        };
+        let (randread, randrw) = ("randread", "randrw");
+        let iops = |iops| (iops, 0.0);
+        let bw = |bw| (0.0, bw);
+        assert_eq!(
+            rate_met(&entry(None, randread), iops(100.0), iops(0.0)),
+            None
+        );
+        let rate = Some(Rate::Iops(1000));
+        assert_eq!(
+            rate_met(&entry(rate.clone(), randread), iops(1990.0), iops(0.0)),
+            Some(true)
+        );
+        assert_eq!(
+            rate_met(&entry(rate.clone(), randread), iops(1500.0), iops(0.0)),
+            Some(false)
+        );
+        // Both directions are capped, 2490 in total does not meet it
+        assert_eq!(
+            rate_met(&entry(rate.clone(), randrw), iops(1990.0), iops(500.0)),
+            Some(false)
+        );
+        assert_eq!(
+            rate_met(&entry(rate, randrw), iops(1990.0), iops(1990.0)),
+            Some(true)
+        );
+
+        // 1m is 1 << 20 bytes to fio
+        let rate = Some(Rate::Bandwidth("1m".into()));
+        assert_eq!(
+            rate_met(&entry(rate.clone(), randread), bw(2.0 * 1048576.0), bw(0.0)),
+            Some(true)
+        );
+        assert_eq!(
+            rate_met(&entry(rate, randread), bw(2.0 * 1_000_000.0), bw(0.0)),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn test_parse_latency_target() -> Result<()> {
+        let output: FioOutput = serde_json::from_str(
+            r#"{
+                "jobs": [{
+                    "jobname": "default",
+                    "read": { "iops": 1000.0 },
+                    "latency_depth": 8,
+                    "latency_target": 500,
+                    "latency_percentile": 99.0,
+                    "latency_window": 1000000
+                }]
+            }"#,
+        )?;
+        let job = &output.jobs[0];
+        assert_eq!(job.latency_depth, Some(8));
+        assert_eq!(job.latency_target, Some(500));
+        assert_eq!(job.write.iops, 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_percentile_lookup() -> Result<()> {
+        let output: FioOutput = serde_json::from_str(
+            r#"{
+                "jobs": [{
+                    "jobname": "default",
+                    "read": {
+                        "iops": 10.0,
+                        "clat_ns": { "mean": 5.0, "percentile": { "50.000000": 4, "99.000000": 9 } }
+                    },
+                    "write": {
+                        "iops": 5.0,
+                        "clat_ns": { "mean": 5.0, "percentile": { "99.000000": 12 } }
+                    }
+                }]
+            }"#,
+        )?;
+        assert_eq!(output.total_iops(), 15.0);
+        assert_eq!(output.total_errors(), 0);
+        assert_eq!(output.job_iops(), vec![15.0]);
+        assert_eq!(output.jobs[0].read.clat_ns.percentile(50.0), Some(4.0));
+        assert_eq!(output.max_clat_percentile(99.0), Some(12.0));
+        assert_eq!(output.max_clat_percentile(99.9), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_file_skips_histograms() -> Result<()> {
+        let path = crate::test::test_dir("histogram.json");
+        let bins = (0..100_000)
+            .map(|i| format!("\"{i}\": 1"))
+            .collect::<Vec<_>>()
+            .join(",");
+        std::fs::write(
+            &path,
+            format!(
+                r#"{{
+                    "fio version": "fio-3.36",
+                    "jobs": [{{
+                        "jobname": "default",
+                        "read": {{
+                            "iops": 7.0,
+                            "bw_bytes": 28672,
+                            "clat_ns": {{ "percentile": {{ "99.000000": 3 }}, "bins": {{ {bins} }} }}
+                        }}
+                    }}]
+                }}"#
+            ),
+        )?;
+
+        let output = parse_file(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(output.total_iops(), 7.0);
+        assert_eq!(output.total_bw_bytes(), 28672);
+        assert_eq!(output.max_clat_percentile(99.0), Some(3.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_histogram_percentile() -> Result<()> {
+        let histograms: HistogramOutput = serde_json::from_str(
+            r#"{"jobs": [
+                {"jobname": "1-write", "write": {"clat_ns": {"bins": {"1000": 9990, "5000": 9, "90000": 1}}}},
+                {"jobname": "2-read", "read": {"clat_ns": {"bins": {"2000": 1}}}}
+            ]}"#,
+        )?;
+        let p = |jobname, p| histograms.percentile(jobname, p).unwrap();
+        assert_eq!(p(Some("1-write"), 99.9), Some(1000.0));
+        assert_eq!(p(Some("1-write"), 99.95), Some(5000.0));
+        assert_eq!(p(Some("1-write"), 99.999), Some(90000.0));
+        assert_eq!(p(Some("1-write"), 100.0), Some(90000.0));
+        assert_eq!(p(Some("2-read"), 50.0), Some(2000.0));
+        assert_eq!(p(Some("3-trim"), 50.0), None);
+        assert_eq!(p(None, 99.9), Some(2000.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_thresholds() -> Result<()> {
+        let thresholds: Thresholds = toml::from_str(
+            r#"
+                min_iops = 100.0
+                max_p99_us = 50.0
+
+                [workloads.randwrite]
+                min_iops = 10.0
+            "#,
+        )?;
+        let aggregate = |workload: &str, iops_mean: f64, p99_us: f64| Aggregate {
+            entry: MatrixEntry {
+                block_size: "4k".into(),
+                jobcount: 1,
+                workload: workload.into(),
+                queue_depth: 1,
+                scheduler: "none".into(),
+                rate: None,
+                thinktime: None,
+                labels: Vec::new(),
+                device: None,
+            },
+            samples: 1,
+            iops_mean,
+            iops_stddev: 0.0,
+            job_iops_stddev_mean: 0.0,
+            bw_bytes_mean: 0.0,
+            p50_ns_mean: None,
+            p99_ns_mean: Some(p99_us * 1000.0),
+            power_watts_mean: None,
+            net_power_watts_mean: None,
+            rate_met: None,
+            iops_pct_of_max: None,
+            bw_pct_of_max: None,
+            latency_degrading: None,
+            histogram_percentiles_ns: Vec::new(),
+            harness_ceiling_iops: None,
+            latency_breakdown: None,
+            spread: Default::default(),
+        };
+
+        let aggregates = vec![
+            aggregate("randread", 200.0, 20.0),
+            aggregate("randread", 50.0, 20.0),
+            aggregate("randwrite", 50.0, 20.0),
+            aggregate("randwrite", 50.0, 80.0),
+        ];
+        let violations = check_thresholds(&thresholds, &aggregates);
+        assert_eq!(violations.len(), 2, "{violations:?}");
+        assert!(violations[0].contains("iops 50 below minimum 100"));
+        assert!(violations[1].contains("p99 80.0us above maximum 50.0us"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_comparison() {
+        let aggregate = |workload: &str, iops_mean: f64| Aggregate {
+            entry: MatrixEntry {
+                block_size: "4k".into(),
+                jobcount: 1,
+                workload: workload.into(),
+                queue_depth: 1,
+                scheduler: "none".into(),
+                rate: None,
+                thinktime: None,
+                labels: Vec::new(),
+                device: None,
+            },
+            samples: 1,
+            iops_mean,
+            iops_stddev: 0.0,
+            job_iops_stddev_mean: 0.0,
+            bw_bytes_mean: 0.0,
+            p50_ns_mean: None,
+            p99_ns_mean: None,
+            power_watts_mean: None,
+            net_power_watts_mean: None,
+            rate_met: None,
+            iops_pct_of_max: None,
+            bw_pct_of_max: None,
+            latency_degrading: None,
+            histogram_percentiles_ns: Vec::new(),
+            harness_ceiling_iops: None,
+            latency_breakdown: None,
+            spread: Default::default(),
+        };
+        let batches = vec![
+            ("6.12 a".to_string(), vec![aggregate("randread", 100.0)]),
+            ("6.13 a".to_string(), vec![aggregate("randread", 300.0)]),
+            (
+                "6.12 a".to_string(),
+                vec![aggregate("randread", 200.0), aggregate("randwrite", 50.0)],
+            ),
+        ];
+        let comparison = comparison(&batches);
+        assert_eq!(comparison.len(), 2);
+        let row = |label: &str, batches, iops_mean| ComparisonRow {
+            label: label.into(),
+            batches,
+            iops_mean,
+            p99_ns_mean: None,
+        };
+        assert_eq!(
+            comparison[0].1,
+            vec![row("6.12 a", 2, 150.0), row("6.13 a", 1, 300.0)]
+        );
+        assert_eq!(comparison[1].1, vec![row("6.12 a", 1, 50.0)]);
+    }
+
+    #[test]
+    fn test_canonical_summary() -> Result<()> {
+        let aggregate = |block_size: &str, iops_mean: f64| Aggregate {
+            entry: MatrixEntry {
+                block_size: block_size.into(),
+                jobcount: 1,
+                workload: "randread".into(),
+                queue_depth: 1,
+                scheduler: "none".into(),
+                rate: None,
+                thinktime: None,
+                labels: Vec::new(),
+                device: None,
+            },
+            samples: 1,
+            iops_mean,
+            iops_stddev: 0.0,
+            job_iops_stddev_mean: 0.0,
+            bw_bytes_mean: 0.0,
+            p50_ns_mean: None,
+            p99_ns_mean: None,
+            power_watts_mean: None,
+            net_power_watts_mean: None,
+            rate_met: None,
+            iops_pct_of_max: None,
+            bw_pct_of_max: None,
+            latency_degrading: None,
+            histogram_percentiles_ns: Vec::new(),
+            harness_ceiling_iops: None,
+            latency_breakdown: None,
+            spread: Default::default(),
+        };
+        let a = canonical_summary(&[aggregate("64k", 10.0), aggregate("4k", 20.0)])?;
+        let b = canonical_summary(&[aggregate("4k", 20.0), aggregate("64k", 10.0)])?;
+        assert_eq!(a, b);
+        assert!(a.find("\"4k\"") < a.find("\"64k\""), "{a}");
+        assert!(a.ends_with("]\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_clat_fields() {
+        assert_eq!(
+            clat_fields(&[
+                ("mean", Some(1500.0)),
+                ("p99", None),
+                ("p99.9", Some(20000.0))
+            ]),
+            " clat mean 1.5us p99.9 20.0us"
+        );
+        assert_eq!(clat_fields(&[("p99", None)]), "");
+    }
+
+    #[test]
+    fn test_brief_summary() -> Result<()> {
+        let output: FioOutput = serde_json::from_str(
+            r#"{"jobs": [{"jobname": "default", "read": {"iops": 1000, "bw_bytes": 4096000,
+                "clat_ns": {"percentile": {"50.000000": 2000, "99.000000": 9000}}}}]}"#,
+        )?;
+        assert_eq!(
+            brief_summary(&output, BandwidthUnit::parse("MB")?),
+            "iops 1000 bw 4.10 MB/s clat p50 2.0us p99 9.0us"
+        );
+        let reduced: FioOutput =
+            serde_json::from_str(r#"{"jobs": [{"jobname": "default", "read": {"iops": 1000}}]}"#)?;
+        assert_eq!(
+            brief_summary(&reduced, BandwidthUnit::parse("MB")?),
+            "iops 1000 bw 0.00 MB/s"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_latency_breakdown() -> Result<()> {
+        let output: FioOutput = serde_json::from_str(
+            r#"{"jobs": [{
+                "jobname": "default",
+                "read": { "iops": 300, "slat_ns": { "mean": 1000 },
+                          "clat_ns": { "mean": 9000 }, "lat_ns": { "mean": 10000 } },
+                "write": { "iops": 100, "slat_ns": { "mean": 2000 },
+                           "clat_ns": { "mean": 20000 }, "lat_ns": { "mean": 22000 } }
+            }]}"#,
+        )?;
+        assert_eq!(output.mean_latency(|s| &s.slat_ns), Some(1250.0));
+        assert_eq!(
+            LatencyBreakdown::of(&[&output]),
+            Some(LatencyBreakdown {
+                slat_ns_mean: 1250.0,
+                clat_ns_mean: 11750.0,
+                lat_ns_mean: 13000.0,
+            })
+        );
+
+        // With gtod_reduce fio measures no latency at all
+        let reduced: FioOutput = serde_json::from_str(
+            r#"{"jobs": [{"jobname": "default", "read": {"iops": 1, "slat_ns": {"mean": 0},
+                "clat_ns": {"mean": 0}, "lat_ns": {"mean": 0}}}]}"#,
+        )?;
+        assert_eq!(reduced.mean_latency(|s| &s.slat_ns), None);
+        assert_eq!(reduced.mean_latency(|s| &s.clat_ns), None);
+        assert_eq!(LatencyBreakdown::of(&[&reduced]), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_into_phases() {
+        let job = |name: &str, iops: f64| FioJob {
+            jobname: name.into(),
+            read: FioStats {
+                iops,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let output = FioOutput {
+            jobs: vec![
+                job("1-write", 1.0),
+                job("1-write", 2.0),
+                job("2-randread", 5.0),
+            ],
+        };
+        let phases = output.into_phases();
+        assert_eq!(phases.len(), 2);
+        assert_eq!(phases[0].0, phase_job_name(0, "write"));
+        assert_eq!(phases[0].1.total_iops(), 3.0);
+        assert_eq!(phases[1].0, "2-randread");
+        assert_eq!(phases[1].1.total_iops(), 5.0);
+    }
+
+    #[test]
+    fn test_parse_run_dir() -> Result<()> {
+        let dir = crate::test::test_dir("run-dir");
+        std::fs::create_dir_all(&dir)?;
+        let config = Config {
+            jobcounts: vec![1, 2],
+            ..Default::default()
+        };
+        let matrix = crate::matrix::build_matrix(&config);
+        let id = |entry| crate::matrix::run_output_id(&config, entry);
+        // A write-only job without a read section, and an output that is cut off
+        std::fs::write(
+            dir.join(format!("{}.json", id(&matrix[0]))),
+            r#"{"jobs": [{"jobname": "default", "write": {"iops": 3.0}}]}"#,
+        )?;
+        std::fs::write(
+            dir.join(format!("{}.json", id(&matrix[1]))),
+            r#"{"jobs": ["#,
+        )?;
+
+        let outputs = parse_run_dir(&config, &dir);
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].0, matrix[0]);
+        assert_eq!(outputs[0].1.total_iops(), 3.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_overhead_roundtrip() -> Result<()> {
+        let dir = crate::test::test_dir("overhead");
+        std::fs::create_dir_all(dir.join(OVERHEAD_DIR))?;
+        assert!(read_overhead(&dir)?.is_empty());
+
+        let ceilings = vec![OverheadCeiling {
+            jobcount: 2,
+            queue_depth: 32,
+            iops: 4e6,
+        }];
+        write_overhead(&dir, &ceilings)?;
+        assert_eq!(read_overhead(&dir)?, ceilings);
+        assert!(run_dirs(&dir)?.is_empty());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_summary_csv() -> Result<()> {
+        let dir = crate::test::test_dir("summary");
+        let run_dir = dir.join("2024-01-01-0000");
+        std::fs::create_dir_all(&run_dir)?;
+        let config = Config {
+            device: "nullb0".into(),
+            block_sizes: vec!["4k".into()],
+            jobcounts: vec![1],
+            queue_depths: vec![1],
+            workloads: vec![Workload::from("randread")],
+            ..Default::default()
+        };
+        let entry = &crate::matrix::build_matrix(&config)[0];
+        std::fs::write(
+            run_dir.join(format!(
+                "{}.json",
+                crate::matrix::run_output_id(&config, entry)
+            )),
+            r#"{"jobs":[{"jobname":"default","read":{"iops":100,"bw_bytes":409600,
+                "clat_ns":{"percentile":{"50.000000":2000,"99.000000":9000}}}}]}"#,
+        )?;
+
+        assert_eq!(write_summary_csv(&config, &dir)?, 1);
+        let csv = std::fs::read_to_string(dir.join(SUMMARY_CSV_FILE))?;
+        std::fs::remove_dir_all(&dir)?;
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("sample,device,workload,block_size,jobcount,queue_depth,"));
+        assert!(lines[0].ends_with(",write_p50_us,write_p99_us,write_p99.9_us"));
+        assert_eq!(lines[1].split(',').count(), lines[0].split(',').count());
+        assert!(lines[1].starts_with("0,nullb0,randread,4k,1,1,none,,,100,"));
+        assert!(lines[1].contains(",2.0,9.0,,0,"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ndjson_roundtrip() -> Result<()> {
+        let dir = crate::test::test_dir("ndjson");
+        let run_dir = dir.join("2024-01-01-0000");
+        std::fs::create_dir_all(&run_dir)?;
+        let config = Config {
+            block_sizes: vec!["4k".into()],
+            jobcounts: vec![1],
+            queue_depths: vec![1, 8],
+            workloads: vec![Workload::from("randread")],
+            ..Default::default()
+        };
+        std::fs::write(dir.join("config.toml"), config.to_toml()?)?;
+        let matrix = crate::matrix::build_matrix(&config);
+        for (entry, iops) in matrix.iter().zip([100, 800]) {
+            let id = crate::matrix::run_output_id(&config, entry);
+            std::fs::write(
+                run_dir.join(format!("{id}.json")),
+                format!(
+                    r#"{{"fio version":"fio-3.36","jobs":[{{"jobname":"default","read":{{"iops":{iops}}}}}]}}"#
+                ),
+            )?;
+        }
+
+        assert_eq!(write_ndjson(&config, &dir, true)?, 2);
+        assert_eq!(std::fs::read_dir(&run_dir)?.count(), 0);
+        let ndjson = std::fs::read_to_string(dir.join(NDJSON_FILE))?;
+        let first: serde_json::Value = serde_json::from_str(ndjson.lines().next().unwrap())?;
+        assert_eq!(first["sample"], 0);
+        assert_eq!(first["entry"]["queue_depth"], 1);
+        assert_eq!(first["output"]["fio version"], "fio-3.36");
+
+        let batch = Batch::load(&dir)?;
+        assert_eq!(
+            batch
+                .samples
+                .iter()
+                .map(|s| (s.entry.queue_depth, s.output.total_iops()))
+                .collect::<Vec<_>>(),
+            vec![(1, 100.0), (8, 800.0)]
+        );
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_device_run_dirs() -> Result<()> {
+        let dir = crate::test::test_dir("devices");
+        let config = Config {
+            devices: vec!["nvme0n1".into(), "nvme1n1".into()],
+            block_sizes: vec!["4k".into()],
+            jobcounts: vec![1],
+            queue_depths: vec![1],
+            workloads: vec![Workload::from("randread")],
+            ..Default::default()
+        };
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("config.toml"), config.to_toml()?)?;
+        // One sample, the devices in directories of their own
+        for (entry, iops) in crate::matrix::build_matrix(&config).iter().zip([100, 200]) {
+            let entry_dir = dir.join("sample-0000").join(entry.device.as_ref().unwrap());
+            std::fs::create_dir_all(&entry_dir)?;
+            let id = crate::matrix::run_output_id(&config, entry);
+            std::fs::write(
+                entry_dir.join(format!("{id}.json")),
+                format!(r#"{{"jobs":[{{"jobname":"default","read":{{"iops":{iops}}}}}]}}"#),
+            )?;
+        }
+
+        assert_eq!(parse_run_dir(&config, &dir.join("sample-0000")).len(), 2);
+        assert_eq!(write_ndjson(&config, &dir, false)?, 2);
+        let ndjson = std::fs::read_to_string(dir.join(NDJSON_FILE))?;
+        let first: serde_json::Value = serde_json::from_str(ndjson.lines().next().unwrap())?;
+        assert_eq!(first["sample"], 0);
+        assert_eq!(first["run_dir"], "sample-0000/nvme0n1");
+        let batch = Batch::load(&dir)?;
+        assert_eq!(batch.samples.len(), 2);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_terse() -> Result<()> {
+        let direction = |bw_kib: u64, iops: u64, p99_us: u64| {
+            let mut fields = vec!["0".to_string(); terse::LEN];
+            fields[terse::BW_KIB] = bw_kib.to_string();
+            fields[terse::IOPS] = iops.to_string();
+            fields[terse::CLAT_PERCENTILES.start] = "50.000000%=10".into();
+            fields[terse::CLAT_PERCENTILES.start + 1] = format!("99.000000%={p99_us}");
+            fields[terse::CLAT_PERCENTILES.start + 2] = "0%=0".into();
+            fields.join(";")
+        };
+        let line = format!(
+            "3;fio-3.36;default;0;0;{};{};0.0%;1.0%;100",
+            direction(4096, 1000, 20),
+            direction(0, 0, 0),
+        );
+
+        let output = parse_terse(&format!("{line}\n"))?;
+        assert_eq!(output.jobs.len(), 1);
+        assert_eq!(output.jobs[0].jobname, "default");
+        assert_eq!(output.total_iops(), 1000.0);
+        assert_eq!(output.total_bw_bytes(), 4096 * 1024);
+        assert_eq!(output.max_clat_percentile(99.0), Some(20_000.0));
+        assert_eq!(output.total_errors(), 0);
+
+        assert!(parse_terse("3;fio-3.36;default;0;0;1;2").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_bandwidth_unit() -> Result<()> {
+        assert_eq!(BandwidthUnit::parse("MiB")?.convert(3 * 1024 * 1024), 3.0);
+        assert_eq!(BandwidthUnit::parse("MB")?.convert(2_000_000), 2.0);
+        assert_eq!(BandwidthUnit::parse("KiB")?.label(), "KiB/s");
+        assert_eq!(BandwidthUnit::parse("Mbit")?.convert(250_000), 2.0);
+        assert!(BandwidthUnit::parse("furlong").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_spread() {
+        assert_eq!(Spread::of(&[]), None);
+        let spread = Spread::of(&[4.0, 1.0, 3.0, 2.0]).unwrap();
+        assert_eq!(spread.mean, 2.5);
+        assert_eq!(spread.median, 2.5);
+        assert!((spread.cv - spread.stddev / 2.5).abs() < 1e-12);
+        let spread = Spread::of(&[90.0, 100.0, 110.0]).unwrap();
+        assert_eq!(spread.median, 100.0);
+        assert_eq!(spread.stddev, 10.0);
+        assert_eq!(spread.cv, 0.1);
+        assert_eq!(Spread::of(&[0.0, 0.0]).unwrap().cv, 0.0);
+    }
+
+    #[test]
+    fn test_compare_baseline() -> Result<()> {
+        let spread = |workload: &str, iops: f64, p99: f64| EntrySpread {
+            entry: MatrixEntry {
+                block_size: "4k".into(),
+                jobcount: 1,
+                workload: workload.into(),
+                queue_depth: 1,
+                scheduler: "none".into(),
+                rate: None,
+                thinktime: None,
+                labels: Vec::new(),
+                device: None,
+            },
+            samples: 3,
+            spread: Spreads {
+                iops: Spread::of(&[iops]).unwrap(),
+                bw_bytes: Spread::of(&[iops * 4096.0]).unwrap(),
+                p50_ns: None,
+                p99_ns: Spread::of(&[p99]).unwrap().into(),
+            },
+        };
+        let baseline = [
+            spread("randread", 1000.0, 100.0),
+            spread("randwrite", 1000.0, 100.0),
+        ];
+        let current = [spread("read", 500.0, 1.0), spread("randread", 960.0, 110.0)];
+
+        let rows = compare_baseline(&baseline, &current);
+        assert!(matches!(rows[0], BaselineRow::Added(_)));
+        assert!(matches!(rows[1], BaselineRow::Matched { .. }));
+        assert!(matches!(rows[2], BaselineRow::Removed(_)));
+        let [iops, bw, p50, p99] = rows[1].changes();
+        assert!((iops.unwrap() + 4.0).abs() < 1e-9);
+        assert!((bw.unwrap() + 4.0).abs() < 1e-9);
+        assert_eq!(p50, None);
+        assert!((p99.unwrap() - 10.0).abs() < 1e-9);
+
+        let found = regressions(&rows, 5.0);
+        assert_eq!(found.len(), 1);
+        assert!(found[0].contains("p99 rose 10.0%"));
+        assert_eq!(regressions(&rows, 3.0).len(), 3);
+
+        let mut reduced = spread("randread", 1000.0, 100.0);
+        reduced.spread.p99_ns = None;
+        let reduced = [reduced];
+        let found = regressions(&compare_baseline(&baseline, &reduced), 5.0);
+        assert_eq!(found.len(), 1);
+        assert!(found[0].contains("no p99 latency"));
+
+        let dir = crate::test::test_dir("baseline");
+        std::fs::create_dir_all(&dir)?;
+        let csv = std::fs::read_to_string(write_comparison_csv(&dir, &rows)?)?;
+        std::fs::remove_dir_all(&dir)?;
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[1], "added,,read,4k,1,1,none,,,,,,");
+        assert_eq!(
+            lines[2],
+            "matched,,randread,4k,1,1,none,,,-4.00,-4.00,,10.00"
+        );
+        assert!(lines[3].starts_with("removed,,randwrite,"));
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+use crate::config::Config;
+use anyhow::Context;
+use anyhow::Result;
+use std::path::Path;
+use std::path::PathBuf;
+
+const AMD_PSTATE_STATUS: &str = "/sys/devices/system/cpu/amd_pstate/status";
+const CPUFREQ_BOOST: &str = "/sys/devices/system/cpu/cpufreq/boost";
+const INTEL_NO_TURBO: &str = "/sys/devices/system/cpu/intel_pstate/no_turbo";
+const NR_HUGEPAGES: &str = "/proc/sys/vm/nr_hugepages";
+const SCALING_GOVERNORS: &str = "/sys/devices/system/cpu/cpufreq/policy*/scaling_governor";
+const SCALING_MAX_FREQS: &str = "/sys/devices/system/cpu/cpufreq/policy*/scaling_max_freq";
+const SCALING_MIN_FREQS: &str = "/sys/devices/system/cpu/cpufreq/policy*/scaling_min_freq";
+
+/// What the system tuning files held before a batch changed them. They are
+/// written back in the order they were saved when dropped, so also when a
+/// batch bails out on an error.
+pub(crate) struct SavedSettings {
+    saved: Vec<(PathBuf, String)>,
+}
+
+/// Save every file the tuning options of `config` write to.
+pub(crate) fn capture(config: &Config) -> Result<SavedSettings> {
+    let mut settings = SavedSettings { saved: Vec::new() };
+    // Switching the amd_pstate mode resets the policies, so it is restored
+    // before them
+    let (amd_fixed, intel_fixed) = (
+        config.amd_fixed_freq().is_some(),
+        config.intel_fixed_freq().is_some(),
+    );
+    if amd_fixed {
+        settings.save(Path::new(AMD_PSTATE_STATUS))?;
+    }
+    if amd_fixed || config.cpufreq_governor_performance {
+        settings.save_glob(SCALING_GOVERNORS)?;
+    }
+    // The maximum goes back first, the saved minimum is never above it
+    if amd_fixed || intel_fixed {
+        settings.save_glob(SCALING_MAX_FREQS)?;
+    }
+    if intel_fixed {
+        settings.save_glob(SCALING_MIN_FREQS)?;
+    }
+    if amd_fixed || config.amd_boost_off() {
+        settings.save(Path::new(CPUFREQ_BOOST))?;
+    }
+    if intel_fixed || config.intel_turbo_off() {
+        settings.save(Path::new(INTEL_NO_TURBO))?;
+    }
+    if config.use_hugepages {
+        settings.save(Path::new(NR_HUGEPAGES))?;
+    }
+    // Those of devices made per config do not exist yet, and go with them
+    if config.fio_client.is_none() {
+        for device_config in config.device_configs() {
+            let queue = Path::new(crate::SYSFS_BLOCK)
+                .join(&device_config.device)
+                .join("queue");
+            settings.save_choice(&queue.join("scheduler"))?;
+            settings.save(&queue.join("iostats"))?;
+        }
+    }
+    Ok(settings)
+}
+
+impl SavedSettings {
+    /// Files that do not exist are left out, writing them fails anyway.
+    fn save(&mut self, path: &Path) -> Result<()> {
+        self.save_value(path, |data| Some(data.trim()))
+    }
+
+    /// Like [`Self::save`] for a list of choices such as `queue/scheduler`,
+    /// of which only the active one can be written back.
+    fn save_choice(&mut self, path: &Path) -> Result<()> {
+        self.save_value(path, crate::active_choice)
+    }
+
+    fn save_value(&mut self, path: &Path, value: fn(&str) -> Option<&str>) -> Result<()> {
+        if !path.exists() || self.saved.iter().any(|(p, _)| p == path) {
+            return Ok(());
+        }
+        let data =
+            std::fs::read_to_string(path).with_context(|| format!("Failed to save {path:?}"))?;
+        let value = value(&data)
+            .with_context(|| format!("No value to save in {path:?}"))?
+            .to_string();
+        log::info!("Saved {path:?}: {value}");
+        self.saved.push((path.to_path_buf(), value));
+        Ok(())
+    }
+
+    fn save_glob(&mut self, pattern: &str) -> Result<()> {
+        for path in glob::glob(pattern).context("Bad settings pattern")? {
+            self.save(&path?)?;
+        }
+        Ok(())
+    }
+
+    /// Write back all saved values. A failing file is logged and the rest
+    /// are still restored.
+    pub(crate) fn restore(&mut self) {
+        for (path, value) in self.saved.drain(..) {
+            match std::fs::write(&path, format!("{value}\n")) {
+                Ok(()) => log::info!("Restored {path:?}: {value}"),
+                Err(e) => log::warn!("Failed to restore {path:?} to {value}: {e:?}"),
+            }
+        }
+    }
+}
+
+impl Drop for SavedSettings {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_save_and_restore() -> Result<()> {
+        let dir = crate::test::test_dir("settings");
+        std::fs::create_dir_all(&dir)?;
+        let (governor, boost) = (dir.join("scaling_governor"), dir.join("boost"));
+        std::fs::write(&governor, "powersave\n")?;
+        std::fs::write(&boost, "1\n")?;
+
+        let mut settings = SavedSettings { saved: Vec::new() };
+        settings.save(&governor)?;
+        settings.save(&boost)?;
+        settings.save(&dir.join("missing"))?;
+        std::fs::write(&governor, "performance\n")?;
+        settings.save(&governor)?;
+        std::fs::write(&boost, "0\n")?;
+        let scheduler = dir.join("scheduler");
+        std::fs::write(&scheduler, "none [mq-deadline] kyber\n")?;
+        settings.save_choice(&scheduler)?;
+        std::fs::write(&scheduler, "[none] mq-deadline kyber\n")?;
+        assert_eq!(settings.saved.len(), 3);
+
+        drop(settings);
+        assert_eq!(std::fs::read_to_string(&governor)?, "powersave\n");
+        assert_eq!(std::fs::read_to_string(&boost)?, "1\n");
+        assert_eq!(std::fs::read_to_string(&scheduler)?, "mq-deadline\n");
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
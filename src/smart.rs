@@ -0,0 +1,117 @@
+use crate::command::CheckExitCode;
+use crate::command::Command;
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use std::process::Stdio;
+
+/// A data unit of the SMART log is a thousand 512 byte sectors.
+const DATA_UNIT_BYTES: u64 = 512 * 1000;
+
+/// The endurance counters of `nvme smart-log`.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub(crate) struct SmartLog {
+    #[serde(deserialize_with = "counter")]
+    pub(crate) data_units_read: u64,
+
+    #[serde(deserialize_with = "counter")]
+    pub(crate) data_units_written: u64,
+
+    #[serde(deserialize_with = "counter")]
+    pub(crate) media_errors: u64,
+
+    /// Vendor estimate of the life used, can go past 100.
+    #[serde(alias = "percentage_used")]
+    pub(crate) percent_used: u64,
+}
+
+/// nvme-cli prints the 128 bit counters as strings in some versions.
+fn counter<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Counter {
+        Number(u64),
+        String(String),
+    }
+    match Counter::deserialize(deserializer)? {
+        Counter::Number(n) => Ok(n),
+        Counter::String(s) => s.trim().parse().map_err(serde::de::Error::custom),
+    }
+}
+
+/// How much a sweep aged the drive.
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct SmartDelta {
+    pub(crate) before: SmartLog,
+    pub(crate) after: SmartLog,
+    pub(crate) bytes_read: u64,
+    pub(crate) bytes_written: u64,
+    pub(crate) media_errors: u64,
+    pub(crate) percent_used: u64,
+}
+
+impl SmartDelta {
+    pub(crate) fn new(before: SmartLog, after: SmartLog) -> Self {
+        let diff = |f: fn(&SmartLog) -> u64| f(&after).saturating_sub(f(&before));
+        Self {
+            bytes_read: diff(|s| s.data_units_read) * DATA_UNIT_BYTES,
+            bytes_written: diff(|s| s.data_units_written) * DATA_UNIT_BYTES,
+            media_errors: diff(|s| s.media_errors),
+            percent_used: diff(|s| s.percent_used),
+            before,
+            after,
+        }
+    }
+}
+
+fn parse(data: &str) -> Result<SmartLog> {
+    serde_json::from_str(data).context("Failed to parse smart log")
+}
+
+/// Read the SMART log of `device`, e.g. `nvme0n1`.
+pub(crate) fn read(device: &str) -> Result<SmartLog> {
+    if !device.starts_with("nvme") {
+        return Err(anyhow!("{device} is not an NVMe device"));
+    }
+    let output = Command::new("nvme")
+        .arg("smart-log")
+        .arg(format!("/dev/{device}"))
+        .arg("--output-format=json")
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to run nvme")?
+        .wait_with_output()?;
+    output
+        .status
+        .check_status()
+        .context("nvme smart-log failed")?;
+    parse(&String::from_utf8_lossy(&output.stdout))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_delta() -> Result<()> {
+        let before = parse(
+            r#"{"critical_warning":0,"temperature":310,"percent_used":3,
+                "data_units_read":1000,"data_units_written":"2000","media_errors":0}"#,
+        )?;
+        let after = parse(
+            r#"{"percentage_used":4,"data_units_read":1000,
+                "data_units_written":2500,"media_errors":1}"#,
+        )?;
+        let delta = SmartDelta::new(before, after);
+        assert_eq!(delta.bytes_read, 0);
+        assert_eq!(delta.bytes_written, 500 * 512 * 1000);
+        assert_eq!(delta.media_errors, 1);
+        assert_eq!(delta.percent_used, 1);
+
+        assert!(parse(r#"{"percent_used":1}"#).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::Duration;
+use std::time::Instant;
+
+const SYSFS: &str = "/sys";
+
+pub(crate) const TELEMETRY_SUFFIX: &str = "-telemetry.csv";
+
+/// The files sampled, looked up once when sampling starts so that every row
+/// has the same columns.
+struct Sources {
+    /// Column name and `temp` file of each thermal zone.
+    zones: Vec<(String, PathBuf)>,
+    /// The temperature the drive reports itself, NVMe drives do via hwmon.
+    device_temp: Option<PathBuf>,
+    device_stat: PathBuf,
+    cpu_freqs: Vec<PathBuf>,
+}
+
+impl Sources {
+    fn find(sysfs: &Path, device: &str) -> Result<Self> {
+        let paths = |pattern: String| -> Result<Vec<PathBuf>> {
+            let mut paths = glob::glob(&pattern)
+                .context("Bad telemetry pattern")?
+                .collect::<Result<Vec<_>, _>>()?;
+            paths.sort();
+            Ok(paths)
+        };
+        let sysfs = sysfs.display();
+
+        let mut zones = Vec::new();
+        for dir in paths(format!("{sysfs}/class/thermal/thermal_zone*"))? {
+            let zone = dir
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default()
+                .trim_start_matches("thermal_")
+                .to_string();
+            let kind = std::fs::read_to_string(dir.join("type")).unwrap_or_default();
+            zones.push((format!("{zone}_{}_c", kind.trim()), dir.join("temp")));
+        }
+
+        let device_dir = PathBuf::from(format!("{sysfs}/class/block/{device}"));
+        let device_stat = device_dir.join("stat");
+        if !device_stat.exists() {
+            return Err(anyhow!("No block device statistics at {device_stat:?}"));
+        }
+        let device_temp = paths(format!(
+            "{}/device/hwmon*/temp1_input",
+            device_dir.display()
+        ))?
+        .into_iter()
+        .next();
+
+        Ok(Self {
+            zones,
+            device_temp,
+            device_stat,
+            cpu_freqs: paths(format!(
+                "{sysfs}/devices/system/cpu/cpufreq/policy*/scaling_cur_freq"
+            ))?,
+        })
+    }
+
+    fn header(&self) -> String {
+        let mut columns = vec!["time_s"];
+        columns.extend(self.zones.iter().map(|(name, _)| name.as_str()));
+        if self.device_temp.is_some() {
+            columns.push("device_c");
+        }
+        columns.extend([
+            "reads",
+            "read_sectors",
+            "writes",
+            "write_sectors",
+            "in_flight",
+            "io_ticks_ms",
+            "cpu_mhz_min",
+            "cpu_mhz_mean",
+            "cpu_mhz_max",
+        ]);
+        columns.join(",")
+    }
+
+    /// One CSV row. A file that cannot be read leaves its fields empty, a
+    /// sensor going away is no reason to stop the run.
+    fn sample(&self, elapsed: Duration) -> String {
+        let read = |path: &Path| std::fs::read_to_string(path).ok();
+        let number = |path: &Path| read(path).and_then(|v| v.trim().parse::<f64>().ok());
+        let celsius = |path: &Path| {
+            number(path)
+                .map(|millis| format!("{:.1}", millis / 1000.0))
+                .unwrap_or_default()
+        };
+
+        let mut fields = vec![format!("{:.3}", elapsed.as_secs_f64())];
+        fields.extend(self.zones.iter().map(|(_, path)| celsius(path)));
+        if let Some(path) = &self.device_temp {
+            fields.push(celsius(path));
+        }
+
+        // Fields of Documentation/block/stat.rst
+        let stat = read(&self.device_stat).unwrap_or_default();
+        let stat = stat.split_whitespace().collect::<Vec<_>>();
+        fields.extend([0, 2, 4, 6, 8, 9].map(|i| stat.get(i).unwrap_or(&"").to_string()));
+
+        let mhz = self
+            .cpu_freqs
+            .iter()
+            .filter_map(|path| number(path))
+            .map(|khz| khz / 1000.0)
+            .collect::<Vec<_>>();
+        match mhz.is_empty() {
+            true => fields.extend([String::new(), String::new(), String::new()]),
+            false => fields.extend([
+                format!("{:.0}", mhz.iter().copied().fold(f64::INFINITY, f64::min)),
+                format!("{:.0}", mhz.iter().sum::<f64>() / mhz.len() as f64),
+                format!("{:.0}", mhz.iter().copied().fold(0.0, f64::max)),
+            ]),
+        }
+        fields.join(",")
+    }
+}
+
+/// Writes a row of temperatures, device counters and cpu frequencies every
+/// interval from a thread of its own. It takes a last row and stops when
+/// dropped, so it does not outlive a failed workload.
+pub(crate) struct Sampler {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<Result<()>>>,
+}
+
+/// Sample the thermal zones, `device` and the cpus every `interval` into the
+/// CSV at `path`.
+pub(crate) fn start(device: &str, interval: Duration, path: &Path) -> Result<Sampler> {
+    start_in(Path::new(SYSFS), device, interval, path)
+}
+
+fn start_in(sysfs: &Path, device: &str, interval: Duration, path: &Path) -> Result<Sampler> {
+    let sources = Sources::find(sysfs, device)?;
+    let mut file =
+        std::fs::File::create(path).with_context(|| format!("Failed to create {path:?}"))?;
+    writeln!(file, "{}", sources.header())?;
+
+    let (stop, stopped) = mpsc::channel();
+    let started = Instant::now();
+    let thread = std::thread::spawn(move || -> Result<()> {
+        loop {
+            writeln!(file, "{}", sources.sample(started.elapsed()))?;
+            if stopped.recv_timeout(interval) != Err(mpsc::RecvTimeoutError::Timeout) {
+                break;
+            }
+        }
+        writeln!(file, "{}", sources.sample(started.elapsed()))?;
+        Ok(())
+    });
+    Ok(Sampler {
+        stop: Some(stop),
+        thread: Some(thread),
+    })
+}
+
+impl Sampler {
+    pub(crate) fn stop(mut self) -> Result<()> {
+        self.stop_inner()
+    }
+
+    fn stop_inner(&mut self) -> Result<()> {
+        // Wakes the thread up early
+        self.stop.take();
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .map_err(|_| anyhow!("Telemetry sampler panicked"))?
+                .context("Failed to write telemetry"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Sampler {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop_inner() {
+            log::warn!("Failed to stop telemetry sampler: {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sampler() -> Result<()> {
+        let dir = crate::test::test_dir("telemetry");
+        let write = |path: &str, value: &str| -> Result<()> {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(path, format!("{value}\n"))?;
+            Ok(())
+        };
+        write("class/thermal/thermal_zone0/type", "x86_pkg_temp")?;
+        write("class/thermal/thermal_zone0/temp", "45000")?;
+        write("class/block/nvme0n1/device/hwmon1/temp1_input", "38900")?;
+        write(
+            "class/block/nvme0n1/stat",
+            "     100 0 800 5 200 0 1600 7 3 40 12 0 0 0 0 0 0",
+        )?;
+        write(
+            "devices/system/cpu/cpufreq/policy0/scaling_cur_freq",
+            "2000000",
+        )?;
+        write(
+            "devices/system/cpu/cpufreq/policy1/scaling_cur_freq",
+            "3000000",
+        )?;
+        assert!(start_in(&dir, "nvme1n1", Duration::ZERO, &dir.join("x.csv")).is_err());
+
+        let path = dir.join("telemetry.csv");
+        let sampler = start_in(&dir, "nvme0n1", Duration::from_secs(60), &path)?;
+        sampler.stop()?;
+
+        let csv = std::fs::read_to_string(&path)?;
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[0],
+            "time_s,zone0_x86_pkg_temp_c,device_c,reads,read_sectors,writes,write_sectors,\
+             in_flight,io_ticks_ms,cpu_mhz_min,cpu_mhz_mean,cpu_mhz_max"
+        );
+        // One when started and one when stopped
+        assert_eq!(lines.len(), 3);
+        assert!(lines[2].ends_with(",45.0,38.9,100,800,200,1600,3,40,2000,2500,3000"));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Interval of the fio histogram log.
+pub(crate) const INTERVAL_MS: u64 = 1000;
+
+/// A p99 rising by more than this fraction of its mean over the run is
+/// reported as degrading.
+const DEGRADING_CHANGE: f64 = 0.2;
+
+/// fio's latency histogram layout, `FIO_IO_U_PLAT_BITS` and
+/// `FIO_IO_U_PLAT_NR`. Logs written with a `log_hist_coarseness` have fewer
+/// bins and are rejected.
+const PLAT_BITS: u32 = 6;
+const PLAT_VAL: usize = 1 << PLAT_BITS;
+const PLAT_NR: usize = 29 * PLAT_VAL;
+
+pub(crate) const TREND_SUFFIX: &str = "-latency-trend.json";
+
+/// p99 completion latency over the run and whether it trends upwards.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct LatencyTrend {
+    /// Seconds into the run and p99 in microseconds, per interval.
+    pub(crate) p99_us: Vec<(f64, f64)>,
+    /// Least squares change of the p99 over the run, relative to its mean.
+    pub(crate) relative_change: f64,
+    pub(crate) degrading: bool,
+}
+
+/// The fio `--write_hist_log` files of a run, one per job.
+pub(crate) fn hist_logs(dir: &Path, prefix: &str) -> Result<Vec<PathBuf>> {
+    let pattern = dir.join(format!("{prefix}_clat_hist.*.log"));
+    let pattern = pattern.to_str().ok_or(anyhow!("path conversion error"))?;
+    Ok(glob::glob(pattern)?.collect::<Result<Vec<_>, _>>()?)
+}
+
+/// Latency in ns that a histogram bin stands for, as `plat_idx_to_val`.
+fn bin_value_ns(idx: usize) -> f64 {
+    if idx < PLAT_VAL << 1 {
+        return idx as f64;
+    }
+    let error_bits = (idx >> PLAT_BITS) as u32 - 1;
+    let base = 1u64 << (error_bits + PLAT_BITS);
+    let k = (idx % PLAT_VAL) as f64;
+    base as f64 + (k + 0.5) * (1u64 << error_bits) as f64
+}
+
+fn percentile_ns(bins: &[u64], p: f64) -> Option<f64> {
+    let total = bins.iter().sum::<u64>();
+    if total == 0 {
+        return None;
+    }
+    let target = ((total as f64 * p / 100.0).ceil() as u64).max(1);
+    let mut seen = 0;
+    for (idx, count) in bins.iter().enumerate() {
+        seen += count;
+        if seen >= target {
+            return Some(bin_value_ns(idx));
+        }
+    }
+    None
+}
+
+/// Lines are `time, direction, block size, bins...`, with the bins counting
+/// the completions since the previous line. Directions and the logs of all
+/// jobs are summed up per interval.
+fn add_hist_log(data: &str, intervals: &mut BTreeMap<u64, Vec<u64>>) -> Result<()> {
+    for line in data.lines().filter(|l| !l.trim().is_empty()) {
+        let fields = line
+            .split(',')
+            .map(|f| f.trim().parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Invalid histogram log line {line:?}"))?;
+        let [time, _direction, _block_size, bins @ ..] = fields.as_slice() else {
+            return Err(anyhow!("Short histogram log line {line:?}"));
+        };
+        if bins.len() != PLAT_NR {
+            return Err(anyhow!(
+                "Expected {PLAT_NR} histogram bins, got {}",
+                bins.len()
+            ));
+        }
+        let interval = (time + INTERVAL_MS / 2) / INTERVAL_MS;
+        let sum = intervals
+            .entry(interval)
+            .or_insert_with(|| vec![0; PLAT_NR]);
+        for (sum, count) in sum.iter_mut().zip(bins) {
+            *sum += count;
+        }
+    }
+    Ok(())
+}
+
+fn trend(p99_us: Vec<(f64, f64)>) -> Option<LatencyTrend> {
+    if p99_us.len() < 3 {
+        return None;
+    }
+    let n = p99_us.len() as f64;
+    let mean_t = p99_us.iter().map(|(t, _)| t).sum::<f64>() / n;
+    let mean_p = p99_us.iter().map(|(_, p)| p).sum::<f64>() / n;
+    let cov = p99_us
+        .iter()
+        .map(|(t, p)| (t - mean_t) * (p - mean_p))
+        .sum::<f64>();
+    let var = p99_us
+        .iter()
+        .map(|(t, _)| (t - mean_t).powi(2))
+        .sum::<f64>();
+    if var == 0.0 || mean_p == 0.0 {
+        return None;
+    }
+    let span = p99_us.last()?.0 - p99_us.first()?.0;
+    let relative_change = cov / var * span / mean_p;
+    Some(LatencyTrend {
+        p99_us,
+        relative_change,
+        degrading: relative_change > DEGRADING_CHANGE,
+    })
+}
+
+/// The trend of the p99 in the histogram logs `paths`, `None` with fewer
+/// than three intervals of data.
+pub(crate) fn analyze(paths: &[PathBuf]) -> Result<Option<LatencyTrend>> {
+    let mut intervals = BTreeMap::new();
+    for path in paths {
+        let data =
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read {path:?}"))?;
+        add_hist_log(&data, &mut intervals).with_context(|| format!("Failed to parse {path:?}"))?;
+    }
+    let p99_us = intervals
+        .iter()
+        .filter_map(|(interval, bins)| {
+            let secs = (interval * INTERVAL_MS) as f64 / 1000.0;
+            Some((secs, percentile_ns(bins, 99.0)? / 1000.0))
+        })
+        .collect();
+    Ok(trend(p99_us))
+}
+
+pub(crate) fn write(path: &Path, trend: &LatencyTrend) -> Result<()> {
+    let file = std::fs::File::create(path).with_context(|| format!("Failed to create {path:?}"))?;
+    serde_json::to_writer_pretty(file, trend).context("Failed to write latency trend")
+}
+
+pub(crate) fn read(path: &Path) -> Result<LatencyTrend> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+    serde_json::from_reader(std::io::BufReader::new(file))
+        .with_context(|| format!("Failed to parse {path:?}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bin_value_ns() {
+        assert_eq!(bin_value_ns(5), 5.0);
+        assert_eq!(bin_value_ns(127), 127.0);
+        // The first lossy group has bins two ns wide
+        assert_eq!(bin_value_ns(128), 129.0);
+        assert_eq!(bin_value_ns(129), 131.0);
+    }
+
+    #[test]
+    fn test_analyze_hist_log() {
+        let line = |time: u64, bin: usize| {
+            let mut bins = vec!["0"; PLAT_NR];
+            bins[bin] = "100";
+            format!("{time}, 0, 4096, {}\n", bins.join(", "))
+        };
+        let mut intervals = BTreeMap::new();
+        let log = [
+            line(1000, 10),
+            line(2001, 20),
+            line(2999, 40),
+            line(4000, 80),
+        ]
+        .concat();
+        add_hist_log(&log, &mut intervals).unwrap();
+        assert_eq!(intervals.len(), 4);
+        assert_eq!(percentile_ns(&intervals[&2], 99.0), Some(20.0));
+        assert_eq!(percentile_ns(&vec![0; PLAT_NR], 99.0), None);
+
+        assert!(add_hist_log("1000, 0, 4096, 1, 2\n", &mut intervals).is_err());
+    }
+
+    #[test]
+    fn test_trend() {
+        let stable = trend(vec![(1.0, 10.0), (2.0, 11.0), (3.0, 10.0), (4.0, 10.5)]).unwrap();
+        assert!(!stable.degrading, "{}", stable.relative_change);
+
+        let degrading = trend(vec![(1.0, 10.0), (2.0, 14.0), (3.0, 18.0), (4.0, 22.0)]).unwrap();
+        assert!(degrading.degrading);
+        assert!((degrading.relative_change - 12.0 / 16.0).abs() < 1e-9);
+
+        assert!(trend(vec![(1.0, 10.0), (2.0, 20.0)]).is_none());
+    }
+}